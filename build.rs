@@ -0,0 +1,42 @@
+//! Turns the vendored `data/usb.ids` into sorted lookup tables,
+//! see `src/usb_ids.rs` for the lookup.
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+#[path = "src/usb_ids/parse.rs"]
+mod parse;
+
+const USB_IDS: &str = "data/usb.ids";
+
+fn main() {
+    println!("cargo:rerun-if-changed={USB_IDS}");
+    println!("cargo:rerun-if-changed=src/usb_ids/parse.rs");
+    let list = fs::read(USB_IDS).expect("Could not read the USB ID list");
+    // the upstream list has had entries that are not valid UTF-8
+    let list = String::from_utf8_lossy(&list);
+    let (vendors, products) = parse::parse(&list);
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "static COMPLETE: bool = {};",
+        parse::is_complete(&list)
+    )
+    .unwrap();
+    writeln!(out, "static VENDORS: &[(u16, &str)] = &[").unwrap();
+    for (v, name) in &vendors {
+        writeln!(out, "    ({v:#06x}, {name:?}),").unwrap();
+    }
+    writeln!(out, "];").unwrap();
+    writeln!(out, "static PRODUCTS: &[(u16, u16, &str)] = &[").unwrap();
+    for (v, p, name) in &products {
+        writeln!(out, "    ({v:#06x}, {p:#06x}, {name:?}),").unwrap();
+    }
+    writeln!(out, "];").unwrap();
+
+    let out_dir = env::var_os("OUT_DIR").expect("OUT_DIR is set by cargo");
+    fs::write(Path::new(&out_dir).join("usb_ids.rs"), out)
+        .expect("Could not write the USB ID tables");
+}
@@ -41,7 +41,8 @@
           tests_f = import ./nix/tests.nix self;
         in
         rec {
-          usbip_wrapper = pkgs.rustPlatform.buildRustPackage {
+          # the toolchain of nixos-22.11 is older than the `rust-version` in Cargo.toml
+          usbip_wrapper = pkgs-unstable.rustPlatform.buildRustPackage {
             pname = "usbip-wrapper";
             version = "v0.1.0";
 
@@ -49,13 +50,16 @@
               root = ./.;
               include = [
                 "src"
+                "data"
+                ./build.rs
                 ./Cargo.lock
                 ./Cargo.toml
               ];
             };
 
-            # cargoSha256 = pkgs.lib.fakeSha256;
-            cargoSha256 = "sha256-35wXNIUg01RX4qNRSYF6PXooRzqRpAkRTGuXEJd6MCs=";
+            # vendor the dependencies from the lock file, so that there is no hash to update
+            # whenever a dependency is added
+            cargoLock.lockFile = ./Cargo.lock;
 
             meta = {
               description = "A simple usbip wrapper";
@@ -166,7 +166,7 @@ in
                 ExecStart = if nu_mode then ''
                   ${config.services.usbip_wrapper_host.package}/bin/usbip-wrapper-executor mount-remote --tcp-port=${builtins.toString port} %I
                 '' else ''
-                  ${config.services.usbip_wrapper_host.package}/bin/usbip_wrapper mount-remote --host %I --tcp-port=${builtins.toString port}
+                  ${config.services.usbip_wrapper_host.package}/bin/usbip_wrapper mount-remote --host %I --tcp-port=${builtins.toString port} --all
                 '';
               };
               path = [ 
@@ -179,7 +179,7 @@ in
                 ExecStart = if nu_mode then ''
                   ${config.services.usbip_wrapper_host.package}/bin/usbip-wrapper-executor unmount-remote | to text
                 '' else ''
-                  ${config.services.usbip_wrapper_host.package}/bin/usbip_wrapper unmount-remote
+                  ${config.services.usbip_wrapper_host.package}/bin/usbip_wrapper unmount-remote --all
                 '';
                 ExecStartPost = ''${pkgs.coreutils}/bin/sleep 1s'';
               };
@@ -66,6 +66,17 @@ in
                 '';
                 example = ''"your-device@XXX.tailscale.net" | "192.XXX.XXX.XXX"'';
               };
+
+              wait = lib.mkOption {
+                type = lib.types.nullOr lib.types.str;
+                description = ''
+                  Keep retrying until all `usb_ids` are mounted or the given time passed,
+                  e.g., while the host is still booting.
+                  Only supported by the Rust implementation.
+                '';
+                default = null;
+                example = ''30s | 2min'';
+              };
             };
           });
         };
@@ -136,7 +147,9 @@ in
                         ${cfg_host.package}/bin/usbip_wrapper mount-remote \
                           --host="${instance_value.host}" \
                           --tcp-port="${builtins.toString instance_value.port}" \
-                          -- ${builtins.concatStringsSep " " instance_value.usb_ids}
+                          ${lib.optionalString (instance_value.wait != null) "--wait=\"${instance_value.wait}\""} \
+                          ${if instance_value.usb_ids == [ ] then "--all"
+                            else "-- " + builtins.concatStringsSep " " instance_value.usb_ids}
                       '';
                     # sleep is required to ensure that USB device is fully mounted
                     ExecStartPost = ''${pkgs.coreutils}/bin/sleep 1s'';
@@ -0,0 +1,99 @@
+//! The USB/IP operations that the [`Host`](crate::Host) and [`Client`](crate::Client) build upon.
+//!
+//! - [`NativeBackend`] talks to the kernel via sysfs and to the usbip host via the protocol
+//! - [`ShellBackend`] calls the `usbip` executable and parses its output
+//! - [`FakeBackend`] keeps all devices in memory, e.g., to test tooling without USB/IP
+use crate::protocol::UsbDevice;
+use crate::sysfs::LocalDevice;
+use crate::usbip_host::BindOutcome;
+use crate::vhci::{AttachError, RemoteConnection};
+use crate::{BusId, Port, UsbId};
+
+mod fake;
+mod native;
+mod shell;
+
+pub use fake::FakeBackend;
+pub use native::NativeBackend;
+pub use shell::ShellBackend;
+
+/// A remote device that is attached to a local vhci port
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AttachedDevice {
+    pub port: Port,
+    /// Unknown until the kernel has enumerated the device
+    pub usb_id: Option<UsbId>,
+    /// Unknown until the kernel has enumerated the device or if the backend cannot read it
+    pub serial: Option<String>,
+    /// Only known if the port was attached by `usbip attach` or this tool
+    pub remote: Option<RemoteConnection>,
+}
+
+pub trait Backend {
+    /// All USB devices that are connected locally, except for hubs
+    fn list_local(&self) -> anyhow::Result<Vec<LocalDevice>>;
+
+    /// Bind the local device to `usbip-host`, so that it can be imported remotely
+    fn bind(&self, busid: &BusId) -> anyhow::Result<BindOutcome>;
+
+    /// Unbind the local device from `usbip-host`, so that it can be used locally again
+    fn unbind(&self, busid: &BusId) -> anyhow::Result<BindOutcome>;
+
+    /// All devices that the usbip host exports
+    fn list_remote(&self, host: &str, tcp_port: u16) -> anyhow::Result<Vec<UsbDevice>>;
+
+    /// Import the device from the usbip host and attach it to a local port.
+    /// If serial numbers are given, the device is only attached if it has one of them,
+    /// otherwise [`AttachError::SerialMismatch`] is returned.
+    /// Returns the used port.
+    fn attach(
+        &self,
+        host: &str,
+        tcp_port: u16,
+        busid: &BusId,
+        serials: &[&str],
+    ) -> Result<Port, AttachError>;
+
+    /// Detach the remote device from the local port
+    fn detach(&self, port: &Port) -> anyhow::Result<()>;
+
+    /// All local ports that have a remote device attached
+    fn list_ports(&self) -> anyhow::Result<Vec<AttachedDevice>>;
+}
+
+/// Allows to keep using the backend, e.g., to inspect a [`FakeBackend`]
+impl<B: Backend + ?Sized> Backend for &B {
+    fn list_local(&self) -> anyhow::Result<Vec<LocalDevice>> {
+        (**self).list_local()
+    }
+
+    fn bind(&self, busid: &BusId) -> anyhow::Result<BindOutcome> {
+        (**self).bind(busid)
+    }
+
+    fn unbind(&self, busid: &BusId) -> anyhow::Result<BindOutcome> {
+        (**self).unbind(busid)
+    }
+
+    fn list_remote(&self, host: &str, tcp_port: u16) -> anyhow::Result<Vec<UsbDevice>> {
+        (**self).list_remote(host, tcp_port)
+    }
+
+    fn attach(
+        &self,
+        host: &str,
+        tcp_port: u16,
+        busid: &BusId,
+        serials: &[&str],
+    ) -> Result<Port, AttachError> {
+        (**self).attach(host, tcp_port, busid, serials)
+    }
+
+    fn detach(&self, port: &Port) -> anyhow::Result<()> {
+        (**self).detach(port)
+    }
+
+    fn list_ports(&self) -> anyhow::Result<Vec<AttachedDevice>> {
+        (**self).list_ports()
+    }
+}
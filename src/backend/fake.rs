@@ -0,0 +1,240 @@
+//! Backend that keeps all devices in memory.
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::anyhow;
+
+use super::{AttachedDevice, Backend};
+use crate::protocol::UsbDevice;
+use crate::sysfs::LocalDevice;
+use crate::usbip_host::{BindOutcome, USBIP_HOST_DRIVER};
+use crate::vhci::{AttachError, RemoteConnection};
+use crate::{serial_matches, BusId, Error, Port};
+
+#[derive(Debug, Default)]
+struct State {
+    local: Vec<LocalDevice>,
+    /// The exported devices of each usbip host
+    remote: HashMap<String, Vec<UsbDevice>>,
+    /// The serial numbers of the exported devices by host and busid
+    remote_serials: HashMap<(String, BusId), String>,
+    ports: Vec<AttachedDevice>,
+    /// The busid of every attach request, in order
+    attach_requests: Vec<BusId>,
+}
+
+/// Simulates the local devices, the usbip hosts and the vhci ports.
+///
+/// Binding a local device to `usbip-host` does not export it to any of the hosts;
+/// add the device via [`FakeBackend::add_remote_device`] instead.
+#[derive(Debug, Default)]
+pub struct FakeBackend {
+    state: Mutex<State>,
+}
+
+impl FakeBackend {
+    pub fn new() -> Self {
+        FakeBackend::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Plug a device in locally
+    pub fn add_local_device(&self, device: LocalDevice) {
+        self.state().local.push(device);
+    }
+
+    /// Export a device from the given usbip host; the TCP port of the host is ignored
+    pub fn add_remote_device(&self, host: &str, device: UsbDevice) {
+        self.state()
+            .remote
+            .entry(host.to_string())
+            .or_default()
+            .push(device);
+    }
+
+    /// Set the serial number of a device that the usbip host exports
+    pub fn set_remote_serial(&self, host: &str, busid: &BusId, serial: &str) {
+        self.state()
+            .remote_serials
+            .insert((host.to_string(), busid.clone()), serial.to_string());
+    }
+
+    /// The current state of the local devices
+    pub fn local_devices(&self) -> Vec<LocalDevice> {
+        self.state().local.clone()
+    }
+
+    /// The current state of the vhci ports
+    pub fn ports(&self) -> Vec<AttachedDevice> {
+        self.state().ports.clone()
+    }
+
+    /// The busid of every attach request so far, including the failed ones
+    pub fn attach_requests(&self) -> Vec<BusId> {
+        self.state().attach_requests.clone()
+    }
+
+    /// Switch the driver of the local device and report whether it changed
+    fn set_driver(&self, busid: &BusId, bound: bool) -> anyhow::Result<BindOutcome> {
+        let mut state = self.state();
+        let device = state
+            .local
+            .iter_mut()
+            .find(|d| d.busid() == busid)
+            .ok_or_else(|| anyhow!("There is no local device with busid {busid}"))?;
+        if (device.driver.as_deref() == Some(USBIP_HOST_DRIVER)) == bound {
+            return Ok(BindOutcome::Unchanged);
+        }
+        device.driver = Some(match bound {
+            true => USBIP_HOST_DRIVER.to_string(),
+            false => "usb".to_string(),
+        });
+        Ok(BindOutcome::Changed)
+    }
+}
+
+impl Backend for FakeBackend {
+    fn list_local(&self) -> anyhow::Result<Vec<LocalDevice>> {
+        Ok(self.local_devices())
+    }
+
+    fn bind(&self, busid: &BusId) -> anyhow::Result<BindOutcome> {
+        self.set_driver(busid, true)
+    }
+
+    fn unbind(&self, busid: &BusId) -> anyhow::Result<BindOutcome> {
+        self.set_driver(busid, false)
+    }
+
+    fn list_remote(&self, host: &str, tcp_port: u16) -> anyhow::Result<Vec<UsbDevice>> {
+        let devices = self.state().remote.get(host).cloned();
+        devices.ok_or_else(|| {
+            Error::HostUnreachable {
+                host: host.to_string(),
+                tcp_port,
+                source: io::ErrorKind::ConnectionRefused.into(),
+            }
+            .into()
+        })
+    }
+
+    fn attach(
+        &self,
+        host: &str,
+        tcp_port: u16,
+        busid: &BusId,
+        serials: &[&str],
+    ) -> Result<Port, AttachError> {
+        let mut state = self.state();
+        state.attach_requests.push(busid.clone());
+        let device = state
+            .remote
+            .get(host)
+            .ok_or_else(|| anyhow!("Could not connect to {host}"))?
+            .iter()
+            .find(|d| &d.busid == busid)
+            .ok_or_else(|| AttachError::NoSuchDevice(busid.clone()))?
+            .clone();
+        let in_use = state.ports.iter().any(|p| {
+            p.remote
+                .as_ref()
+                .is_some_and(|r| r.host == host && &r.busid == busid)
+        });
+        if in_use {
+            return Err(AttachError::DeviceBusy(busid.clone()));
+        }
+        let serial = state
+            .remote_serials
+            .get(&(host.to_string(), busid.clone()))
+            .cloned();
+        if !serial_matches(serials, serial.as_deref()) {
+            return Err(AttachError::SerialMismatch(busid.clone()));
+        }
+        let port = (0..)
+            .map(|n: u32| Port(n.to_string()))
+            .find(|n| !state.ports.iter().any(|p| &p.port == n))
+            .expect("there is always a free port");
+        state.ports.push(AttachedDevice {
+            port: port.clone(),
+            usb_id: Some(device.usb_id()),
+            serial,
+            remote: Some(RemoteConnection {
+                host: host.to_string(),
+                tcp_port: tcp_port.to_string(),
+                busid: busid.clone(),
+            }),
+        });
+        Ok(port)
+    }
+
+    fn detach(&self, port: &Port) -> anyhow::Result<()> {
+        let mut state = self.state();
+        let before = state.ports.len();
+        state.ports.retain(|p| &p.port != port);
+        match state.ports.len() == before {
+            true => Err(anyhow!("Port {port} is not in use")),
+            false => Ok(()),
+        }
+    }
+
+    fn list_ports(&self) -> anyhow::Result<Vec<AttachedDevice>> {
+        Ok(self.ports())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UsbId;
+
+    fn remote_device(busid: &str) -> UsbDevice {
+        UsbDevice {
+            busid: BusId(busid.to_string()),
+            id_vendor: 0x1050,
+            id_product: 0x0407,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_attach_detach() {
+        let fake = FakeBackend::new();
+        fake.add_remote_device("laptop", remote_device("1-1"));
+        fake.add_remote_device("laptop", remote_device("1-2"));
+        let busid = BusId("1-2".to_string());
+        fake.set_remote_serial("laptop", &busid, "CC1234");
+        assert!(matches!(
+            fake.attach("laptop", 3240, &busid, &["CC5678"]),
+            Err(AttachError::SerialMismatch(_))
+        ));
+        let port = fake.attach("laptop", 3240, &busid, &["CC1234"]).unwrap();
+        assert_eq!(port, Port("0".to_string()));
+        assert!(matches!(
+            fake.attach("laptop", 3240, &busid, &[]),
+            Err(AttachError::DeviceBusy(_))
+        ));
+        assert!(matches!(
+            fake.attach("laptop", 3240, &BusId("1-3".to_string()), &[]),
+            Err(AttachError::NoSuchDevice(_))
+        ));
+        assert!(matches!(
+            fake.attach("desktop", 3240, &busid, &[]),
+            Err(AttachError::Other(_))
+        ));
+        assert_eq!(
+            fake.list_ports().unwrap()[0].usb_id,
+            Some(UsbId::new(0x1050, 0x0407))
+        );
+        assert_eq!(
+            fake.list_ports().unwrap()[0].serial.as_deref(),
+            Some("CC1234")
+        );
+        fake.detach(&port).unwrap();
+        assert!(fake.list_ports().unwrap().is_empty());
+        assert!(fake.detach(&port).is_err());
+    }
+}
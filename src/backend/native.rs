@@ -0,0 +1,137 @@
+//! Backend that talks to the kernel and the usbip host directly.
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use super::{AttachedDevice, Backend};
+use crate::protocol::{self, UsbDevice};
+use crate::sysfs::{LocalDevice, Sysfs};
+use crate::usbip_host::{self, BindOutcome};
+use crate::vhci::{AttachError, Vhci, VHCI_STATE_PATH};
+use crate::{BusId, Port};
+
+#[derive(Debug, Clone)]
+pub struct NativeBackend {
+    sysfs: Sysfs,
+    vhci: Vhci,
+}
+
+impl NativeBackend {
+    pub fn new(sysfs: Sysfs) -> Self {
+        NativeBackend::with_state_dir(sysfs, VHCI_STATE_PATH)
+    }
+
+    /// Use a different directory to record the remote end of the attached devices
+    pub fn with_state_dir(sysfs: Sysfs, state_dir: impl Into<PathBuf>) -> Self {
+        NativeBackend {
+            vhci: Vhci::new(sysfs.clone(), state_dir),
+            sysfs,
+        }
+    }
+}
+
+impl Backend for NativeBackend {
+    fn list_local(&self) -> anyhow::Result<Vec<LocalDevice>> {
+        self.sysfs.list_devices()
+    }
+
+    fn bind(&self, busid: &BusId) -> anyhow::Result<BindOutcome> {
+        usbip_host::bind(&self.sysfs, busid)
+    }
+
+    fn unbind(&self, busid: &BusId) -> anyhow::Result<BindOutcome> {
+        usbip_host::unbind(&self.sysfs, busid)
+    }
+
+    fn list_remote(&self, host: &str, tcp_port: u16) -> anyhow::Result<Vec<UsbDevice>> {
+        protocol::list_remote(host, tcp_port)
+    }
+
+    fn attach(
+        &self,
+        host: &str,
+        tcp_port: u16,
+        busid: &BusId,
+        serials: &[&str],
+    ) -> Result<Port, AttachError> {
+        let port = self.vhci.attach(host, tcp_port, busid, serials)?;
+        Ok(Port(port.to_string()))
+    }
+
+    fn detach(&self, port: &Port) -> anyhow::Result<()> {
+        let port = port
+            .0
+            .parse::<u32>()
+            .with_context(|| format!("Invalid port `{port}`"))?;
+        Ok(self.vhci.detach(port)?)
+    }
+
+    fn list_ports(&self) -> anyhow::Result<Vec<AttachedDevice>> {
+        Ok(self
+            .vhci
+            .used_ports()?
+            .into_iter()
+            .map(|p| {
+                let device = p
+                    .local_busid
+                    .as_ref()
+                    .and_then(|b| self.sysfs.device(b).ok());
+                AttachedDevice {
+                    port: Port(p.port.to_string()),
+                    usb_id: device.as_ref().map(|d| d.usb_id()),
+                    serial: device.and_then(|d| d.serial),
+                    remote: p.remote,
+                }
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysfs::tests::FakeSysfs;
+    use crate::UsbId;
+
+    #[test]
+    fn test_list_ports() {
+        let fake = FakeSysfs::new();
+        fake.set_platform_attr(
+            "vhci_hcd.0",
+            "status",
+            "\
+hub port sta spd dev      sockfd local_busid
+hs  0000 006 002 0001000b 000003 3-1
+hs  0001 004 000 00000000 000000 0-0
+hs  0002 006 002 0001000d 000005 3-3
+",
+        );
+        fake.add_device("3-1", "1050", "0407");
+        // 3-3 is not enumerated yet
+        let backend = NativeBackend::with_state_dir(fake.sysfs(), fake.root().join("state"));
+        assert_eq!(
+            backend.list_ports().unwrap(),
+            vec![
+                AttachedDevice {
+                    port: Port("0".to_string()),
+                    usb_id: Some(UsbId::new(0x1050, 0x0407)),
+                    serial: None,
+                    remote: None,
+                },
+                AttachedDevice {
+                    port: Port("2".to_string()),
+                    usb_id: None,
+                    serial: None,
+                    remote: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_detach_invalid_port() {
+        let fake = FakeSysfs::new();
+        let backend = NativeBackend::new(fake.sysfs());
+        assert!(backend.detach(&Port("abc".to_string())).is_err());
+    }
+}
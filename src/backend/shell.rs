@@ -0,0 +1,459 @@
+//! Backend that calls the `usbip` executable and parses its human readable output.
+use std::io;
+use std::process::Output;
+
+use anyhow::{anyhow, Context};
+use log::debug;
+use regex::Regex;
+use xshell::{cmd, Cmd, Shell};
+
+use super::{AttachedDevice, Backend};
+use crate::protocol::{UsbDevice, UsbInterface};
+use crate::sysfs::LocalDevice;
+use crate::usbip_host::BindOutcome;
+use crate::vhci::{AttachError, RemoteConnection};
+use crate::{BusId, Error, Port, UsbId};
+
+pub struct ShellBackend {
+    sh: Shell,
+}
+
+/// Quickly check the `usbip` version and provide additional information
+/// if the executable cannot be found.
+fn check_usbip_version(sh: &Shell) -> Result<(), Error> {
+    let version = cmd!(sh, "usbip version").read().map_err(|e| {
+        debug!("Could not determine installed usbip version: {e}");
+        Error::UsbipMissing { program: "usbip" }
+    })?;
+    debug!("usbip version is: {version}");
+    Ok(())
+}
+
+/// `usbip` prints `unknown vendor`/`unknown product` if the id is not in its database
+fn known_name(name: &str) -> Option<String> {
+    match name.starts_with("unknown ") {
+        true => None,
+        false => Some(name.to_string()),
+    }
+}
+
+fn parse_hex<T: TryFrom<u32>>(s: &str) -> T {
+    // the regular expressions only match valid hex values of the correct length
+    u32::from_str_radix(s, 16)
+        .ok()
+        .and_then(|v| T::try_from(v).ok())
+        .unwrap_or_else(|| unreachable!("invalid hex value `{s}`"))
+}
+
+/// Parse the output of `usbip list --local`
+fn parse_list_local(stdout: &str) -> Vec<LocalDevice> {
+    let re = Regex::new(
+        r"(?m)^\s*- busid (?P<busid>\S+) \((?P<vid>[0-9a-f]{4}):(?P<pid>[0-9a-f]{4})\)\n\s+(?P<vendor>.*?) : (?P<product>.*) \([0-9a-f]{4}:[0-9a-f]{4}\)$",
+    )
+    .unwrap();
+    re.captures_iter(stdout)
+        .map(|cap| LocalDevice {
+            info: UsbDevice {
+                busid: BusId(cap["busid"].to_string()),
+                id_vendor: parse_hex(&cap["vid"]),
+                id_product: parse_hex(&cap["pid"]),
+                ..Default::default()
+            },
+            manufacturer: known_name(&cap["vendor"]),
+            product: known_name(&cap["product"]),
+            serial: None,
+            // not shown by `usbip`
+            driver: None,
+        })
+        .collect()
+}
+
+/// Parse the output of `usbip list --remote`
+fn parse_list_remote(stdout: &str) -> Vec<UsbDevice> {
+    let device_re = Regex::new(
+        r"^\s+(?P<busid>[0-9][0-9.\-]*): .* \((?P<vid>[0-9a-f]{4}):(?P<pid>[0-9a-f]{4})\)$",
+    )
+    .unwrap();
+    let class_re = Regex::new(
+        r"\((?P<class>[0-9a-f]{2})/(?P<subclass>[0-9a-f]{2})/(?P<protocol>[0-9a-f]{2})\)$",
+    )
+    .unwrap();
+    let mut devices: Vec<UsbDevice> = Vec::new();
+    // number of detail lines (path, class, interfaces) of the current device
+    let mut details = 0;
+    for line in stdout.lines() {
+        if let Some(cap) = device_re.captures(line) {
+            devices.push(UsbDevice {
+                busid: BusId(cap["busid"].to_string()),
+                id_vendor: parse_hex(&cap["vid"]),
+                id_product: parse_hex(&cap["pid"]),
+                ..Default::default()
+            });
+            details = 0;
+            continue;
+        }
+        let (Some(device), Some(detail)) = (devices.last_mut(), line.trim().strip_prefix(':'))
+        else {
+            continue;
+        };
+        let detail = detail.trim();
+        let class = class_re.captures(detail);
+        match (details, class) {
+            (0, _) => device.path = detail.to_string(),
+            (1, Some(cap)) => {
+                device.device_class = parse_hex(&cap["class"]);
+                device.device_subclass = parse_hex(&cap["subclass"]);
+                device.device_protocol = parse_hex(&cap["protocol"]);
+            }
+            (_, Some(cap)) => device.interfaces.push(UsbInterface {
+                class: parse_hex(&cap["class"]),
+                subclass: parse_hex(&cap["subclass"]),
+                protocol: parse_hex(&cap["protocol"]),
+            }),
+            (_, None) => debug!("Ignoring unexpected line `{line}`"),
+        }
+        details += 1;
+    }
+    devices
+}
+
+/// Parse the output of `usbip port`
+fn parse_port(stdout: &str) -> Vec<AttachedDevice> {
+    let port_re = Regex::new(r"^Port (?P<port>\d+):").unwrap();
+    let usbid_re = Regex::new(r"\((?P<vid>[0-9a-f]{4}):(?P<pid>[0-9a-f]{4})\)$").unwrap();
+    let remote_re =
+        Regex::new(r"-> usbip://(?P<host>.+):(?P<tcp_port>\d+)/(?P<busid>\S+)$").unwrap();
+    let mut ports: Vec<AttachedDevice> = Vec::new();
+    for line in stdout.lines() {
+        if let Some(cap) = port_re.captures(line) {
+            // `usbip` pads the port number with zeros
+            let port = cap["port"].parse::<u32>().unwrap_or_default();
+            ports.push(AttachedDevice {
+                port: Port(port.to_string()),
+                usb_id: None,
+                // `usbip port` does not show the serial number
+                serial: None,
+                remote: None,
+            });
+            continue;
+        }
+        let Some(attached) = ports.last_mut() else {
+            continue;
+        };
+        if let Some(cap) = remote_re.captures(line) {
+            attached.remote = Some(RemoteConnection {
+                host: cap["host"].to_string(),
+                tcp_port: cap["tcp_port"].to_string(),
+                busid: BusId(cap["busid"].to_string()),
+            });
+        } else if let Some(cap) = usbid_re.captures(line) {
+            attached
+                .usb_id
+                .get_or_insert_with(|| UsbId::new(parse_hex(&cap["vid"]), parse_hex(&cap["pid"])));
+        }
+    }
+    ports
+}
+
+/// Map the error message of `usbip attach` to the matching error
+fn attach_error(busid: &BusId, stderr: &str) -> AttachError {
+    if stderr.contains("open vhci_driver") {
+        AttachError::MissingDriver
+    } else if stderr.contains("Device busy") {
+        AttachError::DeviceBusy(busid.clone())
+    } else if stderr.contains("Device not found") {
+        AttachError::NoSuchDevice(busid.clone())
+    } else {
+        AttachError::Other(anyhow!("`usbip attach` failed: {}", stderr.trim()))
+    }
+}
+
+impl ShellBackend {
+    /// Fails if `usbip` cannot be found
+    pub fn new() -> Result<Self, Error> {
+        let sh = Shell::new().map_err(anyhow::Error::from)?;
+        check_usbip_version(&sh)?;
+        Ok(ShellBackend { sh })
+    }
+
+    /// Run the command and return its output, even if it failed
+    fn output(&self, cmd: Cmd) -> anyhow::Result<Output> {
+        let description = cmd.to_string();
+        debug!("running `{description}`");
+        cmd.ignore_status()
+            .quiet()
+            .output()
+            .with_context(|| format!("Could not run `{description}`"))
+    }
+
+    /// Run the command and return its stdout, fails if the command failed
+    fn read(&self, cmd: Cmd) -> anyhow::Result<String> {
+        let description = cmd.to_string();
+        let output = self.output(cmd)?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "`{description}` failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    /// (Un)bind the device, where `unchanged` is the error message of `usbip`
+    /// if the device is already in the requested state
+    fn bind_cmd(&self, cmd: Cmd, unchanged: &str) -> anyhow::Result<BindOutcome> {
+        let output = self.output(cmd)?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains(unchanged) {
+            return Ok(BindOutcome::Unchanged);
+        }
+        if !output.status.success() || stderr.contains("error: ") {
+            return Err(anyhow!("Unknown error message: {}", stderr.trim()));
+        }
+        Ok(BindOutcome::Changed)
+    }
+}
+
+impl Backend for ShellBackend {
+    fn list_local(&self) -> anyhow::Result<Vec<LocalDevice>> {
+        let sh = &self.sh;
+        Ok(parse_list_local(
+            &self.read(cmd!(sh, "usbip list --local"))?,
+        ))
+    }
+
+    fn bind(&self, busid: &BusId) -> anyhow::Result<BindOutcome> {
+        let (sh, busid) = (&self.sh, &busid.0);
+        self.bind_cmd(
+            cmd!(sh, "usbip bind --busid={busid}"),
+            "already bound to usbip-host",
+        )
+    }
+
+    fn unbind(&self, busid: &BusId) -> anyhow::Result<BindOutcome> {
+        let (sh, busid) = (&self.sh, &busid.0);
+        self.bind_cmd(
+            cmd!(sh, "usbip unbind --busid={busid}"),
+            "device is not bound to usbip-host",
+        )
+    }
+
+    fn list_remote(&self, host: &str, tcp_port: u16) -> anyhow::Result<Vec<UsbDevice>> {
+        let sh = &self.sh;
+        let tcp_port_s = tcp_port.to_string();
+        let output = self.output(cmd!(
+            sh,
+            "usbip --tcp-port={tcp_port_s} list --remote={host}"
+        ))?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("could not connect") {
+            return Err(Error::HostUnreachable {
+                host: host.to_string(),
+                tcp_port,
+                source: io::Error::new(io::ErrorKind::ConnectionRefused, stderr.trim()),
+            }
+            .into());
+        }
+        if !output.status.success() {
+            return Err(anyhow!("`usbip list --remote` failed: {}", stderr.trim()));
+        }
+        Ok(parse_list_remote(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    fn attach(
+        &self,
+        host: &str,
+        tcp_port: u16,
+        busid: &BusId,
+        serials: &[&str],
+    ) -> Result<Port, AttachError> {
+        if !serials.is_empty() {
+            return Err(anyhow!(
+                "`usbip` cannot read the serial number of {busid}, \
+                 use `--backend native` to select remote devices by serial number"
+            )
+            .into());
+        }
+        let sh = &self.sh;
+        let before = self.list_ports()?;
+        let (tcp_port_s, busid_s) = (tcp_port.to_string(), &busid.0);
+        let output = self.output(cmd!(
+            sh,
+            "usbip --tcp-port={tcp_port_s} attach --remote={host} --busid={busid_s}"
+        ))?;
+        if !output.status.success() {
+            return Err(attach_error(
+                busid,
+                &String::from_utf8_lossy(&output.stderr),
+            ));
+        }
+        // `usbip attach` does not tell which port it used
+        let new_ports = self
+            .list_ports()?
+            .into_iter()
+            .filter(|p| !before.iter().any(|b| b.port == p.port))
+            .collect::<Vec<_>>();
+        new_ports
+            .iter()
+            .find(|p| p.remote.as_ref().map(|r| &r.busid) == Some(busid))
+            .or(new_ports.first())
+            .map(|p| p.port.clone())
+            .ok_or_else(|| anyhow!("Could not find the port that {busid} was attached to").into())
+    }
+
+    fn detach(&self, port: &Port) -> anyhow::Result<()> {
+        let (sh, port) = (&self.sh, &port.0);
+        self.read(cmd!(sh, "usbip detach --port={port}"))?;
+        Ok(())
+    }
+
+    fn list_ports(&self) -> anyhow::Result<Vec<AttachedDevice>> {
+        let sh = &self.sh;
+        Ok(parse_port(&self.read(cmd!(sh, "usbip port"))?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIST_LOCAL: &str = "\
+ - busid 1-1 (1050:0407)
+   Yubico.com : Yubikey 4/5 OTP+U2F+CCID (1050:0407)
+
+ - busid 1-4.3.4 (0bda:402e)
+   Realtek Semiconductor Corp. : unknown product (0bda:402e)
+
+";
+
+    const LIST_REMOTE: &str = "\
+Exportable USB devices
+======================
+ - 10.0.0.2
+        1-1: Yubico.com : Yubikey 4/5 OTP+U2F+CCID (1050:0407)
+           : /sys/devices/pci0000:00/0000:00:14.0/usb1/1-1
+           : (Defined at Interface level) (00/00/00)
+           :  0 - Human Interface Device / Boot Interface Subclass / Keyboard (03/01/01)
+           :  1 - Human Interface Device / No Subclass / None (03/00/00)
+
+    1-4.3.4: Realtek Semiconductor Corp. : unknown product (0bda:402e)
+           : /sys/devices/pci0000:00/0000:00:14.0/usb1/1-4/1-4.3/1-4.3.4
+           : (Defined at Interface level) (00/00/00)
+           :  0 - Audio / Control Device / unknown protocol (01/01/00)
+
+";
+
+    const PORT: &str = "\
+Imported USB devices
+====================
+Port 00: <Port in Use> at Full Speed(12Mbps)
+       Yubico.com : Yubikey 4/5 OTP+U2F+CCID (1050:0407)
+       3-1 -> usbip://10.0.0.2:3240/1-1
+           -> remote bus/dev 001/002
+Port 09: <Port in Use> at High Speed(480Mbps)
+       unknown vendor : unknown product (0000:0000)
+       3-2 -> unknown host, remote port and remote busid
+           -> remote bus/dev 001/003
+";
+
+    #[test]
+    fn test_parse_list_local() {
+        let devices = parse_list_local(LIST_LOCAL);
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].busid(), &BusId("1-1".to_string()));
+        assert_eq!(devices[0].usb_id(), UsbId::new(0x1050, 0x0407));
+        assert_eq!(devices[0].manufacturer.as_deref(), Some("Yubico.com"));
+        assert_eq!(
+            devices[0].product.as_deref(),
+            Some("Yubikey 4/5 OTP+U2F+CCID")
+        );
+        assert_eq!(devices[1].busid(), &BusId("1-4.3.4".to_string()));
+        assert_eq!(devices[1].product, None);
+        assert!(parse_list_local("").is_empty());
+    }
+
+    #[test]
+    fn test_parse_list_remote() {
+        let devices = parse_list_remote(LIST_REMOTE);
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].busid, BusId("1-1".to_string()));
+        assert_eq!(devices[0].usb_id(), UsbId::new(0x1050, 0x0407));
+        assert_eq!(
+            devices[0].path,
+            "/sys/devices/pci0000:00/0000:00:14.0/usb1/1-1"
+        );
+        assert_eq!(devices[0].device_class, 0);
+        assert_eq!(
+            devices[0].interfaces,
+            vec![
+                UsbInterface {
+                    class: 3,
+                    subclass: 1,
+                    protocol: 1
+                },
+                UsbInterface {
+                    class: 3,
+                    subclass: 0,
+                    protocol: 0
+                },
+            ]
+        );
+        assert_eq!(devices[1].busid, BusId("1-4.3.4".to_string()));
+        assert_eq!(devices[1].interfaces.len(), 1);
+        assert!(parse_list_remote("").is_empty());
+    }
+
+    #[test]
+    fn test_parse_port() {
+        let ports = parse_port(PORT);
+        assert_eq!(
+            ports,
+            vec![
+                AttachedDevice {
+                    port: Port("0".to_string()),
+                    usb_id: Some(UsbId::new(0x1050, 0x0407)),
+                    serial: None,
+                    remote: Some(RemoteConnection {
+                        host: "10.0.0.2".to_string(),
+                        tcp_port: "3240".to_string(),
+                        busid: BusId("1-1".to_string()),
+                    }),
+                },
+                AttachedDevice {
+                    port: Port("9".to_string()),
+                    usb_id: Some(UsbId::new(0x0000, 0x0000)),
+                    serial: None,
+                    remote: None,
+                },
+            ]
+        );
+        assert!(parse_port("Imported USB devices\n====================\n").is_empty());
+    }
+
+    #[test]
+    fn test_attach_error() {
+        let busid = BusId("1-1".to_string());
+        assert!(matches!(
+            attach_error(
+                &busid,
+                "usbip: error: Attach Request for 1-1 failed - Device busy (exported)"
+            ),
+            AttachError::DeviceBusy(_)
+        ));
+        assert!(matches!(
+            attach_error(
+                &busid,
+                "usbip: error: Attach Request for 1-1 failed - Device not found"
+            ),
+            AttachError::NoSuchDevice(_)
+        ));
+        assert!(matches!(
+            attach_error(&busid, "libusbip: error: udev_device_new_from_subsystem_sysname failed\nusbip: error: open vhci_driver"),
+            AttachError::MissingDriver
+        ));
+        assert!(matches!(
+            attach_error(&busid, "usbip: error: tcp connect"),
+            AttachError::Other(_)
+        ));
+    }
+}
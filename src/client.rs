@@ -0,0 +1,709 @@
+//! Mount the USB devices of a remote host through `vhci_hcd`.
+use std::collections::HashSet;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+
+use crate::backend::{AttachedDevice, Backend};
+use crate::dry_run::{Operation, PlannedOperation};
+use crate::error::for_each_target;
+use crate::list::{DeviceRecord, ListMountable, ListUnmountable};
+use crate::protocol::UsbDevice;
+use crate::selector::{Candidate, MatchCount, Selector};
+use crate::vhci::AttachError;
+use crate::{serial_matches, BusId, Error, Port, UsbId, UsbIdPattern};
+
+/// Whether the remote device was attached or already in use
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MountOutcome {
+    /// Attached to the given vhci port
+    Attached(Port),
+    /// Most likely it was already mounted by a previous call
+    AlreadyInUse,
+}
+
+/// The remote device that was mounted
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MountResult {
+    pub busid: BusId,
+    pub usb_id: UsbId,
+    pub outcome: MountOutcome,
+}
+
+/// First delay between two attempts of [`Client::mount_waiting`], doubled after every attempt
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+/// Exponentially growing delays until the deadline
+#[derive(Debug)]
+struct Backoff {
+    deadline: Instant,
+    delay: Duration,
+}
+
+impl Backoff {
+    fn new(timeout: Duration, now: Instant) -> Self {
+        Backoff {
+            deadline: now + timeout,
+            delay: INITIAL_BACKOFF,
+        }
+    }
+
+    /// The time to wait before the next attempt, `None` once the deadline has passed.
+    /// The last delay is shortened so that the last attempt happens at the deadline.
+    fn next(&mut self, now: Instant) -> Option<Duration> {
+        let remaining = self
+            .deadline
+            .checked_duration_since(now)
+            .filter(|r| !r.is_zero())?;
+        let delay = self.delay.min(remaining);
+        self.delay = (self.delay * 2).min(MAX_BACKOFF);
+        Some(delay)
+    }
+}
+
+/// The vhci port that was detached
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UnmountResult {
+    pub port: Port,
+    pub usb_id: UsbId,
+}
+
+impl UnmountResult {
+    pub fn record(&self) -> DeviceRecord {
+        DeviceRecord {
+            port: Some(self.port.to_string()),
+            ..DeviceRecord::with_usb_id(&self.usb_id)
+        }
+    }
+}
+
+/// The machine that mounts the USB devices of a remote host
+#[derive(Debug, Clone)]
+pub struct Client<B> {
+    backend: B,
+}
+
+impl<B: Backend> Client<B> {
+    pub fn new(backend: B) -> Self {
+        Client { backend }
+    }
+
+    /// All devices that the usbip host exports
+    pub fn list_mountable(&self, host: &str, tcp_port: u16) -> anyhow::Result<ListMountable> {
+        ListMountable::new(&self.backend, host, tcp_port)
+    }
+
+    /// All remote devices that are currently mounted
+    pub fn list_unmountable(&self) -> anyhow::Result<ListUnmountable> {
+        ListUnmountable::new(&self.backend)
+    }
+
+    /// The remote devices that the selector selects, without mounting anything.
+    /// The serial numbers are unknown until the devices are imported and thus not checked.
+    pub fn select_mountable(
+        &self,
+        host: &str,
+        tcp_port: u16,
+        selector: &Selector,
+    ) -> anyhow::Result<Vec<(BusId, UsbId)>> {
+        let list = self.list_mountable(host, tcp_port)?;
+        let matched = list
+            .devices
+            .iter()
+            .filter(|d| selector.matches_ignoring_serial(&remote_candidate(d)))
+            .map(|d| (d.busid.clone(), d.usb_id()))
+            .collect::<Vec<_>>();
+        debug!("Matched Busids: {matched:?}");
+        Ok(matched)
+    }
+
+    /// The mounted devices that the selector selects, without unmounting anything
+    pub fn select_unmountable(&self, selector: &Selector) -> anyhow::Result<Vec<(Port, UsbId)>> {
+        let list = self.list_unmountable()?;
+        let matched = selector.select(
+            list.ports
+                .iter()
+                .filter_map(|p| Some((p.port.clone(), port_candidate(p)?))),
+        );
+        debug!("Matched Ports: {matched:?}");
+        Ok(matched)
+    }
+
+    /// The operations that [`Client::mount_selected`] would run, without mounting anything.
+    /// Devices are only attached if they have one of the required serial numbers.
+    pub fn plan_mount(
+        &self,
+        host: &str,
+        tcp_port: u16,
+        selector: &Selector,
+    ) -> anyhow::Result<Vec<PlannedOperation>> {
+        let list = self.list_mountable(host, tcp_port)?;
+        Ok(list
+            .devices
+            .iter()
+            .zip(list.records())
+            .filter(|(d, _)| selector.matches_ignoring_serial(&remote_candidate(d)))
+            .map(|(d, record)| PlannedOperation {
+                required_serials: selector
+                    .required_serials(&d.usb_id())
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                ..PlannedOperation::new(Operation::Attach, record)
+            })
+            .collect())
+    }
+
+    /// The operations that [`Client::unmount_selected`] would run, without unmounting anything
+    pub fn plan_unmount(&self, selector: &Selector) -> anyhow::Result<Vec<PlannedOperation>> {
+        let list = self.list_unmountable()?;
+        Ok(list
+            .ports
+            .iter()
+            .zip(list.records())
+            .filter(|(p, _)| port_candidate(p).is_some_and(|c| selector.matches(&c)))
+            .map(|(_, record)| PlannedOperation::new(Operation::Detach, record))
+            .collect())
+    }
+
+    /// Mount all remote devices with one of the given ids.
+    /// Not specifying any id will mount all remotely available devices!
+    ///
+    /// The usbip host does not list the serial numbers, so devices are imported
+    /// to read their serial number if one of the ids asks for one.
+    pub fn mount(
+        &self,
+        host: &str,
+        tcp_port: u16,
+        usb_ids: &[UsbIdPattern],
+    ) -> Result<Vec<MountResult>, Error> {
+        self.mount_selected(host, tcp_port, &usb_ids.into())
+    }
+
+    /// Mount all selected remote devices, the busids are the ones of the usbip host.
+    /// An empty selector will mount all remotely available devices!
+    pub fn mount_selected(
+        &self,
+        host: &str,
+        tcp_port: u16,
+        selector: &Selector,
+    ) -> Result<Vec<MountResult>, Error> {
+        let matched_busids = self.select_mountable(host, tcp_port, selector)?;
+        self.mount_matched(host, tcp_port, selector, &matched_busids)
+    }
+
+    /// Mount the remote devices that [`Client::select_mountable`] returned,
+    /// so that the devices that were checked beforehand are the ones that are mounted
+    pub fn mount_matched(
+        &self,
+        host: &str,
+        tcp_port: u16,
+        selector: &Selector,
+        matched_busids: &[(BusId, UsbId)],
+    ) -> Result<Vec<MountResult>, Error> {
+        if matched_busids.is_empty() {
+            return Err(Error::NoMatchingDevice);
+        }
+        let ports = self.attached_ports(selector)?;
+        let mounted = for_each_target(matched_busids.iter().map(|(b, _)| b), |b| {
+            let usb_id = matched_busids
+                .iter()
+                .find_map(|(busid, usb_id)| (busid == *b).then_some(*usb_id))
+                .expect("the busid was selected");
+            self.mount_one(host, tcp_port, selector, &ports, b, usb_id)
+        })?;
+        let mounted = mounted.into_iter().flatten().collect::<Vec<_>>();
+        match mounted.is_empty() {
+            true => Err(Error::NoMatchingDevice),
+            false => Ok(mounted),
+        }
+    }
+
+    /// Same as [`Client::mount_selected`] but retries with exponential backoff until every
+    /// requested USB ID and busid was mounted as often as expected,
+    /// e.g., while the usbip host is still booting.
+    /// Without USB IDs or busids, it waits for any matching device.
+    ///
+    /// Devices that fail to mount are tried again by the next attempt, only errors that
+    /// another attempt cannot fix, e.g., a missing kernel module, are returned right away.
+    /// Once the timeout has passed, it returns [`Error::WaitTimeout`] with the missing ids,
+    /// or the error of the last attempt if nothing was mounted.
+    pub fn mount_waiting(
+        &self,
+        host: &str,
+        tcp_port: u16,
+        selector: &Selector,
+        timeout: Duration,
+    ) -> Result<Vec<MountResult>, Error> {
+        let mut backoff = Backoff::new(timeout, Instant::now());
+        let mut mounted: Vec<MountResult> = Vec::new();
+        // reading the serial number resets the device, so every device is only checked once
+        let mut mismatched = HashSet::new();
+        loop {
+            // the errors of the latest attempt
+            let mut unreachable = None;
+            let mut failed = Vec::new();
+            match self.select_mountable(host, tcp_port, selector) {
+                Ok(matched) => {
+                    // unlike missing devices, too many do not go away by waiting
+                    let too_many = unmet_before_mount(selector, &matched)
+                        .into_iter()
+                        .filter(|m| !m.is_missing())
+                        .collect::<Vec<_>>();
+                    if !too_many.is_empty() {
+                        return Err(Error::UnmetExpectations { unmet: too_many });
+                    }
+                    let ports = self.attached_ports(selector)?;
+                    for (busid, usb_id) in &matched {
+                        if mismatched.contains(busid) {
+                            continue;
+                        }
+                        match self.mount_one(host, tcp_port, selector, &ports, busid, *usb_id) {
+                            Ok(Some(result)) => merge_mounted(&mut mounted, result),
+                            Ok(None) => {
+                                mismatched.insert(busid.clone());
+                            }
+                            Err(e) if is_permanent(&e) => return Err(e),
+                            Err(e) => {
+                                warn!("Could not mount {busid}, trying again: {e}");
+                                failed.push((busid.to_string(), e));
+                            }
+                        }
+                    }
+                }
+                Err(e) => match Error::from(e) {
+                    e if is_permanent(&e) => return Err(e),
+                    e => unreachable = Some(e),
+                },
+            }
+            let missing = missing_ids(selector, &mounted);
+            if missing.is_empty() && !mounted.is_empty() && failed.is_empty() {
+                return Ok(mounted);
+            }
+            match backoff.next(Instant::now()) {
+                Some(delay) => {
+                    info!("Waiting {delay:?} for {missing:?} to appear on {host}");
+                    thread::sleep(delay);
+                }
+                None if mounted.is_empty() => {
+                    // the host never answered or no device could be mounted,
+                    // which is more helpful than a timeout
+                    let last = unreachable.or_else(|| failed.pop().map(|(_, e)| e));
+                    return Err(last.unwrap_or(Error::WaitTimeout { missing }));
+                }
+                None if !missing.is_empty() => return Err(Error::WaitTimeout { missing }),
+                None => {
+                    for (t, e) in &failed {
+                        error!("{t}: {e}");
+                    }
+                    return Err(Error::PartialFailure {
+                        total: mounted.len() + failed.len(),
+                        failed,
+                    });
+                }
+            }
+        }
+    }
+
+    /// The attached ports, only needed to check the serial numbers of mounted devices
+    fn attached_ports(&self, selector: &Selector) -> anyhow::Result<Vec<AttachedDevice>> {
+        match selector.asks_for_serials() {
+            true => Ok(self.list_unmountable()?.ports),
+            false => Ok(Vec::new()),
+        }
+    }
+
+    /// Mount the remote device, `None` if its serial number is not one of the required ones
+    fn mount_one(
+        &self,
+        host: &str,
+        tcp_port: u16,
+        selector: &Selector,
+        ports: &[AttachedDevice],
+        busid: &BusId,
+        usb_id: UsbId,
+    ) -> Result<Option<MountResult>, Error> {
+        let serials = selector.required_serials(&usb_id);
+        // The serial number of a device that is already mounted is known locally
+        let attached = ports.iter().find(|p| {
+            p.remote
+                .as_ref()
+                .is_some_and(|r| r.host == host && &r.busid == busid)
+        });
+        if let Some(serial) = attached.and_then(|p| p.serial.as_deref()) {
+            if !serial_matches(&serials, Some(serial)) {
+                return Ok(None);
+            }
+        }
+        // What happens if the call is execute multiple times?
+        // Since every call has a unique busid it won't be called multiple times
+        // each follow-up call will again check for matching ids and won't find anything
+        let outcome = match self.backend.attach(host, tcp_port, busid, &serials) {
+            Ok(port) => {
+                debug!("attached {busid} to port {port}");
+                MountOutcome::Attached(port)
+            }
+            Err(AttachError::DeviceBusy(b)) => {
+                warn!("{b} is already in use, skipping");
+                MountOutcome::AlreadyInUse
+            }
+            Err(AttachError::SerialMismatch(b)) => {
+                debug!("{b} has a different serial number, skipping");
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(MountResult {
+            busid: busid.clone(),
+            usb_id,
+            outcome,
+        }))
+    }
+
+    /// Unmount all mounted devices with one of the given ids.
+    /// Not specifying any id will unmount all mounted devices!
+    pub fn unmount(&self, usb_ids: &[UsbIdPattern]) -> Result<Vec<UnmountResult>, Error> {
+        self.unmount_selected(&usb_ids.into())
+    }
+
+    /// Unmount all selected devices, the busids are the ones of the usbip host.
+    /// An empty selector will unmount all mounted devices!
+    pub fn unmount_selected(&self, selector: &Selector) -> Result<Vec<UnmountResult>, Error> {
+        let matched_ports = self.select_unmountable(selector)?;
+        if matched_ports.is_empty() {
+            return Err(Error::NoMatchingDevice);
+        }
+        for_each_target(matched_ports.iter().map(|(p, _)| p), |p| {
+            let usb_id = matched_ports
+                .iter()
+                .find_map(|(port, usb_id)| (port == *p).then_some(*usb_id))
+                .expect("the port was selected");
+            self.backend.detach(p)?;
+            debug!("detached {usb_id} from port {p}");
+            Ok(UnmountResult {
+                port: (*p).clone(),
+                usb_id,
+            })
+        })
+    }
+}
+
+/// Add the result of another attempt, a device that was attached
+/// by an earlier attempt is reported as in use by the later ones
+fn merge_mounted(mounted: &mut Vec<MountResult>, result: MountResult) {
+    match mounted.iter_mut().find(|m| m.busid == result.busid) {
+        Some(m) if m.outcome == MountOutcome::AlreadyInUse => *m = result,
+        Some(_) => {}
+        None => mounted.push(result),
+    }
+}
+
+/// Errors that another attempt cannot fix
+fn is_permanent(e: &Error) -> bool {
+    matches!(
+        e,
+        Error::UsbipMissing { .. } | Error::MissingKernelModule { .. } | Error::PermissionDenied(_)
+    )
+}
+
+/// The requested USB IDs and busids that the selected remote devices do not match as expected,
+/// before anything is mounted. The serial numbers are only known once a device is mounted,
+/// so ids that match more devices than expected only count if no serial number is asked for.
+pub fn unmet_before_mount(selector: &Selector, selected: &[(BusId, UsbId)]) -> Vec<MatchCount> {
+    let mut unmet = selector.unmet_expectations(selected.iter().map(|(b, u)| (b, *u)));
+    if selector.asks_for_serials() {
+        unmet.retain(MatchCount::is_missing);
+    }
+    unmet
+}
+
+/// The requested USB IDs and busids that were not mounted, or not as often as expected
+fn missing_ids(selector: &Selector, mounted: &[MountResult]) -> Vec<String> {
+    selector
+        .unmet_expectations(mounted.iter().map(|m| (&m.busid, m.usb_id)))
+        .into_iter()
+        .filter(|m| m.is_missing())
+        .map(|m| m.id.to_string())
+        .collect()
+}
+
+fn remote_candidate(device: &UsbDevice) -> Candidate<'_> {
+    Candidate {
+        usb_id: device.usb_id(),
+        busid: Some(&device.busid),
+        serial: None,
+        classes: Some(device.into()),
+    }
+}
+
+/// Ports whose device has not been enumerated yet cannot be selected
+fn port_candidate(port: &AttachedDevice) -> Option<Candidate<'_>> {
+    Some(Candidate {
+        usb_id: port.usb_id?,
+        busid: port.remote.as_ref().map(|r| &r.busid),
+        serial: port.serial.as_deref(),
+        classes: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::FakeBackend;
+    use crate::protocol::UsbDevice;
+
+    fn fake_remote() -> FakeBackend {
+        let fake = FakeBackend::new();
+        for (busid, id_product) in [("1-1", 0x0407), ("1-2", 0x0407), ("1-3", 0x9540)] {
+            fake.add_remote_device(
+                "laptop",
+                UsbDevice {
+                    busid: BusId(busid.to_string()),
+                    id_vendor: 0x1050,
+                    id_product,
+                    ..Default::default()
+                },
+            );
+        }
+        fake
+    }
+
+    #[test]
+    fn test_mount_unmount() {
+        let fake = fake_remote();
+        let client = Client::new(&fake);
+        let yubikey = [UsbId::new(0x1050, 0x0407).into()];
+        let mut results = client.mount("laptop", 3240, &yubikey).unwrap();
+        results.sort_by(|a, b| a.busid.0.cmp(&b.busid.0));
+        assert_eq!(results.len(), 2);
+        assert!(matches!(results[0].outcome, MountOutcome::Attached(_)));
+        assert!(matches!(results[1].outcome, MountOutcome::Attached(_)));
+        // mounting again is not an error
+        assert!(client
+            .mount("laptop", 3240, &yubikey)
+            .unwrap()
+            .iter()
+            .all(|r| r.outcome == MountOutcome::AlreadyInUse));
+        assert_eq!(fake.ports().len(), 2);
+
+        client.mount("laptop", 3240, &[]).unwrap();
+        assert_eq!(fake.ports().len(), 3);
+        let unmounted = client.unmount(&yubikey).unwrap();
+        assert_eq!(unmounted.len(), 2);
+        assert!(unmounted
+            .iter()
+            .all(|r| r.usb_id == UsbId::new(0x1050, 0x0407)));
+        assert_eq!(fake.ports().len(), 1);
+        client.unmount(&[]).unwrap();
+        assert!(fake.ports().is_empty());
+        assert!(client.unmount(&[]).is_err());
+    }
+
+    #[test]
+    fn test_mount_unmount_excluded() {
+        let fake = fake_remote();
+        let client = Client::new(&fake);
+        let all_but_reader = Selector {
+            excluded: vec!["1-3".parse().unwrap()],
+            ..Default::default()
+        };
+        let mounted = client
+            .mount_selected("laptop", 3240, &all_but_reader)
+            .unwrap();
+        assert_eq!(mounted.len(), 2);
+        assert!(mounted.iter().all(|m| m.busid.0 != "1-3"));
+        client.mount("laptop", 3240, &[]).unwrap();
+        let all_but_yubikeys = Selector {
+            excluded: vec!["1050:0407".parse().unwrap()],
+            ..Default::default()
+        };
+        let unmounted = client.unmount_selected(&all_but_yubikeys).unwrap();
+        assert_eq!(unmounted.len(), 1);
+        assert_eq!(unmounted[0].usb_id, UsbId::new(0x1050, 0x9540));
+        assert_eq!(fake.ports().len(), 2);
+    }
+
+    #[test]
+    fn test_plan_mount_unmount() {
+        let fake = fake_remote();
+        let client = Client::new(&fake);
+        let yubikey = Selector::from(&["1050:0407@CC1234".parse().unwrap()][..]);
+        let plan = client.plan_mount("laptop", 3240, &yubikey).unwrap();
+        let lines = plan.iter().map(|o| o.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "attach laptop/1-1 (1050:0407) if its serial number is CC1234",
+                "attach laptop/1-2 (1050:0407) if its serial number is CC1234"
+            ]
+        );
+        assert!(fake.ports().is_empty());
+        client.mount("laptop", 3240, &[]).unwrap();
+        let plan = client.plan_unmount(&Selector::default()).unwrap();
+        assert_eq!(plan.len(), 3);
+        assert!(plan.iter().all(|o| o.operation == Operation::Detach));
+        assert_eq!(fake.ports().len(), 3);
+    }
+
+    #[test]
+    fn test_mount_vendor_wildcard() {
+        let fake = fake_remote();
+        let client = Client::new(&fake);
+        let vendor = ["1050:*".parse::<UsbIdPattern>().unwrap()];
+        let mut mounted = client
+            .mount("laptop", 3240, &vendor)
+            .unwrap()
+            .into_iter()
+            .map(|m| (m.busid.0, m.usb_id))
+            .collect::<Vec<_>>();
+        mounted.sort();
+        assert_eq!(
+            mounted,
+            vec![
+                ("1-1".to_string(), UsbId::new(0x1050, 0x0407)),
+                ("1-2".to_string(), UsbId::new(0x1050, 0x0407)),
+                ("1-3".to_string(), UsbId::new(0x1050, 0x9540)),
+            ]
+        );
+        assert_eq!(client.unmount(&vendor).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_mount_serial() {
+        let fake = fake_remote();
+        fake.set_remote_serial("laptop", &BusId("1-1".to_string()), "CC1234");
+        fake.set_remote_serial("laptop", &BusId("1-2".to_string()), "CC5678");
+        let client = Client::new(&fake);
+        let yubikey = ["1050:0407@CC5678".parse::<UsbIdPattern>().unwrap()];
+        let mounted = client.mount("laptop", 3240, &yubikey).unwrap();
+        assert_eq!(mounted.len(), 1);
+        assert_eq!(mounted[0].busid, BusId("1-2".to_string()));
+        assert!(matches!(mounted[0].outcome, MountOutcome::Attached(_)));
+        // the mounted device is recognized by its serial number
+        let mounted = client.mount("laptop", 3240, &yubikey).unwrap();
+        assert_eq!(mounted[0].outcome, MountOutcome::AlreadyInUse);
+        assert_eq!(fake.ports().len(), 1);
+
+        client.mount("laptop", 3240, &[]).unwrap();
+        assert_eq!(client.unmount(&yubikey).unwrap().len(), 1);
+        assert_eq!(fake.ports().len(), 2);
+        assert!(matches!(
+            client.mount("laptop", 3240, &[yubikey[0].clone().with_serial("unknown")]),
+            Err(Error::NoMatchingDevice)
+        ));
+    }
+
+    #[test]
+    fn test_mount_unreachable_host() {
+        let fake = fake_remote();
+        assert!(Client::new(&fake).mount("desktop", 3240, &[]).is_err());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let start = Instant::now();
+        let mut backoff = Backoff::new(Duration::from_secs(10), start);
+        let delays = (0..4)
+            .map(|_| backoff.next(start).unwrap().as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, vec![250, 500, 1000, 2000]);
+        assert_eq!(backoff.next(start), Some(Duration::from_secs(4)));
+        assert_eq!(backoff.next(start), Some(Duration::from_secs(8)));
+        assert_eq!(backoff.next(start), Some(Duration::from_secs(8)));
+        // the last delay ends at the deadline
+        let late = start + Duration::from_secs(9);
+        assert_eq!(backoff.next(late), Some(Duration::from_secs(1)));
+        assert_eq!(backoff.next(start + Duration::from_secs(10)), None);
+    }
+
+    #[test]
+    fn test_mount_waiting() {
+        let fake = fake_remote();
+        let client = Client::new(&fake);
+        let reader = Selector::from(&["1050:9540".parse().unwrap()][..]);
+        let mounted = client
+            .mount_waiting("laptop", 3240, &reader, Duration::ZERO)
+            .unwrap();
+        assert_eq!(mounted.len(), 1);
+
+        let token = Selector::from(&["20a0:4108".parse().unwrap()][..]);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(300));
+                fake.add_remote_device(
+                    "laptop",
+                    UsbDevice {
+                        busid: BusId("1-4".to_string()),
+                        id_vendor: 0x20a0,
+                        id_product: 0x4108,
+                        ..Default::default()
+                    },
+                );
+            });
+            let mounted = client
+                .mount_waiting("laptop", 3240, &token, Duration::from_secs(10))
+                .unwrap();
+            assert_eq!(mounted[0].busid, BusId("1-4".to_string()));
+        });
+
+        let missing =
+            Selector::from(&["1050:9540".parse().unwrap(), "dead:beef".parse().unwrap()][..]);
+        let timeout = Duration::from_millis(100);
+        match client.mount_waiting("laptop", 3240, &missing, timeout) {
+            Err(Error::WaitTimeout { missing }) => assert_eq!(missing, vec!["dead:beef"]),
+            r => panic!("unexpected result {r:?}"),
+        }
+        assert!(matches!(
+            client.mount_waiting("desktop", 3240, &reader, timeout),
+            Err(Error::HostUnreachable { .. })
+        ));
+
+        // too many devices fail right away, before any of them is mounted
+        let one_token = Selector {
+            usb_ids: vec!["1050:0407".parse().unwrap()],
+            expected: vec!["1050:0407=1".parse().unwrap()],
+            ..Default::default()
+        };
+        let ports = fake.ports().len();
+        let long = Duration::from_secs(10);
+        assert!(matches!(
+            client.mount_waiting("laptop", 3240, &one_token, long),
+            Err(Error::UnmetExpectations { .. })
+        ));
+        assert_eq!(fake.ports().len(), ports);
+    }
+
+    #[test]
+    fn test_mount_waiting_serial_mismatch() {
+        let fake = fake_remote();
+        fake.set_remote_serial("laptop", &BusId("1-1".to_string()), "CC1111");
+        fake.set_remote_serial("laptop", &BusId("1-2".to_string()), "CC2222");
+        let client = Client::new(&fake);
+        let selector = Selector::from(
+            &[
+                "1050:0407@CC2222".parse().unwrap(),
+                "20a0:4108".parse().unwrap(),
+            ][..],
+        );
+        let timeout = Duration::from_millis(600);
+        assert!(matches!(
+            client.mount_waiting("laptop", 3240, &selector, timeout),
+            Err(Error::WaitTimeout { .. })
+        ));
+        // the other YubiKey is only imported once to read its serial number
+        let requests = fake.attach_requests();
+        assert_eq!(requests.iter().filter(|b| b.0 == "1-1").count(), 1);
+        assert!(requests.iter().filter(|b| b.0 == "1-2").count() > 1);
+    }
+
+    #[test]
+    fn test_unmount_nothing_mounted() {
+        let fake = fake_remote();
+        assert!(matches!(
+            Client::new(&fake).unmount(&[]),
+            Err(Error::NoMatchingDevice)
+        ));
+    }
+}
@@ -0,0 +1,132 @@
+//! The operations that a command would run, see `--dry-run`.
+use core::fmt;
+
+use serde::Serialize;
+
+use crate::list::DeviceRecord;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Bind,
+    Unbind,
+    Attach,
+    Detach,
+}
+
+/// An operation on a single device.
+/// Planned in the same order as the command would run them.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct PlannedOperation {
+    pub operation: Operation,
+    #[serde(flatten)]
+    pub device: DeviceRecord,
+    /// The remote device is only attached if it has one of the serial numbers, any if empty
+    pub required_serials: Vec<String>,
+}
+
+impl PlannedOperation {
+    pub(crate) fn new(operation: Operation, device: DeviceRecord) -> Self {
+        PlannedOperation {
+            operation,
+            device,
+            required_serials: Vec::new(),
+        }
+    }
+
+    /// Whether the device is already in the requested state, if known
+    pub fn is_unchanged(&self) -> bool {
+        match self.operation {
+            Operation::Bind => self.device.bound == Some(true),
+            Operation::Unbind => self.device.bound == Some(false),
+            Operation::Attach | Operation::Detach => false,
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Operation::Bind => "bind",
+            Operation::Unbind => "unbind",
+            Operation::Attach => "attach",
+            Operation::Detach => "detach",
+        };
+        // `pad` to align the planned operations
+        f.pad(name)
+    }
+}
+
+/// One line per operation, e.g., `bind   1-1 (1050:0407)`
+impl fmt::Display for PlannedOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = &self.device;
+        let unknown = || "unknown".to_string();
+        write!(f, "{:<6} ", self.operation)?;
+        match self.operation {
+            Operation::Bind | Operation::Unbind => {
+                write!(f, "{}", d.busid.clone().unwrap_or_else(unknown))?
+            }
+            Operation::Attach => write!(
+                f,
+                "{}/{}",
+                d.remote_host.clone().unwrap_or_else(unknown),
+                d.busid.clone().unwrap_or_else(unknown)
+            )?,
+            Operation::Detach => write!(f, "port {}", d.port.clone().unwrap_or_else(unknown))?,
+        }
+        if let (Some(vid), Some(pid)) = (&d.vid, &d.pid) {
+            write!(f, " ({vid}:{pid})")?;
+        }
+        match self.required_serials.as_slice() {
+            [] => {}
+            [serial] => write!(f, " if its serial number is {serial}")?,
+            serials => write!(f, " if its serial number is one of {}", serials.join(", "))?,
+        }
+        if self.is_unchanged() {
+            write!(f, ", unchanged")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UsbId;
+
+    #[test]
+    fn test_display() {
+        let yubikey = DeviceRecord::with_usb_id(&UsbId::new(0x1050, 0x0407));
+        let bind = PlannedOperation::new(
+            Operation::Bind,
+            DeviceRecord {
+                busid: Some("1-4.3.4".to_string()),
+                bound: Some(true),
+                ..yubikey.clone()
+            },
+        );
+        assert_eq!(bind.to_string(), "bind   1-4.3.4 (1050:0407), unchanged");
+        let attach = PlannedOperation {
+            required_serials: vec!["CC1234".to_string(), "CC5678".to_string()],
+            ..PlannedOperation::new(
+                Operation::Attach,
+                DeviceRecord {
+                    busid: Some("1-1".to_string()),
+                    remote_host: Some("laptop".to_string()),
+                    ..yubikey.clone()
+                },
+            )
+        };
+        assert_eq!(
+            attach.to_string(),
+            "attach laptop/1-1 (1050:0407) if its serial number is one of CC1234, CC5678"
+        );
+        let detach = PlannedOperation::new(Operation::Detach, DeviceRecord::default());
+        assert_eq!(detach.to_string(), "detach port unknown");
+        assert_eq!(
+            serde_json::to_value(&attach).unwrap()["operation"],
+            serde_json::json!("attach")
+        );
+    }
+}
@@ -0,0 +1,205 @@
+//! The errors that scripts and systemd units may want to tell apart.
+//!
+//! Every variant maps to a stable exit code of the CLI:
+//!
+//! | Code | Variant                                 |
+//! |------|-----------------------------------------|
+//! | 1    | [`Error::Other`]                        |
+//! | 2    | invalid command line arguments          |
+//! | 3    | [`Error::UsbipMissing`]                 |
+//! | 4    | [`Error::MissingKernelModule`]          |
+//! | 5    | [`Error::HostUnreachable`]              |
+//! | 6    | [`Error::NoMatchingDevice`]             |
+//! | 7    | [`Error::PermissionDenied`]             |
+//! | 8    | [`Error::PartialFailure`]               |
+//! | 9    | [`Error::WaitTimeout`]                  |
+//!
+//! [`Error::NothingSelected`] shares the exit code 2 with invalid command line arguments and
+//! [`Error::UnmetExpectations`] shares the exit code 6 with [`Error::NoMatchingDevice`].
+use core::fmt;
+use std::io;
+
+use log::error;
+use thiserror::Error;
+
+use crate::selector::MatchCount;
+use crate::vhci::AttachError;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Could not run `{program}`. Is {program} installed/added to PATH?")]
+    UsbipMissing { program: &'static str },
+    #[error(
+        "The `{module}` kernel module is not loaded.\n  Please load it, e.g., via `modprobe {module}`."
+    )]
+    MissingKernelModule { module: &'static str },
+    #[error(
+        "Could not connect to {host}:{tcp_port}. \
+         Is the usbip daemon/server running and is it running via port {tcp_port}?"
+    )]
+    HostUnreachable {
+        host: String,
+        tcp_port: u16,
+        source: io::Error,
+    },
+    /// Hosting every device is most likely a mistake, so at least one device has to be selected
+    #[error("At least one USB ID, busid or class is required to host devices")]
+    NothingSelected,
+    #[error("Found no matching USB devices!")]
+    NoMatchingDevice,
+    #[error("{0:#}\n  Please run the command as root.")]
+    PermissionDenied(anyhow::Error),
+    /// Some of the selected devices could not be (un)mounted or (un)bound
+    #[error("{} of {total} devices failed: {}", failed.len(), failed.iter().map(|(t, _)| t.as_str()).collect::<Vec<_>>().join(", "))]
+    PartialFailure {
+        /// The device or port that failed and why
+        failed: Vec<(String, Error)>,
+        total: usize,
+    },
+    /// Not all of the requested devices appeared before the deadline
+    #[error("Timed out waiting for {}", describe_missing(missing))]
+    WaitTimeout {
+        /// The USB IDs and busids that were never mounted
+        missing: Vec<String>,
+    },
+    /// Some of the requested ids matched no device or not the expected number of devices
+    #[error("Not every requested id matched as expected: {}", unmet.iter().map(|m| m.to_string()).collect::<Vec<_>>().join(", "))]
+    UnmetExpectations { unmet: Vec<MatchCount> },
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+fn describe_missing(missing: &[String]) -> String {
+    match missing {
+        [] => "a matching USB device".to_string(),
+        missing => missing.join(", "),
+    }
+}
+
+impl Error {
+    /// The exit code of the CLI, see the module documentation
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Other(_) => 1,
+            Error::NothingSelected => 2,
+            Error::UsbipMissing { .. } => 3,
+            Error::MissingKernelModule { .. } => 4,
+            Error::HostUnreachable { .. } => 5,
+            Error::NoMatchingDevice | Error::UnmetExpectations { .. } => 6,
+            Error::PermissionDenied(_) => 7,
+            Error::PartialFailure { .. } => 8,
+            Error::WaitTimeout { .. } => 9,
+        }
+    }
+}
+
+/// Recover the typed errors that were passed on as `anyhow::Error`
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<Error>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<AttachError>() {
+            Ok(e) => return e.into(),
+            Err(e) => e,
+        };
+        let permission_denied = e
+            .chain()
+            .filter_map(|c| c.downcast_ref::<io::Error>())
+            .any(|io| io.kind() == io::ErrorKind::PermissionDenied);
+        match permission_denied {
+            true => Error::PermissionDenied(e),
+            false => Error::Other(e),
+        }
+    }
+}
+
+impl From<AttachError> for Error {
+    fn from(e: AttachError) -> Self {
+        match e {
+            AttachError::MissingDriver => Error::MissingKernelModule { module: "vhci_hcd" },
+            AttachError::Other(e) => e.into(),
+            e => Error::Other(e.into()),
+        }
+    }
+}
+
+/// Run `f` for every target and keep going if it fails for some of them.
+/// If it failed for all targets, the first error is returned as is.
+pub(crate) fn for_each_target<T: fmt::Display, R>(
+    targets: impl IntoIterator<Item = T>,
+    mut f: impl FnMut(&T) -> Result<R, Error>,
+) -> Result<Vec<R>, Error> {
+    let mut results = Vec::new();
+    let mut failed = Vec::new();
+    for t in targets {
+        match f(&t) {
+            Ok(r) => results.push(r),
+            Err(e) => failed.push((t.to_string(), e)),
+        }
+    }
+    if failed.is_empty() {
+        return Ok(results);
+    }
+    if results.is_empty() {
+        let (_, first) = failed.remove(0);
+        for (t, e) in failed {
+            error!("{t}: {e}");
+        }
+        return Err(first);
+    }
+    for (t, e) in &failed {
+        error!("{t}: {e}");
+    }
+    Err(Error::PartialFailure {
+        total: results.len() + failed.len(),
+        failed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+
+    use super::*;
+
+    #[test]
+    fn test_from_anyhow() {
+        let e: Error = anyhow::Error::from(Error::NoMatchingDevice).into();
+        assert_eq!(e.exit_code(), 6);
+        let e: Error = anyhow::Error::from(AttachError::MissingDriver).into();
+        assert!(matches!(
+            e,
+            Error::MissingKernelModule { module: "vhci_hcd" }
+        ));
+        let denied = Err::<(), _>(io::Error::from(io::ErrorKind::PermissionDenied))
+            .context("Could not write to /sys/bus/usb/drivers/usbip-host/bind")
+            .unwrap_err();
+        assert_eq!(Error::from(denied).exit_code(), 7);
+        assert_eq!(Error::from(anyhow!("something else")).exit_code(), 1);
+    }
+
+    #[test]
+    fn test_for_each_target() {
+        let check = |t: &u32| match t % 2 {
+            0 => Ok(*t),
+            _ => Err(Error::NoMatchingDevice),
+        };
+        assert_eq!(for_each_target([2, 4], check).unwrap(), vec![2, 4]);
+        assert!(matches!(
+            for_each_target([1, 3], check),
+            Err(Error::NoMatchingDevice)
+        ));
+        match for_each_target([1, 2, 3], check) {
+            Err(Error::PartialFailure { failed, total }) => {
+                assert_eq!(total, 3);
+                assert_eq!(
+                    failed.iter().map(|(t, _)| t.as_str()).collect::<Vec<_>>(),
+                    vec!["1", "3"]
+                );
+            }
+            r => panic!("expected a partial failure, got {r:?}"),
+        }
+    }
+}
@@ -0,0 +1,396 @@
+//! Export local USB devices by binding them to `usbip-host`.
+use core::fmt;
+
+use log::debug;
+
+use crate::backend::Backend;
+use crate::dry_run::{Operation, PlannedOperation};
+use crate::error::for_each_target;
+use crate::list::ListHostable;
+use crate::selector::{Candidate, Selector};
+use crate::sysfs::LocalDevice;
+use crate::usbip_host::BindOutcome;
+use crate::{BusId, Error, UsbId, UsbIdPattern};
+
+/// A simple enum that indicates whether to bind or
+/// unbind a local USB device
+enum BindType {
+    Bind,
+    Unbind,
+}
+
+impl BindType {
+    /// (Un)bind the device through the backend.
+    /// Devices that are already in the requested state are skipped.
+    fn execute(&self, backend: &impl Backend, busid: &BusId) -> anyhow::Result<BindOutcome> {
+        let outcome = match self {
+            BindType::Bind => backend.bind(busid)?,
+            BindType::Unbind => backend.unbind(busid)?,
+        };
+        if outcome == BindOutcome::Unchanged {
+            debug!("Nothing to {self} for {busid}");
+        }
+        Ok(outcome)
+    }
+}
+
+impl fmt::Display for BindType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindType::Bind => write!(f, "bind"),
+            BindType::Unbind => write!(f, "unbind"),
+        }
+    }
+}
+
+/// The device that was (un)bound and whether its state had to be changed
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BindResult {
+    pub busid: BusId,
+    pub usb_id: UsbId,
+    pub outcome: BindOutcome,
+}
+
+/// The machine that the USB devices are plugged into
+#[derive(Debug, Clone)]
+pub struct Host<B> {
+    backend: B,
+}
+
+impl<B: Backend> Host<B> {
+    pub fn new(backend: B) -> Self {
+        Host { backend }
+    }
+
+    /// All USB devices that are connected locally
+    pub fn list(&self) -> anyhow::Result<ListHostable> {
+        ListHostable::new(&self.backend)
+    }
+
+    /// Bind all devices with one of the given ids, so that they can be mounted remotely
+    pub fn host(&self, usb_ids: &[UsbIdPattern]) -> Result<Vec<BindResult>, Error> {
+        self.host_selected(&usb_ids.into())
+    }
+
+    /// Bind all selected devices, so that they can be mounted remotely
+    pub fn host_selected(&self, selector: &Selector) -> Result<Vec<BindResult>, Error> {
+        require_selection(selector)?;
+        self.execute(BindType::Bind, selector)
+    }
+
+    /// The operations that [`Host::host_selected`] would run, without changing anything
+    pub fn plan_host(&self, selector: &Selector) -> Result<Vec<PlannedOperation>, Error> {
+        require_selection(selector)?;
+        Ok(self.plan(Operation::Bind, selector)?)
+    }
+
+    /// Unbind all devices with one of the given ids, so that they can be used locally again.
+    /// Not specifying any id will unbind all hosted devices!
+    pub fn unhost(&self, usb_ids: &[UsbIdPattern]) -> Result<Vec<BindResult>, Error> {
+        self.unhost_selected(&usb_ids.into())
+    }
+
+    /// Unbind all selected devices, so that they can be used locally again.
+    /// An empty selector will unbind all hosted devices!
+    pub fn unhost_selected(&self, selector: &Selector) -> Result<Vec<BindResult>, Error> {
+        self.execute(BindType::Unbind, selector)
+    }
+
+    /// The operations that [`Host::unhost_selected`] would run, without changing anything
+    pub fn plan_unhost(&self, selector: &Selector) -> Result<Vec<PlannedOperation>, Error> {
+        Ok(self.plan(Operation::Unbind, selector)?)
+    }
+
+    /// The local devices that the selector selects, without changing anything
+    pub fn select(&self, selector: &Selector) -> anyhow::Result<Vec<(BusId, UsbId)>> {
+        let list = self.list()?;
+        let matched = selector.select(
+            list.devices
+                .iter()
+                .map(|d| (d.busid().clone(), candidate(d))),
+        );
+        debug!("Matched Busids: {matched:?}");
+        Ok(matched)
+    }
+
+    /// The operation for every selected device, in the order of [`Host::select`]
+    fn plan(
+        &self,
+        operation: Operation,
+        selector: &Selector,
+    ) -> anyhow::Result<Vec<PlannedOperation>> {
+        let list = self.list()?;
+        Ok(list
+            .devices
+            .iter()
+            .zip(list.records())
+            .filter(|(d, _)| selector.matches(&candidate(d)))
+            .map(|(_, record)| PlannedOperation::new(operation, record))
+            .collect())
+    }
+
+    /// (Un)bind every matching device, even if some of them fail
+    fn execute(&self, bind_type: BindType, selector: &Selector) -> Result<Vec<BindResult>, Error> {
+        let matched = self.select(selector)?;
+        if matched.is_empty() {
+            return Err(Error::NoMatchingDevice);
+        }
+        for_each_target(matched.iter().map(|(b, _)| b), |b| {
+            debug!("{bind_type}ing {b}");
+            let usb_id = matched
+                .iter()
+                .find_map(|(busid, usb_id)| (busid == *b).then_some(*usb_id))
+                .expect("the busid was selected");
+            Ok(BindResult {
+                busid: (*b).clone(),
+                usb_id,
+                outcome: bind_type.execute(&self.backend, b)?,
+            })
+        })
+    }
+}
+
+fn candidate(device: &LocalDevice) -> Candidate<'_> {
+    Candidate {
+        usb_id: device.usb_id(),
+        busid: Some(device.busid()),
+        serial: device.serial.as_deref(),
+        classes: Some((&device.info).into()),
+    }
+}
+
+/// Hosting every device is most likely a mistake
+fn require_selection(selector: &Selector) -> Result<(), Error> {
+    match selector.is_empty() {
+        true => Err(Error::NothingSelected),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{FakeBackend, NativeBackend};
+    use crate::protocol::UsbDevice;
+    use crate::sysfs::tests::FakeSysfs;
+    use crate::sysfs::LocalDevice;
+    use crate::usbip_host::USBIP_HOST_DRIVER;
+
+    fn fake_host() -> FakeSysfs {
+        let fake = FakeSysfs::new();
+        fake.add_driver(USBIP_HOST_DRIVER);
+        fake.add_device("1-1", "1050", "0407");
+        fake.set_driver("1-1", Some("usb"));
+        fake.add_device("1-2", "1050", "0407");
+        fake.set_driver("1-2", Some(USBIP_HOST_DRIVER));
+        fake.add_device("1-3", "058f", "9540");
+        fake.set_driver("1-3", Some("usb"));
+        fake
+    }
+
+    fn sorted(mut results: Vec<BindResult>) -> Vec<(String, BindOutcome)> {
+        results.sort_by(|a, b| a.busid.0.cmp(&b.busid.0));
+        results
+            .into_iter()
+            .map(|r| (r.busid.0, r.outcome))
+            .collect()
+    }
+
+    #[test]
+    fn test_host() {
+        let fake = fake_host();
+        let results = Host::new(NativeBackend::new(fake.sysfs()))
+            .host(&[UsbId::new(0x1050, 0x0407).into()])
+            .unwrap();
+        assert_eq!(
+            sorted(results),
+            vec![
+                ("1-1".to_string(), BindOutcome::Changed),
+                ("1-2".to_string(), BindOutcome::Unchanged),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_host_serial() {
+        let fake = fake_host();
+        fake.set_attr("1-1", "serial", "CC1234\n");
+        fake.set_attr("1-2", "serial", "CC5678\n");
+        let host = Host::new(NativeBackend::new(fake.sysfs()));
+        let yubikey = ["1050:0407@CC1234".parse::<UsbIdPattern>().unwrap()];
+        assert_eq!(
+            sorted(host.host(&yubikey).unwrap()),
+            vec![("1-1".to_string(), BindOutcome::Changed)]
+        );
+        // a device without a serial number is never selected by one
+        assert!(matches!(
+            host.unhost(&["058f:9540@CC1234".parse().unwrap()]),
+            Err(Error::NoMatchingDevice)
+        ));
+        assert!(matches!(
+            host.host(&[yubikey[0].clone().with_serial("unknown")]),
+            Err(Error::NoMatchingDevice)
+        ));
+    }
+
+    #[test]
+    fn test_host_busid() {
+        let fake = fake_host();
+        fake.add_device("1-4.3.4", "1050", "0407");
+        let host = Host::new(NativeBackend::new(fake.sysfs()));
+        let selector = Selector {
+            busids: vec!["1-4.3.4".parse().unwrap(), "1-3".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(
+            sorted(host.host_selected(&selector).unwrap()),
+            vec![
+                ("1-3".to_string(), BindOutcome::Changed),
+                ("1-4.3.4".to_string(), BindOutcome::Changed),
+            ]
+        );
+        // the busid narrows down the usb ids
+        let selector = Selector {
+            usb_ids: vec![UsbId::new(0x058f, 0x9540).into()],
+            busids: vec!["1-1".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            host.unhost_selected(&selector),
+            Err(Error::NoMatchingDevice)
+        ));
+    }
+
+    #[test]
+    fn test_host_class() {
+        let fake = fake_host();
+        // every fake device has a HID interface
+        fake.add_interface("1-1", 1, "0b");
+        fake.set_attr("1-3:1.0", "bInterfaceClass", "0b\n");
+        let host = Host::new(NativeBackend::new(fake.sysfs()));
+        let smartcards = Selector {
+            classes: vec!["smartcard".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(
+            sorted(host.host_selected(&smartcards).unwrap()),
+            vec![
+                ("1-1".to_string(), BindOutcome::Changed),
+                ("1-3".to_string(), BindOutcome::Changed),
+            ]
+        );
+        // composable with the usb ids
+        let yubikey_ccid = Selector {
+            usb_ids: vec![UsbId::new(0x1050, 0x0407).into()],
+            interface_classes: vec!["0x0b".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(
+            sorted(host.host_selected(&yubikey_ccid).unwrap()),
+            vec![("1-1".to_string(), BindOutcome::Changed)]
+        );
+    }
+
+    #[test]
+    fn test_plan_host() {
+        let fake = fake_host();
+        let host = Host::new(NativeBackend::new(fake.sysfs()));
+        let plan = host
+            .plan_host(&Selector::from(&[UsbId::new(0x1050, 0x0407).into()][..]))
+            .unwrap();
+        let lines = plan.iter().map(|o| o.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "bind   1-1 (1050:0407)",
+                "bind   1-2 (1050:0407), unchanged"
+            ]
+        );
+        assert!(host.plan_host(&Selector::default()).is_err());
+        assert_eq!(host.plan_unhost(&Selector::default()).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_host_requires_ids() {
+        let fake = fake_host();
+        assert!(matches!(
+            Host::new(NativeBackend::new(fake.sysfs())).host(&[]),
+            Err(Error::NothingSelected)
+        ));
+        assert!(matches!(
+            Host::new(NativeBackend::new(fake.sysfs())).host(&[UsbId::new(0xdead, 0xbeef).into()]),
+            Err(Error::NoMatchingDevice)
+        ));
+    }
+
+    #[test]
+    fn test_host_missing_module() {
+        let fake = FakeSysfs::new();
+        fake.add_device("1-1", "1050", "0407");
+        fake.add_device("1-2", "1050", "0407");
+        let result =
+            Host::new(NativeBackend::new(fake.sysfs())).host(&[UsbId::new(0x1050, 0x0407).into()]);
+        assert!(matches!(
+            result,
+            Err(Error::MissingKernelModule {
+                module: "usbip_host"
+            })
+        ));
+    }
+
+    #[test]
+    fn test_unhost_all() {
+        let fake = fake_host();
+        let host = Host::new(NativeBackend::new(fake.sysfs()));
+        assert_eq!(host.select(&Selector::default()).unwrap().len(), 3);
+        let results = host.unhost(&[]).unwrap();
+        assert_eq!(
+            sorted(results),
+            vec![
+                ("1-1".to_string(), BindOutcome::Unchanged),
+                ("1-2".to_string(), BindOutcome::Changed),
+                ("1-3".to_string(), BindOutcome::Unchanged),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_host_fake_backend() {
+        let fake = FakeBackend::new();
+        for (busid, id_product) in [("1-1", 0x0407), ("1-2", 0x0407), ("1-3", 0x9540)] {
+            fake.add_local_device(LocalDevice {
+                info: UsbDevice {
+                    busid: BusId(busid.to_string()),
+                    id_vendor: 0x1050,
+                    id_product,
+                    ..Default::default()
+                },
+                ..Default::default()
+            });
+        }
+        let host = Host::new(&fake);
+        let results = host.host(&[UsbId::new(0x1050, 0x0407).into()]).unwrap();
+        assert!(results.iter().all(|r| r.outcome == BindOutcome::Changed));
+        let bound = |fake: &FakeBackend| {
+            let mut bound = fake
+                .local_devices()
+                .into_iter()
+                .filter(|d| d.driver.as_deref() == Some(USBIP_HOST_DRIVER))
+                .map(|d| d.busid().0.clone())
+                .collect::<Vec<_>>();
+            bound.sort();
+            bound
+        };
+        assert_eq!(bound(&fake), vec!["1-1", "1-2"]);
+        let results = host.unhost(&[]).unwrap();
+        assert_eq!(
+            sorted(results),
+            vec![
+                ("1-1".to_string(), BindOutcome::Changed),
+                ("1-2".to_string(), BindOutcome::Changed),
+                ("1-3".to_string(), BindOutcome::Unchanged),
+            ]
+        );
+        assert!(bound(&fake).is_empty());
+    }
+}
@@ -0,0 +1,583 @@
+//! Runs the USB/IP server of `start-usb-hoster` and reports its state to systemd.
+//!
+//! Both the built-in server and the external `usbipd` daemon announce when they
+//! accept connections, keep the status up to date with the exported devices, and
+//! announce the shutdown after `SIGTERM`/`SIGINT` or once their lifetime ended.
+use core::fmt;
+use std::fs;
+use std::net::TcpListener;
+use std::os::unix::process::ExitStatusExt;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+use log::{debug, info, warn};
+
+use crate::server::Server;
+use crate::systemd::Notifier;
+
+/// How often the state of the server and the exported devices is checked
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// TCP state of a listening socket in `/proc/net/tcp`, see `include/net/tcp_states.h`
+const TCP_LISTEN: &str = "0A";
+
+/// Returns a flag that is set once the process should shut down
+pub fn shutdown_flag() -> anyhow::Result<Arc<AtomicBool>> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(&shutdown))
+            .context("Could not register the signal handler")?;
+    }
+    Ok(shutdown)
+}
+
+/// Parse a duration either as plain seconds or as a time span like `15min` or `1h 30min`
+pub fn parse_duration(s: &str) -> Result<Duration, humantime::DurationError> {
+    match s.parse::<u64>() {
+        Ok(secs) => Ok(Duration::from_secs(secs)),
+        Err(_) => humantime::parse_duration(s),
+    }
+}
+
+/// Limits after which the hoster exits on its own
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Lifetime {
+    /// Exit after this time, even if a device is still in use
+    pub max_lifetime: Option<Duration>,
+    /// Exit after no device was imported for this time
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Lifetime {
+    pub fn is_limited(&self) -> bool {
+        self.max_lifetime.is_some() || self.idle_timeout.is_some()
+    }
+}
+
+/// Why the hoster stops serving
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum ExitReason {
+    Shutdown,
+    ServerExited,
+    MaxLifetime(Duration),
+    IdleTimeout(Duration),
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitReason::Shutdown => write!(f, "Received shutdown signal"),
+            ExitReason::ServerExited => write!(f, "Server exited"),
+            ExitReason::MaxLifetime(d) => write!(
+                f,
+                "Reached the maximum lifetime of {}",
+                humantime::format_duration(*d)
+            ),
+            ExitReason::IdleTimeout(d) => write!(
+                f,
+                "No device was imported for {}",
+                humantime::format_duration(*d)
+            ),
+        }
+    }
+}
+
+/// Tracks since when the hoster runs and since when no device is in use
+#[derive(Debug)]
+struct LifetimeTracker {
+    lifetime: Lifetime,
+    started: Instant,
+    idle_since: Option<Instant>,
+}
+
+impl LifetimeTracker {
+    fn new(lifetime: Lifetime, now: Instant) -> Self {
+        LifetimeTracker {
+            lifetime,
+            started: now,
+            idle_since: Some(now),
+        }
+    }
+
+    /// Returns the reason to exit if one of the limits is reached
+    fn update(&mut self, now: Instant, in_use: bool) -> Option<ExitReason> {
+        if in_use {
+            self.idle_since = None;
+        } else if self.idle_since.is_none() {
+            self.idle_since = Some(now);
+        }
+        if let Some(max) = self.lifetime.max_lifetime {
+            if now.duration_since(self.started) >= max {
+                return Some(ExitReason::MaxLifetime(max));
+            }
+        }
+        if let (Some(timeout), Some(idle_since)) = (self.lifetime.idle_timeout, self.idle_since) {
+            if now.duration_since(idle_since) >= timeout {
+                return Some(ExitReason::IdleTimeout(timeout));
+            }
+        }
+        None
+    }
+}
+
+/// The inodes of the sockets in the `/proc/net/tcp` or `/proc/net/tcp6` table
+/// that listen on the given port
+fn listening_inodes(table: &str, tcp_port: u16) -> Vec<u64> {
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let port = fields
+                .get(1)
+                .and_then(|local| local.rsplit_once(':'))
+                .and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+            if port != Some(tcp_port) || fields.get(3) != Some(&TCP_LISTEN) {
+                return None;
+            }
+            fields.get(9)?.parse().ok()
+        })
+        .collect()
+}
+
+/// The inodes of the sockets that the process has open, linked as `socket:[<inode>]`
+fn socket_inodes(pid: u32) -> Vec<u64> {
+    let Ok(entries) = fs::read_dir(format!("/proc/{pid}/fd")) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|e| fs::read_link(e.ok()?.path()).ok())
+        .filter_map(|target| {
+            target
+                .to_str()?
+                .strip_prefix("socket:[")?
+                .strip_suffix(']')?
+                .parse()
+                .ok()
+        })
+        .collect()
+}
+
+/// Whether the process itself listens on the given port,
+/// a stale or foreign listener on the same port does not count
+fn process_is_listening(pid: u32, tcp_port: u16) -> bool {
+    let sockets = socket_inodes(pid);
+    ["/proc/net/tcp", "/proc/net/tcp6"].iter().any(|path| {
+        fs::read_to_string(path)
+            .map(|table| {
+                listening_inodes(&table, tcp_port)
+                    .iter()
+                    .any(|inode| sockets.contains(inode))
+            })
+            .unwrap_or(false)
+    })
+}
+
+#[derive(Debug)]
+pub struct Hoster {
+    server: Server,
+    notifier: Notifier,
+    lifetime: Lifetime,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Hoster {
+    pub fn new(
+        server: Server,
+        notifier: Notifier,
+        lifetime: Lifetime,
+        shutdown: Arc<AtomicBool>,
+    ) -> Self {
+        Hoster {
+            server,
+            notifier,
+            lifetime,
+            shutdown,
+        }
+    }
+
+    /// Update the status if the exported devices changed
+    fn report_status(&self, last: &mut Option<String>) {
+        let status = self.server.describe_exported().unwrap_or_else(|e| {
+            warn!("Could not list the exported devices: {e:#}");
+            "Could not list the exported devices".to_string()
+        });
+        if last.as_ref() != Some(&status) {
+            self.notifier.status(&status);
+            *last = Some(status);
+        }
+    }
+
+    /// Check the lifetime limits, if any are set
+    fn check_lifetime(&self, tracker: &mut LifetimeTracker) -> Option<ExitReason> {
+        if !self.lifetime.is_limited() {
+            return None;
+        }
+        let in_use = self.server.any_in_use().unwrap_or_else(|e| {
+            // Rather keep running than pulling the device from a client
+            warn!("Could not read whether the devices are in use: {e:#}");
+            true
+        });
+        tracker.update(Instant::now(), in_use)
+    }
+
+    fn announce_stop(&self, reason: ExitReason) {
+        info!("{reason}, shutting down");
+        self.notifier.status(&reason.to_string());
+        self.notifier.stopping();
+    }
+
+    /// With a limited lifetime, the devices are unbound on exit,
+    /// so that they can be used locally again
+    fn release_devices(&self) -> anyhow::Result<()> {
+        if self.lifetime.is_limited() {
+            self.server.unbind_exported()?;
+        }
+        Ok(())
+    }
+
+    /// Serve the listeners with the built-in server until the process should shut down
+    pub fn run_builtin(&self, listeners: &[TcpListener]) -> anyhow::Result<()> {
+        // Separate from the shutdown flag to tell a failed server from a signal
+        let stop_server = AtomicBool::new(false);
+        let result = thread::scope(|scope| {
+            let handle = scope.spawn(|| self.server.serve_all(listeners, &stop_server));
+            // The listeners are already bound, so connections are queued from here on
+            self.notifier.ready();
+            let mut tracker = LifetimeTracker::new(self.lifetime, Instant::now());
+            let mut last_status = None;
+            let reason = loop {
+                if handle.is_finished() {
+                    break ExitReason::ServerExited;
+                }
+                if self.shutdown.load(Ordering::Relaxed) {
+                    break ExitReason::Shutdown;
+                }
+                self.report_status(&mut last_status);
+                if let Some(reason) = self.check_lifetime(&mut tracker) {
+                    break reason;
+                }
+                thread::sleep(POLL_INTERVAL);
+            };
+            self.announce_stop(reason);
+            stop_server.store(true, Ordering::Relaxed);
+            handle
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("Server thread panicked")))
+        });
+        self.release_devices()?;
+        result
+    }
+
+    /// Run `usbipd` until it exits or the process should shut down.
+    /// The daemon is ready once it has a socket that listens on `tcp_port`,
+    /// as it does not send any notifications on its own.
+    pub fn run_usbipd(&self, mut usbipd: Command, tcp_port: u16) -> anyhow::Result<()> {
+        let mut child = usbipd
+            .spawn()
+            .context("Could not successfully start usbipd")?;
+        let mut ready = false;
+        let mut tracker = LifetimeTracker::new(self.lifetime, Instant::now());
+        let mut last_status = None;
+        let reason = loop {
+            if child.try_wait()?.is_some() {
+                break ExitReason::ServerExited;
+            }
+            if self.shutdown.load(Ordering::Relaxed) {
+                break ExitReason::Shutdown;
+            }
+            if !ready && process_is_listening(child.id(), tcp_port) {
+                self.notifier.ready();
+                ready = true;
+            }
+            if ready {
+                self.report_status(&mut last_status);
+            }
+            if let Some(reason) = self.check_lifetime(&mut tracker) {
+                break reason;
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        self.announce_stop(reason);
+        let stopped_by_us = reason != ExitReason::ServerExited;
+        if stopped_by_us {
+            debug!("Stopping usbipd");
+            // SAFETY: the child has not been reaped yet, so the PID still belongs to it
+            unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+        }
+        let status = child.wait()?;
+        self.release_devices()?;
+        let terminated_by_us = stopped_by_us && status.signal() == Some(libc::SIGTERM);
+        if !status.success() && !terminated_by_us {
+            return Err(anyhow!("usbipd exited with {status}"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    use rstest::rstest;
+
+    use super::*;
+    use crate::protocol;
+    use crate::sysfs::tests::FakeSysfs;
+    use crate::systemd::tests::FakeNotifySocket;
+    use crate::usbip_host::USBIP_HOST_DRIVER;
+
+    const PROC_NET_TCP: &str = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:0CA8 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 22170 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:0277 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 21035 1 0000000000000000 100 0 0 10 0
+   2: 0F02000A:0016 0202000A:D5A2 01 00000000:00000000 02:000A2C6B 00000000     0        0 23456 2 0000000000000000 20 4 30 10 -1
+";
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    /// One exported device with the given `usbip_status`
+    fn fake_host(usbip_status: &str) -> FakeSysfs {
+        let fake = FakeSysfs::new();
+        fake.add_device("1-1", "1050", "0407");
+        fake.add_driver(USBIP_HOST_DRIVER);
+        fake.set_driver("1-1", Some(USBIP_HOST_DRIVER));
+        fake.set_attr("1-1", "usbip_status", usbip_status);
+        fake
+    }
+
+    fn hoster(fake: &FakeSysfs, notify: &FakeNotifySocket, lifetime: Lifetime) -> Hoster {
+        Hoster::new(
+            Server::new(fake.sysfs()),
+            notify.notifier(),
+            lifetime,
+            Arc::new(AtomicBool::new(false)),
+        )
+    }
+
+    fn read_unbind(fake: &FakeSysfs) -> Option<String> {
+        fs::read_to_string(fake.root().join("bus/usb/drivers/usbip-host/unbind")).ok()
+    }
+
+    #[rstest]
+    #[case("90", secs(90))]
+    #[case("90s", secs(90))]
+    #[case("15min", secs(15 * 60))]
+    #[case("1h 30min", secs(90 * 60))]
+    fn test_parse_duration(#[case] input: &str, #[case] expected: Duration) {
+        assert_eq!(parse_duration(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_parse_duration_invalid() {
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("-1").is_err());
+    }
+
+    #[test]
+    fn test_listening_inodes() {
+        assert_eq!(listening_inodes(PROC_NET_TCP, 3240), vec![22170]);
+        assert_eq!(listening_inodes(PROC_NET_TCP, 631), vec![21035]);
+        // established connection, not a listening socket
+        assert!(listening_inodes(PROC_NET_TCP, 22).is_empty());
+        assert!(listening_inodes(PROC_NET_TCP, 3241).is_empty());
+        assert!(listening_inodes("", 3240).is_empty());
+    }
+
+    /// `sleep` stands in for usbipd and inherits the listener, so that it listens itself
+    fn listening_usbipd(listener: &TcpListener) -> Command {
+        let fd = listener.as_raw_fd();
+        let mut usbipd = Command::new("sleep");
+        usbipd.arg("60");
+        // SAFETY: `fcntl` is async-signal-safe
+        unsafe {
+            usbipd.pre_exec(move || match libc::fcntl(fd, libc::F_SETFD, 0) {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            });
+        }
+        usbipd
+    }
+
+    #[test]
+    fn test_lifetime_unlimited() {
+        let start = Instant::now();
+        let mut tracker = LifetimeTracker::new(Lifetime::default(), start);
+        assert_eq!(tracker.update(start + secs(100_000), false), None);
+    }
+
+    #[test]
+    fn test_max_lifetime_ignores_usage() {
+        let start = Instant::now();
+        let lifetime = Lifetime {
+            max_lifetime: Some(secs(60)),
+            idle_timeout: None,
+        };
+        let mut tracker = LifetimeTracker::new(lifetime, start);
+        assert_eq!(tracker.update(start + secs(59), true), None);
+        assert_eq!(
+            tracker.update(start + secs(60), true),
+            Some(ExitReason::MaxLifetime(secs(60)))
+        );
+    }
+
+    #[test]
+    fn test_idle_timeout_restarts_after_usage() {
+        let start = Instant::now();
+        let lifetime = Lifetime {
+            max_lifetime: None,
+            idle_timeout: Some(secs(10)),
+        };
+        let mut tracker = LifetimeTracker::new(lifetime, start);
+        assert_eq!(tracker.update(start + secs(5), false), None);
+        // in use for a long time
+        assert_eq!(tracker.update(start + secs(8), true), None);
+        assert_eq!(tracker.update(start + secs(100), true), None);
+        // released again, so the timeout starts from here
+        assert_eq!(tracker.update(start + secs(101), false), None);
+        assert_eq!(tracker.update(start + secs(110), false), None);
+        assert_eq!(
+            tracker.update(start + secs(111), false),
+            Some(ExitReason::IdleTimeout(secs(10)))
+        );
+    }
+
+    #[test]
+    fn test_idle_timeout_from_start() {
+        let start = Instant::now();
+        let lifetime = Lifetime {
+            max_lifetime: Some(secs(60)),
+            idle_timeout: Some(secs(10)),
+        };
+        let mut tracker = LifetimeTracker::new(lifetime, start);
+        assert_eq!(
+            tracker.update(start + secs(10), false),
+            Some(ExitReason::IdleTimeout(secs(10)))
+        );
+    }
+
+    #[test]
+    fn test_run_builtin_notifies() {
+        let fake = fake_host("1");
+        let notify = FakeNotifySocket::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let hoster = hoster(&fake, &notify, Lifetime::default());
+        thread::scope(|scope| {
+            let handle = scope.spawn(|| hoster.run_builtin(&[listener]));
+            assert_eq!(notify.recv(), "READY=1");
+            assert_eq!(notify.recv(), "STATUS=Exporting 1-1 (1050:0407)");
+            assert_eq!(protocol::list_remote("127.0.0.1", port).unwrap().len(), 1);
+            hoster.shutdown.store(true, Ordering::Relaxed);
+            assert_eq!(notify.recv(), "STATUS=Received shutdown signal");
+            assert_eq!(notify.recv(), "STOPPING=1");
+            handle.join().unwrap().unwrap();
+        });
+        // without a lifetime the devices stay exported
+        assert_eq!(read_unbind(&fake), None);
+    }
+
+    #[test]
+    fn test_run_builtin_idle_timeout() {
+        let fake = fake_host("1");
+        let notify = FakeNotifySocket::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let lifetime = Lifetime {
+            max_lifetime: None,
+            idle_timeout: Some(Duration::ZERO),
+        };
+        hoster(&fake, &notify, lifetime)
+            .run_builtin(&[listener])
+            .unwrap();
+        assert_eq!(notify.recv(), "READY=1");
+        assert_eq!(notify.recv(), "STATUS=Exporting 1-1 (1050:0407)");
+        assert_eq!(notify.recv(), "STATUS=No device was imported for 0s");
+        assert_eq!(notify.recv(), "STOPPING=1");
+        assert_eq!(read_unbind(&fake).unwrap(), "1-1");
+    }
+
+    #[test]
+    fn test_run_builtin_keeps_device_in_use() {
+        let fake = fake_host("2");
+        let notify = FakeNotifySocket::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let lifetime = Lifetime {
+            max_lifetime: Some(secs(1)),
+            idle_timeout: Some(Duration::ZERO),
+        };
+        hoster(&fake, &notify, lifetime)
+            .run_builtin(&[listener])
+            .unwrap();
+        assert_eq!(notify.recv(), "READY=1");
+        assert_eq!(notify.recv(), "STATUS=Exporting 1-1 (1050:0407, in use)");
+        // the idle timeout never triggers while the device is in use
+        assert_eq!(notify.recv(), "STATUS=Reached the maximum lifetime of 1s");
+        assert_eq!(notify.recv(), "STOPPING=1");
+        assert_eq!(read_unbind(&fake).unwrap(), "1-1");
+    }
+
+    #[test]
+    fn test_run_usbipd_notifies() {
+        let fake = FakeSysfs::new();
+        let notify = FakeNotifySocket::new();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let usbipd = listening_usbipd(&listener);
+        let hoster = hoster(&fake, &notify, Lifetime::default());
+        thread::scope(|scope| {
+            let handle = scope.spawn(|| hoster.run_usbipd(usbipd, port));
+            assert_eq!(notify.recv(), "READY=1");
+            assert_eq!(notify.recv(), "STATUS=No devices are exported");
+            hoster.shutdown.store(true, Ordering::Relaxed);
+            assert_eq!(notify.recv(), "STATUS=Received shutdown signal");
+            assert_eq!(notify.recv(), "STOPPING=1");
+            handle.join().unwrap().unwrap();
+        });
+        drop(listener);
+    }
+
+    #[test]
+    fn test_run_usbipd_foreign_listener() {
+        let fake = FakeSysfs::new();
+        let notify = FakeNotifySocket::new();
+        // another process listens on the port, but not usbipd
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut usbipd = Command::new("sleep");
+        usbipd.arg("60");
+        let hoster = hoster(&fake, &notify, Lifetime::default());
+        thread::scope(|scope| {
+            let handle = scope.spawn(|| hoster.run_usbipd(usbipd, port));
+            thread::sleep(POLL_INTERVAL * 2);
+            hoster.shutdown.store(true, Ordering::Relaxed);
+            assert_eq!(notify.recv(), "STATUS=Received shutdown signal");
+            handle.join().unwrap().unwrap();
+        });
+        drop(listener);
+    }
+
+    #[test]
+    fn test_run_usbipd_max_lifetime() {
+        let fake = fake_host("2");
+        let notify = FakeNotifySocket::new();
+        let mut usbipd = Command::new("sleep");
+        usbipd.arg("60");
+        let lifetime = Lifetime {
+            max_lifetime: Some(Duration::ZERO),
+            idle_timeout: None,
+        };
+        hoster(&fake, &notify, lifetime)
+            .run_usbipd(usbipd, 0)
+            .unwrap();
+        assert_eq!(notify.recv(), "STATUS=Reached the maximum lifetime of 0s");
+        assert_eq!(notify.recv(), "STOPPING=1");
+        assert_eq!(read_unbind(&fake).unwrap(), "1-1");
+    }
+}
@@ -0,0 +1,510 @@
+//! Bind/unbind and mount/unmount USB devices via USB/IP.
+//!
+//! The [`Host`] exports the local USB devices and the [`Client`] mounts
+//! the devices of a remote host. Both select the devices by their [`UsbId`]
+//! and are idempotent, i.e., devices that are already in the requested
+//! state are reported as unchanged instead of raising an error.
+//! The actual USB/IP operations are provided by a [`Backend`].
+//!
+//! ```no_run
+//! use usbip_wrapper::backend::NativeBackend;
+//! use usbip_wrapper::{Client, Host, Sysfs, UsbIdPattern};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! // all devices of the vendor, use `1050:0407` for a single product
+//! let yubikeys = ["1050:*".parse::<UsbIdPattern>()?];
+//! // on the machine the device is plugged into
+//! Host::new(NativeBackend::new(Sysfs::default())).host(&yubikeys)?;
+//! // on the machine that wants to use the device
+//! let client = Client::new(NativeBackend::new(Sysfs::default()));
+//! for mounted in client.mount("laptop", 3240, &yubikeys)? {
+//!     println!("{}: {:?}", mounted.busid, mounted.outcome);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use core::fmt;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+pub mod backend;
+pub mod client;
+pub mod dry_run;
+pub mod error;
+pub mod host;
+pub mod hoster;
+pub mod list;
+pub mod protocol;
+pub mod selector;
+pub mod server;
+pub mod sysfs;
+pub mod systemd;
+pub mod usb_ids;
+pub mod usbip_host;
+pub mod vhci;
+
+pub use backend::Backend;
+pub use client::Client;
+pub use error::Error;
+pub use host::Host;
+pub use selector::Selector;
+pub use sysfs::Sysfs;
+
+/// Simple struct string-variant that contains
+/// a unique BusId (which may change between reboots!)
+///
+/// Devices behind hubs have a dotted busid, e.g., `1-4.3.4`
+/// is port 4 of the hub at port 3 of the hub at port 4 of bus 1.
+#[derive(Debug, Default, Eq, PartialEq, Hash, Clone)]
+pub struct BusId(pub String);
+
+/// Internal USB port that the "virtual"/remote USB
+/// was locally attached to.
+/// Has NOTHING to do with the TCP port!
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+pub struct Port(pub String);
+
+/// The vendor and product id of a USB device
+/// that might be shared across multiple USB
+/// devices from the same vendor, for example, when having multiple
+/// hardware keys, like the Yubikey plugged in.
+///
+/// Parses `1050:0407`, `1050:0407` in uppercase and `0x1050:0x0407`,
+/// and is always displayed as `1050:0407`.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy)]
+pub struct UsbId {
+    pub vendor: u16,
+    pub product: u16,
+}
+
+impl UsbId {
+    pub fn new(vendor: u16, product: u16) -> Self {
+        UsbId { vendor, product }
+    }
+}
+
+/// A USB class code, e.g., `0b` for smartcard readers (CCID).
+///
+/// Parses the hexadecimal code with an optional `0x` prefix or one of the
+/// [`USB_CLASS_NAMES`], e.g., `hid`, and is always displayed as `0x0b`.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
+pub struct UsbClass(pub u8);
+
+/// Short names of the base classes defined by the USB-IF
+pub const USB_CLASS_NAMES: &[(&str, u8)] = &[
+    ("audio", 0x01),
+    ("cdc", 0x02),
+    ("hid", 0x03),
+    ("physical", 0x05),
+    ("image", 0x06),
+    ("printer", 0x07),
+    ("storage", 0x08),
+    ("hub", 0x09),
+    ("cdc-data", 0x0a),
+    ("smartcard", 0x0b),
+    ("ccid", 0x0b),
+    ("security", 0x0d),
+    ("video", 0x0e),
+    ("healthcare", 0x0f),
+    ("audio-video", 0x10),
+    ("billboard", 0x11),
+    ("diagnostic", 0xdc),
+    ("wireless", 0xe0),
+    ("misc", 0xef),
+    ("application", 0xfe),
+    ("vendor", 0xff),
+];
+
+/// Selects the devices with the given vendor id and either
+/// the given product id or, for `VVVV:*`, any product id.
+/// `VVVV:PPPP@SERIAL` narrows it down to the device with the given serial number.
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+pub struct UsbIdPattern {
+    pub vendor: u16,
+    pub product: Option<u16>,
+    pub serial: Option<String>,
+}
+
+impl UsbIdPattern {
+    /// Whether the id matches, the serial number is checked by [`serial_matches`]
+    pub fn matches(&self, usb_id: &UsbId) -> bool {
+        self.vendor == usb_id.vendor && self.product.map_or(true, |p| p == usb_id.product)
+    }
+
+    pub fn is_wildcard(&self) -> bool {
+        self.product.is_none()
+    }
+
+    /// Only select the device with the given serial number
+    pub fn with_serial(self, serial: impl Into<String>) -> Self {
+        UsbIdPattern {
+            serial: Some(serial.into()),
+            ..self
+        }
+    }
+}
+
+impl From<UsbId> for UsbIdPattern {
+    fn from(usb_id: UsbId) -> Self {
+        UsbIdPattern {
+            vendor: usb_id.vendor,
+            product: Some(usb_id.product),
+            serial: None,
+        }
+    }
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+#[error("Invalid USB ID `{0}`, expected the hexadecimal vendor and product id as `VVVV:PPPP`, e.g., `1050:0407`")]
+pub struct ParseUsbIdError(String);
+
+/// Strip the optional `0x` prefix of a hexadecimal number
+fn strip_hex_prefix(s: &str) -> &str {
+    s.strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s)
+}
+
+/// Parse a vendor or product id with exactly 4 hex digits and an optional `0x` prefix.
+/// A shorter id is most likely truncated, e.g., `1050:407`, and is rejected.
+fn parse_hex_id(part: &str) -> Option<u16> {
+    let digits = strip_hex_prefix(part);
+    // `from_str_radix` would also accept a leading `+`
+    match digits.len() == 4 && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => u16::from_str_radix(digits, 16).ok(),
+        false => None,
+    }
+}
+
+impl FromStr for UsbId {
+    type Err = ParseUsbIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseUsbIdError(s.to_string());
+        let (vendor, product) = s.trim().split_once(':').ok_or_else(err)?;
+        Ok(UsbId {
+            vendor: parse_hex_id(vendor).ok_or_else(err)?,
+            product: parse_hex_id(product).ok_or_else(err)?,
+        })
+    }
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+#[error("Invalid USB class `{0}`, expected the hexadecimal class code, e.g., `0x0b`, or a name like `hid` or `smartcard`")]
+pub struct ParseUsbClassError(String);
+
+impl FromStr for UsbClass {
+    type Err = ParseUsbClassError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_lowercase();
+        USB_CLASS_NAMES
+            .iter()
+            .find_map(|(n, class)| (*n == name).then_some(*class))
+            .or_else(|| {
+                let code = strip_hex_prefix(&name);
+                // `from_str_radix` would also accept a leading `+`
+                match code.chars().all(|c| c.is_ascii_hexdigit()) {
+                    true => u8::from_str_radix(code, 16).ok(),
+                    false => None,
+                }
+            })
+            .map(UsbClass)
+            .ok_or_else(|| ParseUsbClassError(s.to_string()))
+    }
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+#[error("Invalid busid `{0}`, expected the bus and the port path as `BUS-PORT[.PORT]...`, e.g., `1-4.3.4`")]
+pub struct ParseBusIdError(String);
+
+impl FromStr for BusId {
+    type Err = ParseBusIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let is_number = |n: &str| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit());
+        let valid = s
+            .trim()
+            .split_once('-')
+            .is_some_and(|(bus, ports)| is_number(bus) && ports.split('.').all(is_number));
+        match valid {
+            true => Ok(BusId(s.trim().to_string())),
+            false => Err(ParseBusIdError(s.to_string())),
+        }
+    }
+}
+
+impl FromStr for UsbIdPattern {
+    type Err = ParseUsbIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseUsbIdError(s.to_string());
+        let (id, serial) = match s.trim().split_once('@') {
+            Some((_, "")) => return Err(err()),
+            Some((id, serial)) => (id, Some(serial.to_string())),
+            None => (s, None),
+        };
+        let pattern = match id.trim().strip_suffix(":*") {
+            Some(vendor) => UsbIdPattern {
+                vendor: parse_hex_id(vendor).ok_or_else(err)?,
+                product: None,
+                serial: None,
+            },
+            None => id.parse::<UsbId>().map_err(|_| err())?.into(),
+        };
+        Ok(UsbIdPattern { serial, ..pattern })
+    }
+}
+
+impl fmt::Display for BusId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // `pad` to respect the alignment of the listings
+        f.pad(&self.0)
+    }
+}
+
+impl fmt::Display for UsbId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vendor, self.product)
+    }
+}
+
+impl fmt::Display for UsbIdPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.product {
+            Some(product) => write!(f, "{:04x}:{product:04x}", self.vendor)?,
+            None => write!(f, "{:04x}:*", self.vendor)?,
+        }
+        match &self.serial {
+            Some(serial) => write!(f, "@{serial}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for UsbClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#04x}", self.0)
+    }
+}
+
+impl fmt::Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(&self.0)
+    }
+}
+
+/// Given the usb_id patterns and a map of usb_ids and a value set, return
+/// all values that match any of the patterns.
+pub fn collect_matching<'a, T>(
+    m: &'a HashMap<UsbId, HashSet<T>>,
+    usb_ids: &[UsbIdPattern],
+) -> Vec<&'a T> {
+    m.iter()
+        .filter(|(usbid, _set)| usb_ids.iter().any(|p| p.matches(usbid)))
+        .flat_map(|(_usbid, set)| set)
+        .collect()
+}
+
+pub fn all_values<T>(m: &HashMap<UsbId, HashSet<T>>) -> Vec<&T> {
+    m.values().flatten().collect()
+}
+
+/// The serial numbers that the patterns matching the usb_id ask for.
+/// Empty if any of them accepts every serial number, or if there are no patterns.
+pub fn required_serials<'a>(patterns: &'a [UsbIdPattern], usb_id: &UsbId) -> Vec<&'a str> {
+    let matching = patterns.iter().filter(|p| p.matches(usb_id));
+    let mut serials = Vec::new();
+    for p in matching {
+        match &p.serial {
+            Some(serial) => serials.push(serial.as_str()),
+            None => return Vec::new(),
+        }
+    }
+    serials
+}
+
+/// Whether the serial number is one of the required ones, see [`required_serials`].
+/// An unknown serial number never matches a required one.
+pub fn serial_matches(required: &[&str], serial: Option<&str>) -> bool {
+    required.is_empty() || serial.is_some_and(|s| required.contains(&s))
+}
+
+/// The concrete usb_ids that each of the patterns matched
+pub fn expand_patterns(
+    patterns: &[UsbIdPattern],
+    usb_ids: &[UsbId],
+) -> Vec<(UsbIdPattern, Vec<UsbId>)> {
+    patterns
+        .iter()
+        .map(|p| {
+            let mut matched = usb_ids
+                .iter()
+                .filter(|u| p.matches(u))
+                .copied()
+                .collect::<Vec<_>>();
+            matched.sort();
+            matched.dedup();
+            (p.clone(), matched)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("1050:0407")]
+    #[case("1050:0407\n")]
+    #[case("0x1050:0x0407")]
+    #[case("0X1050:0X0407")]
+    fn test_parse_usb_id(#[case] input: &str) {
+        let usb_id = input.parse::<UsbId>().unwrap();
+        assert_eq!(usb_id, UsbId::new(0x1050, 0x0407));
+        assert_eq!(usb_id.to_string(), "1050:0407");
+    }
+
+    #[test]
+    fn test_parse_uppercase_usb_id() {
+        assert_eq!(
+            "058F:9540".parse::<UsbId>().unwrap(),
+            "058f:9540".parse::<UsbId>().unwrap()
+        );
+    }
+
+    #[rstest]
+    #[case("1050:*", None, None)]
+    #[case("0x1050:*", None, None)]
+    #[case("1050:0407", Some(0x0407), None)]
+    #[case("1050:0407@12345678", Some(0x0407), Some("12345678"))]
+    #[case("1050:*@12345678", None, Some("12345678"))]
+    fn test_parse_usb_id_pattern(
+        #[case] input: &str,
+        #[case] product: Option<u16>,
+        #[case] serial: Option<&str>,
+    ) {
+        let pattern = input.parse::<UsbIdPattern>().unwrap();
+        assert_eq!(
+            pattern,
+            UsbIdPattern {
+                vendor: 0x1050,
+                product,
+                serial: serial.map(String::from),
+            }
+        );
+        assert_eq!(
+            pattern.to_string().parse::<UsbIdPattern>().unwrap(),
+            pattern
+        );
+        assert!(pattern.matches(&UsbId::new(0x1050, 0x0407)));
+        assert!(!pattern.matches(&UsbId::new(0x058f, 0x0407)));
+        assert!(":*".parse::<UsbIdPattern>().is_err());
+        assert!("*:0407".parse::<UsbIdPattern>().is_err());
+        assert!("1050:0407@".parse::<UsbIdPattern>().is_err());
+        assert!("1050:04O7@1".parse::<UsbIdPattern>().is_err());
+    }
+
+    #[rstest]
+    #[case("1-1")]
+    #[case("1-4.3.4")]
+    #[case("12-10.1\n")]
+    fn test_parse_busid(#[case] input: &str) {
+        assert_eq!(input.parse::<BusId>().unwrap().0, input.trim());
+    }
+
+    #[rstest]
+    #[case("1")]
+    #[case("1-")]
+    #[case("-1")]
+    #[case("1-4.")]
+    #[case("1-4..3")]
+    #[case("usb1")]
+    #[case("1050:0407")]
+    fn test_parse_invalid_busid(#[case] input: &str) {
+        let err = input.parse::<BusId>().unwrap_err();
+        assert!(err.to_string().contains(&format!("`{input}`")));
+    }
+
+    #[rstest]
+    #[case("0x0b", 0x0b)]
+    #[case("0B", 0x0b)]
+    #[case("3", 0x03)]
+    #[case("hid", 0x03)]
+    #[case(" SmartCard ", 0x0b)]
+    #[case("0xff", 0xff)]
+    #[case("ccid", 0x0b)]
+    fn test_parse_usb_class(#[case] input: &str, #[case] expected: u8) {
+        let class = input.parse::<UsbClass>();
+        assert_eq!(class, Ok(UsbClass(expected)));
+        assert_eq!(class.unwrap().to_string(), format!("0x{expected:02x}"));
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("0x100")]
+    #[case("fido")]
+    #[case("-1")]
+    fn test_parse_invalid_usb_class(#[case] input: &str) {
+        let err = input.parse::<UsbClass>().unwrap_err();
+        assert!(err.to_string().contains(&format!("`{input}`")));
+    }
+
+    #[test]
+    fn test_required_serials() {
+        let yubikey = UsbId::new(0x1050, 0x0407);
+        let patterns =
+            ["1050:0407@1", "1050:*@2", "058f:9540"].map(|p| p.parse::<UsbIdPattern>().unwrap());
+        assert_eq!(required_serials(&patterns, &yubikey), vec!["1", "2"]);
+        assert!(required_serials(&patterns, &UsbId::new(0x058f, 0x9540)).is_empty());
+        assert!(required_serials(&[], &yubikey).is_empty());
+        let any = ["1050:0407@1", "1050:*"].map(|p| p.parse::<UsbIdPattern>().unwrap());
+        assert!(required_serials(&any, &yubikey).is_empty());
+
+        assert!(serial_matches(&["1", "2"], Some("2")));
+        assert!(!serial_matches(&["1", "2"], Some("3")));
+        assert!(!serial_matches(&["1"], None));
+        assert!(serial_matches(&[], None));
+    }
+
+    #[test]
+    fn test_expand_patterns() {
+        let usb_ids = [
+            UsbId::new(0x1050, 0x0410),
+            UsbId::new(0x1050, 0x0406),
+            UsbId::new(0x058f, 0x9540),
+            UsbId::new(0x1050, 0x0406),
+        ];
+        let patterns = ["1050:*", "dead:beef"].map(|p| p.parse::<UsbIdPattern>().unwrap());
+        assert_eq!(
+            expand_patterns(&patterns, &usb_ids),
+            vec![
+                (
+                    patterns[0].clone(),
+                    vec![UsbId::new(0x1050, 0x0406), UsbId::new(0x1050, 0x0410)]
+                ),
+                (patterns[1].clone(), vec![]),
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case("1050:04O7")]
+    #[case("10500407")]
+    #[case("1050:")]
+    #[case(":0407")]
+    #[case("10500:0407")]
+    #[case("1050:407")]
+    #[case("0x1050:0x407")]
+    #[case("1050:+407")]
+    #[case("1050:0407:1")]
+    fn test_parse_invalid_usb_id(#[case] input: &str) {
+        let err = input.parse::<UsbId>().unwrap_err();
+        assert!(err.to_string().contains(&format!("`{input}`")));
+    }
+}
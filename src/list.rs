@@ -0,0 +1,429 @@
+//! Listings of the hostable, mountable and unmountable USB devices.
+//!
+//! The `Display` implementations mimic the output of the matching `usbip` commands.
+use core::fmt;
+use std::collections::{HashMap, HashSet};
+
+use log::debug;
+use serde::Serialize;
+
+use crate::backend::{AttachedDevice, Backend};
+use crate::protocol::UsbDevice;
+use crate::sysfs::LocalDevice;
+use crate::usb_ids;
+use crate::usbip_host::USBIP_HOST_DRIVER;
+use crate::{BusId, Port, UsbId};
+
+/// Simple Pair wrapper for convenience around `BusId` and `UsbId`
+struct IdPair {
+    bus_id: BusId,
+    usb_id: UsbId,
+}
+
+struct UsbPortPair {
+    usb_id: UsbId,
+    port: Port,
+}
+
+/// A device of any of the listings in a machine-readable form.
+/// Fields that do not apply to the listing are `None`.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize)]
+pub struct DeviceRecord {
+    pub busid: Option<String>,
+    pub vid: Option<String>,
+    pub pid: Option<String>,
+    pub vendor: Option<String>,
+    pub product: Option<String>,
+    /// Unknown for the devices that the usbip host exports
+    pub serial: Option<String>,
+    /// The driver of a local device
+    pub driver: Option<String>,
+    /// Whether the local device is bound to `usbip-host`,
+    /// unknown if the device has no driver or the backend cannot tell
+    pub bound: Option<bool>,
+    pub remote_host: Option<String>,
+    /// The vhci port that the remote device is attached to
+    pub port: Option<String>,
+}
+
+impl DeviceRecord {
+    /// The names are taken from the USB ID list
+    pub(crate) fn with_usb_id(usb_id: &UsbId) -> Self {
+        DeviceRecord {
+            vid: Some(format!("{:04x}", usb_id.vendor)),
+            pid: Some(format!("{:04x}", usb_id.product)),
+            vendor: usb_ids::vendor_name(usb_id.vendor).map(String::from),
+            product: usb_ids::product_name(*usb_id).map(String::from),
+            ..Default::default()
+        }
+    }
+}
+
+/// The name of the vendor in the USB ID list, named like `usbip` does if it is not listed
+fn vendor_or_unknown(usb_id: UsbId) -> &'static str {
+    usb_ids::vendor_name(usb_id.vendor).unwrap_or("unknown vendor")
+}
+
+/// The name of the product in the USB ID list, named like `usbip` does if it is not listed
+fn product_or_unknown(usb_id: UsbId) -> &'static str {
+    usb_ids::product_name(usb_id).unwrap_or("unknown product")
+}
+
+/// All locally hostable USB devices
+#[derive(Debug)]
+pub struct ListHostable {
+    pub devices: Vec<LocalDevice>,
+}
+
+/// All remotely mountable USB devices as reported by the usbip host
+#[derive(Debug)]
+pub struct ListMountable {
+    pub host: String,
+    pub devices: Vec<UsbDevice>,
+}
+
+/// All, from a remote usbip-hosted, mounted devices
+/// that can be unmounted
+#[derive(Debug)]
+pub struct ListUnmountable {
+    /// The used vhci ports and the attached devices
+    pub ports: Vec<AttachedDevice>,
+}
+
+impl ListHostable {
+    pub fn new(backend: &impl Backend) -> anyhow::Result<Self> {
+        let devices = backend.list_local()?;
+        Ok(ListHostable { devices })
+    }
+
+    pub fn build_usbid_map(&self) -> HashMap<UsbId, HashSet<BusId>> {
+        build_usbid_map_from_pairs(self.devices.iter().map(|d| IdPair {
+            bus_id: d.busid().clone(),
+            usb_id: d.usb_id(),
+        }))
+    }
+
+    pub fn records(&self) -> Vec<DeviceRecord> {
+        self.devices
+            .iter()
+            .map(|d| {
+                let record = DeviceRecord::with_usb_id(&d.usb_id());
+                // prefer the names that the device reports itself
+                DeviceRecord {
+                    busid: Some(d.busid().to_string()),
+                    vendor: d.manufacturer.clone().or(record.vendor),
+                    product: d.product.clone().or(record.product),
+                    serial: d.serial.clone(),
+                    driver: d.driver.clone(),
+                    bound: d.driver.as_ref().map(|driver| driver == USBIP_HOST_DRIVER),
+                    ..record
+                }
+            })
+            .collect()
+    }
+}
+
+/// Mimics the output of `usbip list --local`
+/// but also shows the driver the device is currently bound to
+impl fmt::Display for ListHostable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for d in &self.devices {
+            let usb_id = d.usb_id();
+            writeln!(f, " - busid {} ({usb_id})", d.busid())?;
+            writeln!(
+                f,
+                "   {} : {} ({usb_id})",
+                d.manufacturer
+                    .as_deref()
+                    .unwrap_or_else(|| vendor_or_unknown(usb_id)),
+                d.product
+                    .as_deref()
+                    .unwrap_or_else(|| product_or_unknown(usb_id)),
+            )?;
+            if let Some(serial) = &d.serial {
+                writeln!(f, "   serial: {serial}")?;
+            }
+            writeln!(f, "   driver: {}", d.driver.as_deref().unwrap_or("none"))?;
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Group the given pairs to a usbid-{busid} map
+fn build_usbid_map_from_pairs(
+    pairs: impl Iterator<Item = IdPair>,
+) -> HashMap<UsbId, HashSet<BusId>> {
+    pairs.fold(HashMap::new(), |mut acc, p| {
+        acc.entry(p.usb_id).or_default().insert(p.bus_id);
+        acc
+    })
+}
+
+impl ListMountable {
+    /// Ask the usbip host for a list of all mountable USB devices
+    pub fn new(backend: &impl Backend, host: &str, port: u16) -> anyhow::Result<Self> {
+        let devices = backend.list_remote(host, port)?;
+        Ok(ListMountable {
+            host: host.to_string(),
+            devices,
+        })
+    }
+
+    pub fn build_usbid_map(&self) -> HashMap<UsbId, HashSet<BusId>> {
+        build_usbid_map_from_pairs(self.devices.iter().map(|d| IdPair {
+            bus_id: d.busid.clone(),
+            usb_id: d.usb_id(),
+        }))
+    }
+
+    pub fn records(&self) -> Vec<DeviceRecord> {
+        self.devices
+            .iter()
+            .map(|d| DeviceRecord {
+                busid: Some(d.busid.to_string()),
+                remote_host: Some(self.host.clone()),
+                ..DeviceRecord::with_usb_id(&d.usb_id())
+            })
+            .collect()
+    }
+}
+
+/// Mimics the output of `usbip list --remote`
+impl fmt::Display for ListMountable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Exportable USB devices")?;
+        writeln!(f, "======================")?;
+        writeln!(f, " - {}", self.host)?;
+        for d in &self.devices {
+            let usb_id = d.usb_id();
+            writeln!(
+                f,
+                "{:>11}: {} : {} ({usb_id})",
+                d.busid,
+                vendor_or_unknown(usb_id),
+                product_or_unknown(usb_id),
+            )?;
+            writeln!(f, "{:>11}: {}", "", d.path)?;
+            writeln!(
+                f,
+                "{:>11}: ({:02x}/{:02x}/{:02x})",
+                "", d.device_class, d.device_subclass, d.device_protocol
+            )?;
+            for (i, intf) in d.interfaces.iter().enumerate() {
+                writeln!(
+                    f,
+                    "{:>11}: {i:>2} - ({:02x}/{:02x}/{:02x})",
+                    "", intf.class, intf.subclass, intf.protocol
+                )?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl ListUnmountable {
+    /// Read the used vhci ports and look up the attached devices
+    pub fn new(backend: &impl Backend) -> anyhow::Result<Self> {
+        let ports = backend.list_ports()?;
+        Ok(ListUnmountable { ports })
+    }
+
+    pub fn build_usbid_map(&self) -> HashMap<UsbId, HashSet<Port>> {
+        self.ports
+            .iter()
+            .filter_map(|p| match &p.usb_id {
+                Some(usb_id) => Some(UsbPortPair {
+                    port: p.port.clone(),
+                    usb_id: *usb_id,
+                }),
+                None => {
+                    debug!("Device at port {} is not enumerated yet", p.port);
+                    None
+                }
+            })
+            .fold(HashMap::new(), |mut acc, p| {
+                acc.entry(p.usb_id).or_default().insert(p.port);
+                acc
+            })
+    }
+
+    /// The busid is the one of the device on the remote host
+    pub fn records(&self) -> Vec<DeviceRecord> {
+        self.ports
+            .iter()
+            .map(|p| {
+                let record = match &p.usb_id {
+                    Some(usb_id) => DeviceRecord::with_usb_id(usb_id),
+                    None => DeviceRecord::default(),
+                };
+                DeviceRecord {
+                    busid: p.remote.as_ref().map(|r| r.busid.to_string()),
+                    serial: p.serial.clone(),
+                    remote_host: p.remote.as_ref().map(|r| r.host.clone()),
+                    port: Some(p.port.to_string()),
+                    ..record
+                }
+            })
+            .collect()
+    }
+}
+
+/// Mimics the output of `usbip port`
+impl fmt::Display for ListUnmountable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Imported USB devices")?;
+        writeln!(f, "====================")?;
+        for p in &self.ports {
+            writeln!(f, "Port {:0>2}: <Port in Use>", p.port)?;
+            match &p.usb_id {
+                Some(usb_id) => writeln!(
+                    f,
+                    "       {} : {} ({usb_id})",
+                    vendor_or_unknown(*usb_id),
+                    product_or_unknown(*usb_id),
+                )?,
+                None => writeln!(f, "       (not enumerated yet)")?,
+            }
+            match &p.remote {
+                Some(r) => writeln!(f, "       -> usbip://{}:{}/{}", r.host, r.tcp_port, r.busid)?,
+                None => writeln!(f, "       -> unknown host, remote port and remote busid")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::NativeBackend;
+    use crate::collect_matching;
+    use crate::protocol;
+    use crate::sysfs;
+
+    #[test]
+    fn test_find_matching_pairs() {
+        let device = |busid: &str, id_product: u16| UsbDevice {
+            path: format!("/sys/devices/usb1/{busid}"),
+            busid: BusId(busid.to_string()),
+            busnum: 1,
+            devnum: 2,
+            speed: protocol::UsbSpeed::Full,
+            id_vendor: 0x058f,
+            id_product,
+            bcd_device: 0,
+            device_class: 0,
+            device_subclass: 0,
+            device_protocol: 0,
+            configuration_value: 1,
+            num_configurations: 1,
+            interfaces: Vec::new(),
+        };
+        let list = ListMountable {
+            host: "localhost".to_string(),
+            devices: vec![
+                device("1-11", 0x9540),
+                device("1-12", 0x9540),
+                device("12-1", 0x0001),
+            ],
+        };
+        let m = list.build_usbid_map();
+        assert_eq!(m.len(), 2);
+        assert_eq!(
+            m[&UsbId::new(0x058f, 0x9540)],
+            ["1-11", "1-12"]
+                .into_iter()
+                .map(|b| BusId(b.to_string()))
+                .collect()
+        );
+        assert_eq!(
+            collect_matching(&m, &[UsbId::new(0x058f, 0x0001).into()]),
+            vec![&BusId("12-1".to_string())]
+        );
+        let text = list.to_string();
+        assert!(
+            text.contains("       1-11: Alcor Micro Corp. : AU9540 Smartcard Reader (058f:9540)")
+        );
+        assert!(text.contains("       12-1: Alcor Micro Corp. : unknown product (058f:0001)"));
+    }
+
+    #[test]
+    fn test_port() {
+        let fake = sysfs::tests::FakeSysfs::new();
+        fake.set_platform_attr(
+            "vhci_hcd.0",
+            "status",
+            "\
+hub port sta spd dev      sockfd local_busid
+hs  0000 006 002 0001000b 000003 3-1
+hs  0001 006 002 0001000c 000004 3-2
+hs  0002 006 002 0001000d 000005 3-3
+hs  0003 004 000 00000000 000000 0-0
+",
+        );
+        fake.add_device("3-1", "1050", "0407");
+        fake.add_device("3-2", "1050", "0407");
+        // 3-3 is not enumerated yet
+        let backend = NativeBackend::with_state_dir(fake.sysfs(), fake.root().join("state"));
+        let list = ListUnmountable::new(&backend).unwrap();
+        assert_eq!(list.ports.len(), 3);
+        let m = list.build_usbid_map();
+        assert_eq!(m.len(), 1);
+        assert_eq!(
+            m[&UsbId::new(0x1050, 0x0407)],
+            [Port("0".to_string()), Port("1".to_string())].into()
+        );
+        let records = list.records();
+        assert_eq!(records[0].vid.as_deref(), Some("1050"));
+        assert_eq!(records[0].port.as_deref(), Some("0"));
+        assert_eq!(records[2].vid, None);
+    }
+
+    #[test]
+    fn test_hostable_records() {
+        let fake = sysfs::tests::FakeSysfs::new();
+        fake.add_device("1-1", "1050", "0407");
+        fake.set_driver("1-1", Some(USBIP_HOST_DRIVER));
+        fake.set_attr("1-1", "manufacturer", "Yubico\n");
+        fake.set_attr("1-1", "serial", "CC1234\n");
+        let list = ListHostable::new(&NativeBackend::new(fake.sysfs())).unwrap();
+        let record = &list.records()[0];
+        assert_eq!(
+            serde_json::to_value(record).unwrap(),
+            serde_json::json!({
+                "busid": "1-1",
+                "vid": "1050",
+                "pid": "0407",
+                "vendor": "Yubico",
+                // the device does not report a name, it is taken from the USB ID list
+                "product": "Yubikey 4/5 OTP+U2F+CCID",
+                "serial": "CC1234",
+                "driver": "usbip-host",
+                "bound": true,
+                "remote_host": null,
+                "port": null,
+            })
+        );
+    }
+
+    #[test]
+    fn test_hostable_behind_hub() {
+        let fake = sysfs::tests::FakeSysfs::new();
+        fake.add_device("1-4.3.4", "0bda", "402e");
+        fake.add_device("1-4.3.5", "413c", "b06f");
+        fake.add_device("1-4.5", "413c", "b06e");
+        let list = ListHostable::new(&NativeBackend::new(fake.sysfs())).unwrap();
+        let mut busids = list
+            .devices
+            .iter()
+            .map(|d| d.busid().to_string())
+            .collect::<Vec<_>>();
+        busids.sort();
+        assert_eq!(busids, vec!["1-4.3.4", "1-4.3.5", "1-4.5"]);
+        let text = list.to_string();
+        assert!(text.contains("busid 1-4.3.4 (0bda:402e)"), "{text}");
+        assert!(text.contains("Realtek Semiconductor Corp. : unknown product (0bda:402e)"));
+    }
+}
@@ -1,48 +1,111 @@
-use core::fmt;
+use std::io::{self, IsTerminal};
 use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
 
 use anyhow::{anyhow, Context};
-use clap::{Parser, Subcommand};
-use env_logger;
-use log::{debug, error};
-use regex::Regex;
-use std::collections::{HashMap, HashSet};
-use std::string::String;
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use log::debug;
+use serde::Serialize;
 use xshell::{cmd, Shell};
 
+use usbip_wrapper::backend::{NativeBackend, ShellBackend};
+use usbip_wrapper::client::{unmet_before_mount, MountOutcome};
+use usbip_wrapper::dry_run::PlannedOperation;
+use usbip_wrapper::hoster::{self, Hoster, Lifetime};
+use usbip_wrapper::selector::{Exclusion, Expectation, MatchCount, RequestedId};
+use usbip_wrapper::server::{self, Server};
+use usbip_wrapper::{
+    systemd, usb_ids, Backend, BusId, Client, Error, Host, Selector, Sysfs, UsbClass, UsbId,
+    UsbIdPattern,
+};
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Root of the sysfs hierarchy that is used to find the local USB devices
+    #[arg(long, global = true, default_value = "/sys", env = "USBIP_SYSFS_ROOT")]
+    sysfs_root: PathBuf,
+
+    /// How to talk to the kernel and the usbip host
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = BackendKind::Native,
+        env = "USBIP_BACKEND"
+    )]
+    backend: BackendKind,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum BackendKind {
+    /// Use sysfs and the USB/IP protocol directly
+    Native,
+    /// Call the `usbip` executable
+    Shell,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Bind USB device
     Host {
+        /// Not required for binding, only kept for backwards compatibility
         #[arg(long, default_value_t = 3240, env = "USBIP_TCP_PORT")]
-        tcp_port: u32,
-        #[arg(last = true, required = true)]
-        usb_ids: Vec<String>,
+        tcp_port: u16,
+        /// UsbIds to bind, `VVVV:*` binds all devices of the vendor.
+        /// Not required if the devices are selected by `--busid`, a class or `--expect`
+        #[arg(
+            last = true,
+            required_unless_present_any = ["busid", "class", "interface_class", "expect"]
+        )]
+        usb_ids: Vec<UsbIdPattern>,
+        #[command(flatten)]
+        selectors: Selectors,
+        #[command(flatten)]
+        classes: ClassSelectors,
+        #[command(flatten)]
+        expectations: Expectations,
+        #[command(flatten)]
+        dry_run: DryRun,
     },
     /// Unbind USB device
     /// If unhosted while remote is still connected, it seems like
     /// it will also be disconnected from the client without any issues.
     Unhost {
+        /// Not required for unbinding, only kept for backwards compatibility
         #[arg(long, default_value_t = 3240, env = "USBIP_TCP_PORT")]
-        tcp_port: u32,
-        /// Not specifying a value will unbind all hosted USB devices!
+        tcp_port: u16,
+        /// UsbIds to unbind, `VVVV:*` unbinds all devices of the vendor.
+        /// Unbinding all hosted USB devices requires `--all`.
         #[arg(last = true)]
-        usb_ids: Vec<String>,
+        usb_ids: Vec<UsbIdPattern>,
+        #[command(flatten)]
+        selectors: Selectors,
+        #[command(flatten)]
+        classes: ClassSelectors,
+        #[command(flatten)]
+        expectations: Expectations,
+        #[command(flatten)]
+        all: AllDevices,
+        #[command(flatten)]
+        dry_run: DryRun,
     },
-    /// Start usbip daemon via `usbipd`
+    /// Start usbip daemon via `usbipd` or the built-in server
     StartUsbHoster {
+        /// Use the built-in server instead of the external `usbipd` daemon
+        #[arg(long, env = "USBIP_DAEMON_BUILTIN")]
+        builtin: bool,
+
         /// Start daemon with debug option enabled
         #[arg(long, env = "USBIP_DAEMON_DEBUG")]
         debug: bool,
 
-        /// Change path to PID file
+        /// Change path to PID file, only used by `usbipd`
         #[arg(
             long,
             default_value = "/var/run/usbipd.pid",
@@ -50,35 +113,72 @@ enum Commands {
         )]
         pid: PathBuf,
 
-        /// Select which TCP port to use
+        /// Select which TCP port to use, ignored if the sockets are passed
+        /// by systemd via socket activation (which implies `--builtin`)
         /// Please note that the environment variable does NOT contain DAEMON
         /// to highlight that the environment variable is shared between the
         /// usbip interface and the daemon.
         #[arg(long, default_value_t = 3240, env = "USBIP_TCP_PORT")]
-        tcp_port: u32,
+        tcp_port: u16,
+
+        /// Exit after the given time, even if a device is still in use,
+        /// e.g., `900` (seconds) or `15min`.
+        /// If a lifetime is set, the exported devices are unbound on exit.
+        #[arg(long, value_parser = hoster::parse_duration, env = "USBIP_DAEMON_MAX_LIFETIME")]
+        max_lifetime: Option<Duration>,
+
+        /// Exit after no device was imported for the given time, e.g., `90` (seconds) or `5min`.
+        /// If a lifetime is set, the exported devices are unbound on exit.
+        #[arg(long, value_parser = hoster::parse_duration, env = "USBIP_DAEMON_IDLE_TIMEOUT")]
+        idle_timeout: Option<Duration>,
     },
     /// List all devices that can be hosted, i.e. all USB devices that are connected locally
-    ListHostable {},
+    ListHostable {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+    },
     /// List all devices that can be mounted from an usbip host.
     /// Defaults to `localhost` which allows to quickly debug if previous mounted usb devices
     /// were attached correctly. For _real_ use, please overwrite the `host` value to the external
     /// usbip host/server.
     ListMountable {
         #[arg(long, default_value_t = 3240, env = "USBIP_TCP_PORT")]
-        tcp_port: u32,
+        tcp_port: u16,
         #[arg(long, default_value = "localhost", env = "USBIP_REMOTE_HOST")]
         host: String,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+    },
+    /// List all remote devices that are mounted locally and can be unmounted
+    ListUnmountable {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
     },
     /// Mount devices from an usbip host.
     MountRemote {
         #[arg(long, default_value_t = 3240, env = "USBIP_TCP_PORT")]
-        tcp_port: u32,
+        tcp_port: u16,
         #[arg(long, required = true, env = "USBIP_REMOTE_HOST")]
         host: String,
-        /// UsbIds to mount; if none are given it will default to mounting
-        /// _all_ remotely available USB devices!
+        /// UsbIds to mount, `VVVV:*` mounts all devices of the vendor.
+        /// Mounting _all_ remotely available USB devices requires `--all`.
         #[arg(last = true)]
-        usb_ids: Vec<String>,
+        usb_ids: Vec<UsbIdPattern>,
+        #[command(flatten)]
+        selectors: Selectors,
+        #[command(flatten)]
+        classes: ClassSelectors,
+        #[command(flatten)]
+        expectations: Expectations,
+        #[command(flatten)]
+        all: AllDevices,
+        #[command(flatten)]
+        dry_run: DryRun,
+        /// Retry with exponential backoff until every requested id was mounted or
+        /// the given time passed, e.g., `30` (seconds) or `2min`.
+        /// Useful while the usbip host is still booting.
+        #[arg(long, value_parser = hoster::parse_duration, conflicts_with = "dry_run")]
+        wait: Option<Duration>,
     },
     /// Unmount remote device
     /// Required (!) to be able to re-mount the USB device again
@@ -87,562 +187,600 @@ enum Commands {
     /// but the host will be called `unknown host, remote port and remote busid`
     /// but it will still list the used port and the usbid
     UnmountRemote {
+        /// Print the detached ports in the given format
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+        /// UsbIds to unmount, `VVVV:*` unmounts all devices of the vendor.
+        /// Unmounting all mounted USB devices requires `--all`.
         #[arg(last = true)]
-        usb_ids: Vec<String>,
+        usb_ids: Vec<UsbIdPattern>,
+        #[command(flatten)]
+        selectors: Selectors,
+        #[command(flatten)]
+        all: AllDevices,
+        #[command(flatten)]
+        dry_run: DryRun,
     },
 }
 
-/// A simple enum that indicates whether to bind or
-/// unbind a local USB device
-enum BindType {
-    Bind,
-    Unbind,
+/// Narrow down the devices that the UsbIds select
+#[derive(Debug, Args)]
+struct Selectors {
+    /// Only select the device at the given busid, e.g., `1-4.3.4` for a device behind a hub.
+    /// May be given multiple times. For remote devices, it is the busid on the usbip host,
+    /// see `list-mountable`.
+    #[arg(long)]
+    busid: Vec<BusId>,
+    /// Only select the device with the given serial number, same as `VVVV:PPPP@SERIAL`.
+    /// May be given multiple times. The serial numbers of remote devices can only be
+    /// read by the native backend, which imports every candidate device to read it.
+    /// A device with another serial number is released again, which resets it on the usbip host.
+    #[arg(long, requires = "usb_ids")]
+    serial: Vec<String>,
+    /// Do not select the devices with the given USB ID (`VVVV:PPPP` or `VVVV:*`), busid or class,
+    /// e.g., `--exclude video` to skip the webcam. Applied after matching and may be given
+    /// multiple times. Devices whose class or busid is unknown are excluded as well,
+    /// classes cannot be excluded on `unmount-remote`.
+    #[arg(long)]
+    exclude: Vec<Exclusion>,
 }
 
-impl BindType {
-    // TODO: Read up if this can be split into two parts!
-    fn execute(&self, busid: &BusId, tcp_port: u32) -> anyhow::Result<()> {
-        let port = tcp_port.to_string();
-        let b = busid.to_string();
-        let sh = Shell::new()?;
-        match &self {
-            BindType::Bind => {
-                let bind_type_s = self.to_string();
-                let stderr = cmd!(sh, "usbip --tcp-port {port} {bind_type_s} --busid={b}")
-                    .ignore_status()
-                    .read_stderr()
-                    .expect("Error during reading StdErr!");
-                if stderr.contains("error: ") && !stderr.contains("already bound to usbip-host") {
-                    Err(anyhow!("Unknown error message: {stderr}"))?;
-                }
-                Ok(())
-            }
-            BindType::Unbind => {
-                let bind_type_s = self.to_string();
-                let stderr = cmd!(sh, "usbip --tcp-port {port} {bind_type_s} --busid={b}")
-                    .ignore_status()
-                    .read_stderr()
-                    .expect("Error during reading StdErr!");
-                if stderr.contains("error: ")
-                    && !stderr.contains("device is not bound to usbip-host")
-                {
-                    Err(anyhow!("Unknown error message: {stderr}"))?;
-                }
-                Ok(())
-            }
+impl Selectors {
+    /// Combine the UsbIds with the other selectors
+    fn selector(self, usb_ids: Vec<UsbIdPattern>) -> Selector {
+        Selector {
+            usb_ids: self.patterns(usb_ids),
+            busids: self.busid,
+            excluded: self.exclude,
+            ..Default::default()
         }
     }
-}
 
-impl fmt::Display for BindType {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            BindType::Bind => write!(f, "bind"),
-            BindType::Unbind => write!(f, "unbind"),
+    /// Whether a device is excluded by its class
+    fn excludes_classes(&self) -> bool {
+        self.exclude
+            .iter()
+            .any(|e| matches!(e, Exclusion::Class(_)))
+    }
+
+    /// Turn the UsbIds into the patterns that select the devices
+    fn patterns(&self, usb_ids: Vec<UsbIdPattern>) -> Vec<UsbIdPattern> {
+        if self.serial.is_empty() {
+            return usb_ids;
         }
+        usb_ids
+            .into_iter()
+            .flat_map(|p| match p.serial {
+                // an explicit `@SERIAL` takes precedence
+                Some(_) => vec![p],
+                None => self
+                    .serial
+                    .iter()
+                    .map(|s| p.clone().with_serial(s))
+                    .collect(),
+            })
+            .collect()
     }
 }
 
-/// Simple struct string-variant that contains
-/// a unique BusId (which may change between reboots!)
-#[derive(Debug, Eq, PartialEq, Hash, Clone)]
-struct BusId(String);
-
-/// Internal USB port that the "virtual"/remote USB
-/// was locally attached to.
-/// Has NOTHING to do with the TCP port!
-#[derive(Debug, Eq, PartialEq, Hash, Clone)]
-struct Port(String);
-
-/// Simple struct string-variant that contains
-/// a UsbId/VendorId that might be shared across multiple USB
-/// devices from the same vendor, for example, when having multiple
-/// hardware keys, like the Yubikey plugged in
-#[derive(Debug, Eq, PartialEq, Hash, Clone)]
-struct UsbId(String);
-
-/// Simple Pair wrapper for convenience around `BusId` and `UsbId`
-struct IdPair {
-    bus_id: BusId,
-    usb_id: UsbId,
+/// Narrow down the devices by their class codes, e.g., `--class smartcard`.
+/// The classes of local devices are read from sysfs, which requires `--backend native`.
+#[derive(Debug, Args)]
+struct ClassSelectors {
+    /// Only select the devices of the given class, given as hex code, e.g., `0x0b`, or name,
+    /// e.g., `smartcard` or `hid`. Devices that define the class per interface,
+    /// like most smartcard readers, match if one of their interfaces has the class.
+    /// May be given multiple times.
+    #[arg(long)]
+    class: Vec<UsbClass>,
+    /// Only select the devices with an interface of the given class, e.g., `hid`.
+    /// May be given multiple times.
+    #[arg(long)]
+    interface_class: Vec<UsbClass>,
 }
 
-struct UsbPortPair {
-    usb_id: UsbId,
-    port: Port,
-}
+impl ClassSelectors {
+    fn is_empty(&self) -> bool {
+        self.class.is_empty() && self.interface_class.is_empty()
+    }
 
-impl fmt::Display for BusId {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+    fn narrow(self, selector: Selector) -> Selector {
+        Selector {
+            classes: self.class,
+            interface_classes: self.interface_class,
+            ..selector
+        }
     }
 }
 
-impl fmt::Display for UsbId {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
-    }
+/// Guard against acting on every device because an argument was forgotten
+#[derive(Debug, Args)]
+struct AllDevices {
+    /// Act on all devices if no UsbId, busid or class is given.
+    /// On a terminal, the devices are listed and have to be confirmed.
+    #[arg(long)]
+    all: bool,
+    /// Do not ask for confirmation before acting on all devices
+    #[arg(long, short, requires = "all")]
+    yes: bool,
 }
 
-impl fmt::Display for Port {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+impl AllDevices {
+    /// Refuse an empty selector unless `--all` is given.
+    /// `action` is the verb of the command, e.g., `unmount`.
+    fn require(&self, selector: &Selector, action: &str) {
+        if selector.is_empty() && !self.all {
+            Cli::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    format!(
+                        "no UsbId, busid or class was given, \
+                         pass `--all` to {action} all devices"
+                    ),
+                )
+                .exit();
+        }
     }
-}
 
-/// A simple struct string-variant that contains
-/// all locally hostable USB devices in a detailed/pretty format
-#[derive(Debug)]
-struct ListHostable(String);
-
-/// A simple struct string-variant that contains
-/// all locally hostable USB devices in a compressed/parsable format
-#[derive(Debug)]
-struct ListHostableParsable(String);
-
-/// A simple struct string-variant that contains
-/// all remotely mountable USB devices
-#[derive(Debug)]
-struct ListMountable(String);
-
-/// A simple struct string-variant that contains
-/// all, from a remote usbip-hosted, mounted devices
-/// that can be unmounted
-#[derive(Debug)]
-struct ListUnmountable(String);
-
-// Sharing life-time mostly as practice, should actually clone for better UX
-// TODO: Think about if this is not simply a set operation and all values
-/// Given the set of usb_ids and a map of usb_ids and a value set, return
-/// all values that match.
-fn collect_matching<'a, T>(
-    m: &'a HashMap<UsbId, HashSet<T>>,
-    usb_ids: &HashSet<UsbId>,
-) -> Vec<&'a T> {
-    m.iter()
-        .filter(|(usbid, _set)| usb_ids.contains(usbid))
-        .flat_map(|(_usbid, set)| set)
-        .collect()
+    /// Same as [`AllDevices::require`] but also lets the user confirm the `devices`
+    /// on a terminal if all devices are selected
+    fn confirm(
+        &self,
+        selector: &Selector,
+        action: &str,
+        devices: impl FnOnce() -> anyhow::Result<Vec<String>>,
+    ) -> Result<(), Error> {
+        self.require(selector, action);
+        if !selector.is_empty() || self.yes || !io::stdin().is_terminal() {
+            return Ok(());
+        }
+        let devices = devices()?;
+        // nothing to confirm, the command reports that no device matched
+        if devices.is_empty() {
+            return Ok(());
+        }
+        eprintln!("This will {action} all {} devices:", devices.len());
+        for device in &devices {
+            eprintln!("  {device}");
+        }
+        eprint!("Continue? [y/N] ");
+        let mut answer = String::new();
+        io::stdin()
+            .read_line(&mut answer)
+            .context("Could not read the confirmation")?;
+        match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => Ok(()),
+            _ => Err(anyhow!("Aborted, no device was changed").into()),
+        }
+    }
 }
 
-fn all_values<'a, T>(m: &'a HashMap<UsbId, HashSet<T>>) -> Vec<&'a T> {
-    m.iter().flat_map(|(_usbid, set)| set).collect()
+/// Check how many devices each requested UsbId and busid matched
+#[derive(Debug, Args)]
+struct Expectations {
+    /// Fail if one of the UsbIds or busids matched no device, instead of only warning about it
+    #[arg(long)]
+    require_all: bool,
+    /// Fail unless exactly COUNT devices match the UsbId or busid, e.g., `1050:0407=2`
+    /// for two YubiKeys. The devices are selected as if the id was given as well.
+    /// May be given multiple times.
+    #[arg(long, value_name = "ID=COUNT")]
+    expect: Vec<Expectation>,
 }
 
-impl ListHostable {
-    fn new() -> anyhow::Result<Self> {
-        let sh = Shell::new()?;
-        let stdout = cmd!(sh, "usbip list --local").read()?;
-        Ok(ListHostable(stdout))
+impl Expectations {
+    /// Select the expected ids as well
+    fn extend(&self, mut selector: Selector) -> Selector {
+        for expectation in &self.expect {
+            match &expectation.id {
+                RequestedId::UsbId(pattern) if !selector.usb_ids.contains(pattern) => {
+                    selector.usb_ids.push(pattern.clone())
+                }
+                RequestedId::BusId(busid) if !selector.busids.contains(busid) => {
+                    selector.busids.push(busid.clone())
+                }
+                _ => {}
+            }
+        }
+        Selector {
+            expected: self.expect.clone(),
+            ..selector
+        }
     }
-}
 
-/// Small helper that parses the source to a usbid-{busid} map given a regular expression.
-/// The regular expression must contain the capture groups `busid` and `usbid`, otherwise
-/// the function will return an error.
-/// I wish there was a way to enforce this at compile time...
-fn build_usbid_map_helper(
-    source: &str,
-    regex: &Regex,
-) -> anyhow::Result<HashMap<UsbId, HashSet<BusId>>> {
-    if regex
-        .capture_names()
-        .filter_map(|cap| cap)
-        .collect::<HashSet<_>>()
-        != vec!["busid", "usbid"].into_iter().collect::<HashSet<_>>()
-    {
-        return Err(anyhow!("Provided invalid regular expression!\nMust have capture groups that contain `usbid` and `busid`!"));
+    /// Fail if the unmet ids were required or expected to match, see [`Expectations::check`]
+    fn require(&self, unmet: Vec<MatchCount>) -> Result<Vec<MatchCount>, Error> {
+        if (self.require_all && !unmet.is_empty()) || unmet.iter().any(|m| m.expected.is_some()) {
+            return Err(Error::UnmetExpectations { unmet });
+        }
+        Ok(unmet)
     }
-    let res = regex
-        .captures_iter(source)
-        .filter_map(|cap| match (cap.name("busid"), cap.name("usbid")) {
-            // defining a pair guarantees that there is no difference due to ordering
-            (Some(busid), Some(usbid)) => Some(IdPair {
-                bus_id: BusId(busid.as_str().to_string()),
-                usb_id: UsbId(usbid.as_str().to_string()),
-            }),
-            _ => None,
-        })
-        .fold(HashMap::new(), |mut acc, p| {
-            acc.entry(p.usb_id)
-                .or_insert(HashSet::new())
-                .insert(p.bus_id);
-            acc
-        });
-    Ok(res)
-}
 
-impl ListHostableParsable {
-    fn new() -> anyhow::Result<Self> {
-        let sh = Shell::new()?;
-        let stdout = cmd!(sh, "usbip list --parsable --local").read()?;
-        Ok(ListHostableParsable(stdout))
+    /// Warn about the ids that matched none of the selected devices,
+    /// or fail if they were required or expected to match.
+    /// Runs before anything is changed, so that a failed expectation has no side effects.
+    fn check(&self, unmet: Vec<MatchCount>) -> Result<(), Error> {
+        for m in self.require(unmet)? {
+            eprintln!("Warning: {m}");
+        }
+        Ok(())
     }
+}
 
-    fn build_usbid_map(&self) -> HashMap<UsbId, HashSet<BusId>> {
-        build_usbid_map_helper(
-            &self.0,
-            &Regex::new(r"busid=(?P<busid>.*?)#usbid=(?P<usbid>.*?)#").unwrap(),
-        )
-        .unwrap()
-    }
+/// Print the operations instead of running them
+#[derive(Debug, Args)]
+struct DryRun {
+    /// Only print the bind/attach/detach operations that would run, as `text`, `json` or `jsonl`.
+    /// The devices are discovered and matched as usual, but nothing is changed.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "text"
+    )]
+    dry_run: Option<OutputFormat>,
 }
 
-impl ListMountable {
-    /// Call `usbip` and get a list of all mountable USB devices
-    fn new(host: &str, port: u32) -> anyhow::Result<Self> {
-        let sh = Shell::new()?;
-        let port = port.to_string();
-        let stdout = cmd!(sh, "usbip --tcp-port={port} list --remote={host}").read();
-        match stdout {
-            Ok(s) => Ok(ListMountable(s)),
-            Err(s) => {
-                let err_s = s.to_string();
-                if err_s.contains("command not found") {
-                    Err(anyhow!(
-                        "Cannot find the command `usbip`.\n  \
-                         Please install the usbip package or add it to your PATH"
-                    ))
-                } else {
-                    Err(anyhow!(
-                        "Is the usbip daemon/server running and is it runnig via port {port}?"
-                    ))
-                }
+impl Commands {
+    /// Whether the local devices are selected or excluded by their class
+    fn selects_local_classes(&self) -> bool {
+        match self {
+            Commands::Host {
+                selectors, classes, ..
             }
+            | Commands::Unhost {
+                selectors, classes, ..
+            } => !classes.is_empty() || selectors.excludes_classes(),
+            _ => false,
         }
     }
+}
 
-    fn build_usbid_map(&self) -> HashMap<UsbId, HashSet<BusId>> {
-        build_usbid_map_helper(
-            &self.0,
-            &Regex::new(r"\s+(?P<busid>[0-9\-]+):.+?:.+\((?P<usbid>[0-9a-fA-F]+:[0-9a-fA-F]+)\)")
-                .unwrap(),
-        )
-        .unwrap()
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Similar to the output of `usbip`
+    Text,
+    /// A single JSON array of all devices
+    Json,
+    /// One JSON object per line and device
+    Jsonl,
 }
 
-impl ListUnmountable {
-    /// Call `usbip` and get a list of all mountable USB devices
-    fn new() -> anyhow::Result<Self> {
-        let sh = Shell::new()?;
-        let stdout = cmd!(sh, "usbip port").read();
-        match stdout {
-            Ok(s) => Ok(ListUnmountable(s)),
-            Err(s) => {
-                let err_s = s.to_string();
-                if err_s.contains("command not found") {
-                    Err(anyhow!(
-                        "Cannot find the command `usbip`.\n  \
-                         Please install the usbip package or add it to your PATH"
-                    ))
-                } else {
-                    Err(anyhow!(s))
-                }
-            }
+/// Show which concrete ids the `VVVV:*` patterns expanded to.
+/// Patterns that expanded to nothing are reported by [`Expectations::check`].
+/// Printed to stderr to keep the machine-readable output on stdout intact.
+fn print_expansions(patterns: &[UsbIdPattern], usb_ids: impl Iterator<Item = UsbId>) {
+    let usb_ids = usb_ids.collect::<Vec<_>>();
+    for (pattern, matched) in usbip_wrapper::expand_patterns(patterns, &usb_ids) {
+        if !pattern.is_wildcard() || matched.is_empty() {
+            continue;
         }
+        eprintln!(
+            "{pattern} expanded to {}",
+            matched
+                .iter()
+                .map(|u| u.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
     }
+}
 
-    // TODO: Think about how to refactor this code!
-    fn build_usbid_map(&self) -> HashMap<UsbId, HashSet<Port>> {
-        Regex::new(r"Port\s+(?P<port>\d+).*\n.*\((?P<usbid>[0-9a-fA-F]+:[0-9a-fA-F]+)\)")
-            .unwrap()
-            .captures_iter(&self.0)
-            .filter_map(|cap| match (cap.name("port"), cap.name("usbid")) {
-                // defining a pair guarantees that there is no difference due to ordering
-                (Some(port), Some(usbid)) => Some(UsbPortPair {
-                    port: Port(port.as_str().to_string()),
-                    usb_id: UsbId(usbid.as_str().to_string()),
-                }),
-                _ => None,
-            })
-            .fold(HashMap::new(), |mut acc, p| {
-                acc.entry(p.usb_id).or_insert(HashSet::new()).insert(p.port);
-                acc
-            })
+/// Warn about the ids that are not in the USB ID list as they are likely a typo.
+/// The list may be outdated, so the ids are still used.
+/// A trimmed list misses too many devices to tell, so there is no warning at all.
+fn warn_unknown_ids(selector: &Selector) {
+    if !usb_ids::is_complete() {
+        return;
+    }
+    let excluded = selector.excluded.iter().filter_map(|e| match e {
+        Exclusion::UsbId(pattern) => Some(pattern),
+        _ => None,
+    });
+    let patterns = selector.usb_ids.iter().chain(excluded);
+    for pattern in patterns.filter(|p| !usb_ids::is_known(p)) {
+        eprintln!("Warning: {pattern} is not in the USB ID list, is it a typo?");
     }
 }
 
-/// Quickly check the `usbip` version and provide additional information
-/// if the executable cannot be found.
-fn check_usbip_version(sh: &Shell) -> anyhow::Result<()> {
-    let version = cmd!(sh, "usbip version").read().with_context(|| {
-        "Could not determine installed usbip version. Is usbip installed/added to PATH?"
-    })?;
-    debug!("usbip version is: {version}");
-    Ok(())
+/// Print the records in a machine-readable format, returns `false` for `OutputFormat::Text`
+fn print_records(format: OutputFormat, records: &[impl Serialize]) -> anyhow::Result<bool> {
+    match format {
+        OutputFormat::Text => return Ok(false),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(records)?),
+        OutputFormat::Jsonl => {
+            for r in records {
+                println!("{}", serde_json::to_string(r)?);
+            }
+        }
+    }
+    Ok(true)
 }
 
-/// Bind/Unbind all UsbIds from the given HashSet over the given TCP port
-/// TODO: Does it actually require the correct TCP port?
-// fn bind_usb_ids(bind_type: BindType, usb_ids: &HashSet<UsbId>, port: u32) -> anyhow::Result<()> {
-//     let hs = ListHostableParsable::new()?.build_usbid_map();
-//     let matched_busids = collect_matching(&hs, &usb_ids);
-//     debug!("Matched Busids: {matched_busids:?}");
-//     if matched_busids.len() == 0 {
-//         warn!("Found no matching USB IDs!");
-//         println!("Found no matching USB IDs!");
-//         return Ok(());
-//     }
-//     for b in matched_busids {
-//         debug!("{bind_type}ing {b}");
-//         bind_type.execute(b, port)?;
-//     }
-//     Ok(())
-// }
+/// Print the planned operations, fails like the command if no device matches
+fn print_plan(format: OutputFormat, plan: &[PlannedOperation]) -> Result<(), Error> {
+    if !print_records(format, plan)? {
+        for operation in plan {
+            println!("{operation}");
+        }
+    }
+    match plan.is_empty() {
+        true => Err(Error::NoMatchingDevice),
+        false => Ok(()),
+    }
+}
 
 // Some weird notes for readme:
 // You can bind before you start the daemon/server and it will work!
-fn main() -> anyhow::Result<()> {
+/// Exits with the code of the error, see `usbip_wrapper::error` for the list of codes
+fn main() -> ExitCode {
     env_logger::init();
     let cli = Cli::parse();
-    let sh = Shell::new()?;
+    if let Commands::UnmountRemote { selectors, .. } = &cli.command {
+        if selectors.excludes_classes() {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "the classes of mounted devices are not known, \
+                     `--exclude` on `unmount-remote` only accepts USB IDs and busids",
+                )
+                .exit();
+        }
+    }
+    if let BackendKind::Shell = cli.backend {
+        if cli.command.selects_local_classes() {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "`usbip` does not list the classes of local devices, \
+                     use `--backend native` to select or exclude them by class",
+                )
+                .exit();
+        }
+    }
 
-    let command = cli.command;
-    check_usbip_version(&sh)?;
+    let sysfs = Sysfs::new(cli.sysfs_root);
+    let result = match cli.backend {
+        BackendKind::Native => run(NativeBackend::new(sysfs.clone()), sysfs, cli.command),
+        BackendKind::Shell => {
+            ShellBackend::new().and_then(|backend| run(backend, sysfs, cli.command))
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            let code = e.exit_code();
+            // same format as returning the error from `main`
+            eprintln!("Error: {:?}", anyhow::Error::from(e));
+            ExitCode::from(code)
+        }
+    }
+}
 
+fn run(backend: impl Backend, sysfs: Sysfs, command: Commands) -> Result<(), Error> {
     match command {
-        Commands::Host { usb_ids, tcp_port } => {
-            // TODO: Implement FromString for this type
-            let usb_ids_set = usb_ids
-                .into_iter()
-                .map(|u| UsbId(u))
-                .collect::<HashSet<UsbId>>();
-            let hs = ListHostableParsable::new()?.build_usbid_map();
-            let matched_busids = collect_matching(&hs, &usb_ids_set);
-            debug!("Matched Busids: {matched_busids:?}");
-            if matched_busids.len() == 0 {
-                return Err(anyhow!("Found no matching USB IDs!"));
-            }
-            for b in matched_busids {
-                debug!("hosting {b}");
-                BindType::Bind.execute(b, tcp_port)?;
+        Commands::Host {
+            usb_ids,
+            selectors,
+            classes,
+            expectations,
+            dry_run,
+            ..
+        } => {
+            let selector = expectations.extend(classes.narrow(selectors.selector(usb_ids)));
+            warn_unknown_ids(&selector);
+            let host = Host::new(backend);
+            let selected = host.select(&selector)?;
+            expectations
+                .check(selector.unmet_expectations(selected.iter().map(|(b, u)| (b, *u))))?;
+            if let Some(format) = dry_run.dry_run {
+                return print_plan(format, &host.plan_host(&selector)?);
             }
+            let bound = host.host_selected(&selector)?;
+            print_expansions(&selector.usb_ids, bound.iter().map(|b| b.usb_id));
             Ok(())
         }
-        Commands::Unhost { usb_ids, tcp_port } => {
-            // TODO: Implement FromString for this type
-            // bind_usb_ids(BindType::Unbind, &usb_ids_set, tcp_port)
-            let usbid_map = ListHostableParsable::new()?.build_usbid_map();
-            let matched_busids = match usb_ids.len() {
-                // TODO: Figure out how to auto-unhost all available usb sticks!
-                // => Just brute-force through all possible values!
-                0 => all_values(&usbid_map),
-                _ => {
-                    let usb_ids_set = usb_ids
-                        .into_iter()
-                        .map(|u| UsbId(u))
-                        .collect::<HashSet<UsbId>>();
-                    collect_matching(&usbid_map, &usb_ids_set)
-                }
-            };
-            debug!("Matched Busids: {matched_busids:?}");
-            if matched_busids.len() == 0 {
-                return Err(anyhow!("Found no matching USB IDs!"));
-            }
-            for b in matched_busids {
-                debug!("unbinding {b}");
-                BindType::Unbind.execute(b, tcp_port)?;
+        Commands::Unhost {
+            usb_ids,
+            selectors,
+            classes,
+            expectations,
+            all,
+            dry_run,
+            ..
+        } => {
+            let selector = expectations.extend(classes.narrow(selectors.selector(usb_ids)));
+            warn_unknown_ids(&selector);
+            let host = Host::new(backend);
+            all.require(&selector, "unhost");
+            let selected = host.select(&selector)?;
+            expectations
+                .check(selector.unmet_expectations(selected.iter().map(|(b, u)| (b, *u))))?;
+            if let Some(format) = dry_run.dry_run {
+                return print_plan(format, &host.plan_unhost(&selector)?);
             }
+            all.confirm(&selector, "unhost", || {
+                Ok(selected
+                    .iter()
+                    .map(|(busid, usb_id)| format!("{busid}: {usb_id}"))
+                    .collect())
+            })?;
+            let unbound = host.unhost_selected(&selector)?;
+            print_expansions(&selector.usb_ids, unbound.iter().map(|b| b.usb_id));
             Ok(())
         }
-        Commands::ListMountable { tcp_port, host } => {
-            let list_output = ListMountable::new(&host, tcp_port)?;
-            if list_output.build_usbid_map().len() == 0 {
+        Commands::ListMountable {
+            tcp_port,
+            host,
+            output,
+        } => {
+            let list_output = Client::new(backend).list_mountable(&host, tcp_port)?;
+            if print_records(output, &list_output.records())? {
+                return Ok(());
+            }
+            if list_output.devices.is_empty() {
                 println!("No mountable devices found. Use the `host` sub-command on the USB host to add USB devices.")
             } else {
-                println!("{}", list_output.0);
+                print!("{list_output}");
             }
             Ok(())
         }
-        Commands::ListHostable {} => {
-            let list_output = ListHostable::new()?;
-            println!("{}", list_output.0);
+        Commands::ListHostable { output } => {
+            let list_output = Host::new(backend).list()?;
+            if !print_records(output, &list_output.records())? {
+                print!("{list_output}");
+            }
+            Ok(())
+        }
+        Commands::ListUnmountable { output } => {
+            let list_output = Client::new(backend).list_unmountable()?;
+            if !print_records(output, &list_output.records())? {
+                print!("{list_output}");
+            }
             Ok(())
         }
         Commands::MountRemote {
             tcp_port,
             host,
             usb_ids,
+            selectors,
+            classes,
+            expectations,
+            all,
+            dry_run,
+            wait,
         } => {
-            let usbid_map = ListMountable::new(&host, tcp_port)?.build_usbid_map();
-            let matched_busids = match usb_ids.len() {
-                0 => all_values(&usbid_map),
-                _ => {
-                    let usb_ids_set = usb_ids
-                        .into_iter()
-                        .map(|u| UsbId(u))
-                        .collect::<HashSet<UsbId>>();
-                    collect_matching(&usbid_map, &usb_ids_set)
+            let selector = expectations.extend(classes.narrow(selectors.selector(usb_ids)));
+            warn_unknown_ids(&selector);
+            let client = Client::new(backend);
+            all.require(&selector, "mount");
+            let describe = |selected: Vec<(BusId, UsbId)>| {
+                selected
+                    .into_iter()
+                    .map(|(busid, usb_id)| format!("{host}/{busid}: {usb_id}"))
+                    .collect()
+            };
+            let mounted = match wait {
+                // the devices may not be there yet, `mount_waiting` selects them on every attempt
+                Some(timeout) => {
+                    all.confirm(&selector, "mount", || {
+                        Ok(describe(
+                            client.select_mountable(&host, tcp_port, &selector)?,
+                        ))
+                    })?;
+                    client.mount_waiting(&host, tcp_port, &selector, timeout)?
+                }
+                // the devices are only selected once, so that the checked ones are mounted
+                None => {
+                    let selected = client.select_mountable(&host, tcp_port, &selector)?;
+                    let unmet = unmet_before_mount(&selector, &selected);
+                    if let Some(format) = dry_run.dry_run {
+                        expectations.check(unmet)?;
+                        return print_plan(format, &client.plan_mount(&host, tcp_port, &selector)?);
+                    }
+                    expectations.require(unmet)?;
+                    all.confirm(&selector, "mount", || Ok(describe(selected.clone())))?;
+                    client.mount_matched(&host, tcp_port, &selector, &selected)?
                 }
             };
-            debug!("Matched Busids: {matched_busids:?}");
-            if matched_busids.len() == 0 {
-                return Err(anyhow!("Found no matching USB IDs!"));
-            }
-            let port = tcp_port.to_string();
-            let host_s = host.to_string();
-
-            // TODO: Potentially export as separat functionality
-            for b in matched_busids {
-                let b_s = b.to_string();
-                // What happens if the call is execute multiple times?
-                // Since every call has a unique busid it won't be called multiple times
-                // each follow-up call will again check for matching ids and won't find anything
-                let stderr = cmd!(
-                    sh,
-                    "usbip --tcp-port {port} attach --busid={b_s} --remote={host_s}"
-                )
-                .ignore_status()
-                .read_stderr()
-                .expect("Error while reading from stderr");
-                if stderr.contains("open vhci_driver") {
-                    error!("Missing vhci-hcd driver module");
-                    Err(anyhow!("Please enable the `vhci-hcd` kernel module."))?
+            print_expansions(&selector.usb_ids, mounted.iter().map(|m| m.usb_id));
+            for m in &mounted {
+                if let MountOutcome::Attached(port) = &m.outcome {
+                    debug!("{} is available at port {port}", m.busid);
                 }
             }
-
-            Ok(())
+            // the serial numbers are only known once the devices are mounted
+            expectations
+                .check(selector.unmet_expectations(mounted.iter().map(|m| (&m.busid, m.usb_id))))
         }
         Commands::StartUsbHoster {
+            builtin,
             debug,
             pid,
             tcp_port,
+            max_lifetime,
+            idle_timeout,
         } => {
-            let tcp_port_s = tcp_port.to_string();
-            let version = cmd!(
-                sh,
-                "usbipd --version"
-            )
-            .read()
-            .with_context(|| {
-                "Could not determine installed usbipd version. Is the daemon usbipd installed/added to PATH?"
-            })?;
-            let debug_option = match debug {
-                true => ["--debug"],
-                false => [""],
-            };
-            debug!("usbipd version is: {version}");
-            cmd!(
-                sh,
-                "usbipd --tcp-port {tcp_port_s} --pid {pid} {debug_option...}"
-            )
-            .run()
-            .with_context(|| "Could not successfully start usbipd")?;
+            let activated = systemd::listen_fds()?;
+            let hoster = Hoster::new(
+                Server::new(sysfs),
+                systemd::Notifier::from_env(),
+                Lifetime {
+                    max_lifetime,
+                    idle_timeout,
+                },
+                hoster::shutdown_flag()?,
+            );
+            // `usbipd` cannot take over the sockets, so socket activation
+            // always uses the built-in server
+            if builtin || !activated.is_empty() {
+                let listeners = if activated.is_empty() {
+                    vec![server::listen(tcp_port)?]
+                } else {
+                    debug!("Using {} socket(s) passed by systemd", activated.len());
+                    activated
+                };
+                for listener in &listeners {
+                    // Same message as `usbipd` to keep scripts that wait for it working
+                    let addr = listener
+                        .local_addr()
+                        .context("Could not determine the listening address")?;
+                    println!("listening on {addr}");
+                }
+                hoster.run_builtin(&listeners)?;
+            } else {
+                let sh = Shell::new().map_err(anyhow::Error::from)?;
+                let tcp_port_s = tcp_port.to_string();
+                let version = cmd!(sh, "usbipd --version").read().map_err(|e| {
+                    debug!("Could not determine installed usbipd version: {e}");
+                    Error::UsbipMissing { program: "usbipd" }
+                })?;
+                let debug_option: &[&str] = match debug {
+                    true => &["--debug"],
+                    false => &[],
+                };
+                debug!("usbipd version is: {version}");
+                let usbipd = cmd!(
+                    sh,
+                    "usbipd --tcp-port {tcp_port_s} --pid {pid} {debug_option...}"
+                );
+                hoster.run_usbipd(usbipd.into(), tcp_port)?;
+            }
             println!("Shutting down");
             Ok(())
         }
-        Commands::UnmountRemote { usb_ids } => {
-            let usbid_map = ListUnmountable::new()?.build_usbid_map();
-            let matched_ports = match usb_ids.len() {
-                0 => all_values(&usbid_map),
-                _ => {
-                    let usb_ids_set = usb_ids
-                        .into_iter()
-                        .map(|u| UsbId(u))
-                        .collect::<HashSet<UsbId>>();
-                    collect_matching(&usbid_map, &usb_ids_set)
-                }
-            };
-            debug!("Matched Ports: {matched_ports:?}");
-            if matched_ports.len() == 0 {
-                return Err(anyhow!("Found no matching ports!"));
+        Commands::UnmountRemote {
+            output,
+            usb_ids,
+            selectors,
+            all,
+            dry_run,
+        } => {
+            let selector = selectors.selector(usb_ids);
+            warn_unknown_ids(&selector);
+            let client = Client::new(backend);
+            if let Some(format) = dry_run.dry_run {
+                all.require(&selector, "unmount");
+                return print_plan(format, &client.plan_unmount(&selector)?);
             }
-            // TODO: Potentially export as separat functionality
-            for p in matched_ports {
-                let p_s = p.to_string();
-                // What happens if the call is execute multiple times?
-                // Since every call has a unique busid it won't be called multiple times
-                // each follow-up call will again check for matching ids and won't find anything
-                let stderr = cmd!(sh, "usbip detach --port={p_s}")
-                    .ignore_status()
-                    .read_stderr()
-                    .expect("Error while reading from stderr");
-                println!("{stderr}");
+            all.confirm(&selector, "unmount", || {
+                Ok(client
+                    .select_unmountable(&selector)?
+                    .into_iter()
+                    .map(|(port, usb_id)| format!("port {port}: {usb_id}"))
+                    .collect())
+            })?;
+            let unmounted = client.unmount_selected(&selector)?;
+            print_expansions(&selector.usb_ids, unmounted.iter().map(|u| u.usb_id));
+            let records = unmounted.iter().map(|u| u.record()).collect::<Vec<_>>();
+            if !print_records(output, &records)? {
+                for u in unmounted {
+                    println!("{} at port {} is now detached", u.usb_id, u.port);
+                }
             }
             Ok(())
         }
     }
 }
-
-#[cfg(test)]
-mod tests {
-    use rstest::*;
-
-    use super::*;
-
-    #[fixture]
-    pub fn parsable_list_output() -> String {
-        "busid=1-11#usbid=058f:9540#
-        busid=1-1#usbid=04f2:b67c#
-        busid=1-14#usbid=8087:0029#
-        busid=1-8#usbid=04f2:b67c#
-        busid=1-9#usbid=06cb:00bd#"
-            .to_string()
-    }
-
-    // #[rstest]
-    // #[case("8087:0029", vec!["1-14".to_string()])]
-    // #[case("8087:002", Vec::new())]
-    // #[case("04f2:b67c", vec!["1-1".to_string(), "1-8".to_string()])]
-    // fn test_find_matching_busid_given_usbid(
-    //     parsable_list_output: String,
-    //     #[case] usbid: String,
-    //     #[case] expected: Vec<String>,
-    // ) {
-    //     UsbIpOutput::ListLocalParsable::assert_eq!(
-    //         expected,
-    //         matching_busids(&parsable_list_output, &vec![usbid]) // test_match(&parsable_list_output, &usbid)
-    //     )
-    // }
-
-    // TODO: make these actual tests!
-
-    #[test]
-    fn test_find_matching_pairs() {
-        let s = "
-         1-11: Alcor Micro Corp. : AU9540 Smartcard Reader (058f:9540)
-         1-12: Alcor Micro Corp. with : colon in name : AU9540 Smartcard Reader (058f:9540)
-         12-1: Alcor Micro Corp. : AU9540 Smartcard Reader (0000:0000) with trick name (058f:9540)
-        "
-        .to_string();
-        // let pairs = [
-        //     ("1-11", "058f:9540"),
-        //     ("1-12", "058f:9540"),
-        //     ("12-1", "058f:9540"),
-        // ]
-        // .into_iter()
-        // .map(|(bus_id, usb_id)| IdPair {
-        //     bus_id: BusId(bus_id.to_string()),
-        //     usb_id: UsbId(usb_id.to_string()),
-        // })
-        // .collect::<Vec<IdPair>>();
-
-        println!("{:?}", ListMountable(s).build_usbid_map())
-    }
-
-    #[test]
-    fn test_port() {
-        let s = "
-            Port 00: <Port in Use> at Full Speed(12Mbps)
-               Yubico.com : Yubikey 4/5 OTP+U2F+CCID (1050:0407)
-               3-1 -> unknown host, remote port and remote busid
-                   -> remote bus/dev 001/011
-
-            Port 01: <Port in Use> at Full Speed(12Mbps)
-               Yubico.com : Yubikey 4/5 OTP+U2F+CCID (1050:0407)
-               3-1 -> usbip://nixos-laptop:5000/1-7
-                   -> remote bus/dev 001/011
-        "
-        .to_string();
-        println!("{:?}", ListUnmountable(s).build_usbid_map())
-    }
-    // TODO: Add these as they are valid busids when connected via usb-multi
-    //    - busid 1-4.3.4 (0bda:402e)
-    //   Realtek Semiconductor Corp. : unknown product (0bda:402e)
-
-    // - busid 1-4.3.5 (413c:b06f)
-    //   Dell Computer Corp. : unknown product (413c:b06f)
-
-    // - busid 1-4.5 (413c:b06e
-}
@@ -0,0 +1,917 @@
+//! Minimal implementation of the USB/IP wire protocol.
+//!
+//! Only the _operation_ messages that are exchanged before a device is
+//! imported are implemented. Everything after the import (the URB traffic)
+//! is handled by the kernel modules, except for reading the serial number
+//! of an imported device before the stream is handed to the kernel.
+//! See <https://docs.kernel.org/usb/usbip_protocol.html> for the specification.
+//! All values are transmitted in network byte order (big endian).
+use core::fmt;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+
+use crate::{BusId, Error, UsbId};
+
+/// Protocol version that is sent and expected by the userspace tools.
+pub const USBIP_VERSION: u16 = 0x0111;
+
+pub const OP_REQ_DEVLIST: u16 = 0x8005;
+pub const OP_REP_DEVLIST: u16 = 0x0005;
+pub const OP_REQ_IMPORT: u16 = 0x8003;
+pub const OP_REP_IMPORT: u16 = 0x0003;
+
+/// Status codes of an operation reply, see `ST_*` of the usbip userspace tools
+pub const ST_OK: u32 = 0;
+/// Device is not available, i.e., not exported
+pub const ST_NA: u32 = 1;
+/// Device is already imported by a different client
+pub const ST_DEV_BUSY: u32 = 2;
+/// Device is in an error state
+pub const ST_DEV_ERR: u32 = 3;
+/// Device with the requested busid does not exist
+pub const ST_NODEV: u32 = 4;
+/// Any other error
+pub const ST_ERROR: u32 = 5;
+
+pub const USBIP_CMD_SUBMIT: u32 = 0x0001;
+pub const USBIP_RET_SUBMIT: u32 = 0x0003;
+const USBIP_DIR_IN: u32 = 1;
+
+/// `bmRequestType` of a standard device-to-host request to the device
+const USB_DIR_IN_STANDARD_DEVICE: u8 = 0x80;
+const USB_REQ_GET_DESCRIPTOR: u8 = 0x06;
+const USB_DT_DEVICE: u8 = 0x01;
+const USB_DT_STRING: u8 = 0x03;
+/// Length of the device descriptor
+const USB_DT_DEVICE_SIZE: u16 = 18;
+/// Offset of `iSerialNumber` in the device descriptor
+const SERIAL_INDEX_OFFSET: usize = 16;
+
+/// Size of the fixed-size `path` field of `usbip_usb_device`
+const PATH_SIZE: usize = 256;
+/// Size of the fixed-size `busid` field of `usbip_usb_device`
+const BUSID_SIZE: usize = 32;
+
+/// How long to wait for the remote before giving up.
+/// The usbip daemon answers immediately, so a hanging connection
+/// is a strong indicator that something else is listening on the port.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Common header of every operation message
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct OpHeader {
+    pub version: u16,
+    pub code: u16,
+    pub status: u32,
+}
+
+impl OpHeader {
+    pub fn request(code: u16) -> Self {
+        OpHeader {
+            version: USBIP_VERSION,
+            code,
+            status: 0,
+        }
+    }
+
+    fn read_from(r: &mut impl Read) -> std::io::Result<Self> {
+        Ok(OpHeader {
+            version: read_u16(r)?,
+            code: read_u16(r)?,
+            status: read_u32(r)?,
+        })
+    }
+
+    fn write_to(&self, w: &mut impl Write) -> std::io::Result<()> {
+        w.write_all(&self.version.to_be_bytes())?;
+        w.write_all(&self.code.to_be_bytes())?;
+        w.write_all(&self.status.to_be_bytes())
+    }
+
+    /// Verify that the header is a successful reply of the expected kind
+    fn expect_reply(&self, code: u16) -> anyhow::Result<()> {
+        if self.code != code {
+            return Err(anyhow!(
+                "Unexpected reply from usbip host: expected code {code:#06x} but got {:#06x}",
+                self.code
+            ));
+        }
+        if self.status != ST_OK {
+            return Err(anyhow!(
+                "The usbip host refused the request with status {}",
+                self.status
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Speed of a USB device as defined by the kernel's `enum usb_device_speed`
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub enum UsbSpeed {
+    #[default]
+    Unknown,
+    Low,
+    Full,
+    High,
+    Wireless,
+    Super,
+    SuperPlus,
+}
+
+impl From<u32> for UsbSpeed {
+    fn from(v: u32) -> Self {
+        match v {
+            1 => UsbSpeed::Low,
+            2 => UsbSpeed::Full,
+            3 => UsbSpeed::High,
+            4 => UsbSpeed::Wireless,
+            5 => UsbSpeed::Super,
+            6 => UsbSpeed::SuperPlus,
+            _ => UsbSpeed::Unknown,
+        }
+    }
+}
+
+impl From<UsbSpeed> for u32 {
+    fn from(s: UsbSpeed) -> Self {
+        match s {
+            UsbSpeed::Unknown => 0,
+            UsbSpeed::Low => 1,
+            UsbSpeed::Full => 2,
+            UsbSpeed::High => 3,
+            UsbSpeed::Wireless => 4,
+            UsbSpeed::Super => 5,
+            UsbSpeed::SuperPlus => 6,
+        }
+    }
+}
+
+impl fmt::Display for UsbSpeed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UsbSpeed::Unknown => write!(f, "Unknown Speed"),
+            UsbSpeed::Low => write!(f, "Low Speed(1.5Mbps)"),
+            UsbSpeed::Full => write!(f, "Full Speed(12Mbps)"),
+            UsbSpeed::High => write!(f, "High Speed(480Mbps)"),
+            UsbSpeed::Wireless => write!(f, "Wireless"),
+            UsbSpeed::Super => write!(f, "Super Speed(5000Mbps)"),
+            UsbSpeed::SuperPlus => write!(f, "Super Speed Plus(10000Mbps)"),
+        }
+    }
+}
+
+/// Class triple of a single interface of an exported device
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct UsbInterface {
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+/// A USB device as it is described by the usbip host,
+/// corresponds to `struct usbip_usb_device`
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+pub struct UsbDevice {
+    /// sysfs path of the device on the host
+    pub path: String,
+    pub busid: BusId,
+    pub busnum: u32,
+    pub devnum: u32,
+    pub speed: UsbSpeed,
+    pub id_vendor: u16,
+    pub id_product: u16,
+    pub bcd_device: u16,
+    pub device_class: u8,
+    pub device_subclass: u8,
+    pub device_protocol: u8,
+    pub configuration_value: u8,
+    pub num_configurations: u8,
+    /// Only filled if the message contains the interface list,
+    /// which is the case for `OP_REP_DEVLIST`
+    pub interfaces: Vec<UsbInterface>,
+}
+
+impl UsbDevice {
+    pub fn usb_id(&self) -> UsbId {
+        UsbId::new(self.id_vendor, self.id_product)
+    }
+
+    /// Read the fixed-size part of the device description.
+    /// Returns the device and the number of interfaces that may follow.
+    fn read_from(r: &mut impl Read) -> anyhow::Result<(Self, u8)> {
+        let path = read_c_string::<PATH_SIZE>(r)?;
+        let busid = BusId(read_c_string::<BUSID_SIZE>(r)?);
+        let busnum = read_u32(r)?;
+        let devnum = read_u32(r)?;
+        let speed = UsbSpeed::from(read_u32(r)?);
+        let id_vendor = read_u16(r)?;
+        let id_product = read_u16(r)?;
+        let bcd_device = read_u16(r)?;
+        let [device_class, device_subclass, device_protocol, configuration_value, num_configurations, num_interfaces] =
+            read_array::<6>(r)?;
+        Ok((
+            UsbDevice {
+                path,
+                busid,
+                busnum,
+                devnum,
+                speed,
+                id_vendor,
+                id_product,
+                bcd_device,
+                device_class,
+                device_subclass,
+                device_protocol,
+                configuration_value,
+                num_configurations,
+                interfaces: Vec::new(),
+            },
+            num_interfaces,
+        ))
+    }
+
+    /// Write the fixed-size part of the device description
+    fn write_to(&self, w: &mut impl Write) -> anyhow::Result<()> {
+        write_c_string::<PATH_SIZE>(w, &self.path)?;
+        write_c_string::<BUSID_SIZE>(w, &self.busid.0)?;
+        w.write_all(&self.busnum.to_be_bytes())?;
+        w.write_all(&self.devnum.to_be_bytes())?;
+        w.write_all(&u32::from(self.speed).to_be_bytes())?;
+        w.write_all(&self.id_vendor.to_be_bytes())?;
+        w.write_all(&self.id_product.to_be_bytes())?;
+        w.write_all(&self.bcd_device.to_be_bytes())?;
+        w.write_all(&[
+            self.device_class,
+            self.device_subclass,
+            self.device_protocol,
+            self.configuration_value,
+            self.num_configurations,
+            u8::try_from(self.interfaces.len())?,
+        ])?;
+        Ok(())
+    }
+
+    /// Write the device description followed by its interface list
+    fn write_with_interfaces(&self, w: &mut impl Write) -> anyhow::Result<()> {
+        self.write_to(w)?;
+        for intf in &self.interfaces {
+            w.write_all(&[intf.class, intf.subclass, intf.protocol, 0])?;
+        }
+        Ok(())
+    }
+
+    /// Read a device description that is followed by its interface list
+    fn read_with_interfaces(r: &mut impl Read) -> anyhow::Result<Self> {
+        let (mut dev, num_interfaces) = UsbDevice::read_from(r)?;
+        for _ in 0..num_interfaces {
+            let [class, subclass, protocol, _padding] = read_array::<4>(r)?;
+            dev.interfaces.push(UsbInterface {
+                class,
+                subclass,
+                protocol,
+            });
+        }
+        Ok(dev)
+    }
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> std::io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u16(r: &mut impl Read) -> std::io::Result<u16> {
+    Ok(u16::from_be_bytes(read_array(r)?))
+}
+
+fn read_u32(r: &mut impl Read) -> std::io::Result<u32> {
+    Ok(u32::from_be_bytes(read_array(r)?))
+}
+
+/// Write a string into a fixed-size, NUL-padded field
+fn write_c_string<const N: usize>(w: &mut impl Write, s: &str) -> anyhow::Result<()> {
+    if s.len() >= N {
+        return Err(anyhow!("`{s}` does not fit into {N} bytes"));
+    }
+    let mut buf = [0u8; N];
+    buf[..s.len()].copy_from_slice(s.as_bytes());
+    w.write_all(&buf)?;
+    Ok(())
+}
+
+/// Read a fixed-size, NUL-padded string field
+fn read_c_string<const N: usize>(r: &mut impl Read) -> anyhow::Result<String> {
+    let buf = read_array::<N>(r)?;
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(N);
+    String::from_utf8(buf[..end].to_vec()).with_context(|| "usbip host sent a non-UTF-8 string")
+}
+
+/// Open a TCP connection to the usbip host
+pub fn connect(host: &str, port: u16) -> anyhow::Result<TcpStream> {
+    let stream = TcpStream::connect((host, port)).map_err(|source| Error::HostUnreachable {
+        host: host.to_string(),
+        tcp_port: port,
+        source,
+    })?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    Ok(stream)
+}
+
+/// Request the list of exported devices over an already connected stream.
+/// The host closes the connection after answering the request.
+pub fn request_devlist<S: Read + Write>(stream: &mut S) -> anyhow::Result<Vec<UsbDevice>> {
+    OpHeader::request(OP_REQ_DEVLIST).write_to(stream)?;
+    stream.flush()?;
+    let header = OpHeader::read_from(stream)
+        .with_context(|| "Could not read the device list reply from the usbip host")?;
+    header.expect_reply(OP_REP_DEVLIST)?;
+    let num_devices = read_u32(stream)?;
+    (0..num_devices)
+        .map(|_| UsbDevice::read_with_interfaces(stream))
+        .collect()
+}
+
+/// Answer of the usbip host to an import request
+#[derive(Debug, Eq, PartialEq)]
+pub enum ImportReply {
+    /// The device is exported and the stream can be handed to the kernel
+    Accepted(UsbDevice),
+    /// The host refused to export the device with the given `ST_*` status
+    Refused(u32),
+}
+
+/// Request to import the device with the given busid over an already connected stream.
+/// If the request is accepted, the stream must be kept open as it
+/// is used for the URB traffic afterwards.
+pub fn request_import<S: Read + Write>(
+    stream: &mut S,
+    busid: &BusId,
+) -> anyhow::Result<ImportReply> {
+    OpHeader::request(OP_REQ_IMPORT).write_to(stream)?;
+    write_c_string::<BUSID_SIZE>(stream, &busid.0)?;
+    stream.flush()?;
+    let header = OpHeader::read_from(stream)
+        .with_context(|| "Could not read the import reply from the usbip host")?;
+    if header.code == OP_REP_IMPORT && header.status != ST_OK {
+        return Ok(ImportReply::Refused(header.status));
+    }
+    header.expect_reply(OP_REP_IMPORT)?;
+    let (device, _num_interfaces) = UsbDevice::read_from(stream)?;
+    if &device.busid != busid {
+        return Err(anyhow!(
+            "The usbip host sent {} instead of the requested device {busid}",
+            device.busid
+        ));
+    }
+    Ok(ImportReply::Accepted(device))
+}
+
+/// Read a descriptor of the imported device with a control transfer
+/// and return the transferred bytes.
+fn get_descriptor<S: Read + Write>(
+    stream: &mut S,
+    devid: u32,
+    seqnum: u32,
+    kind: u8,
+    index: u8,
+    language: u16,
+    length: u16,
+) -> anyhow::Result<Vec<u8>> {
+    // `usbip_header_basic` and `usbip_header_cmd_submit`
+    let header = [
+        USBIP_CMD_SUBMIT,
+        seqnum,
+        devid,
+        USBIP_DIR_IN,
+        0, // endpoint
+        0, // transfer_flags
+        u32::from(length),
+        0, // start_frame
+        0, // number_of_packets
+        0, // interval
+    ];
+    for value in header {
+        stream.write_all(&value.to_be_bytes())?;
+    }
+    // the setup packet is sent as is, i.e., in little endian
+    stream.write_all(&[
+        USB_DIR_IN_STANDARD_DEVICE,
+        USB_REQ_GET_DESCRIPTOR,
+        index,
+        kind,
+    ])?;
+    stream.write_all(&language.to_le_bytes())?;
+    stream.write_all(&length.to_le_bytes())?;
+    stream.flush()?;
+
+    // `usbip_header_basic` and `usbip_header_ret_submit`
+    let command = read_u32(stream)?;
+    let reply_seqnum = read_u32(stream)?;
+    // devid, direction and endpoint
+    read_array::<12>(stream)?;
+    let status = read_u32(stream)? as i32;
+    let actual_length = read_u32(stream)?;
+    // start_frame, number_of_packets, error_count and the padding
+    read_array::<20>(stream)?;
+    if command != USBIP_RET_SUBMIT || reply_seqnum != seqnum {
+        return Err(anyhow!(
+            "Unexpected reply of the usbip host to a descriptor request"
+        ));
+    }
+    if status != 0 {
+        return Err(anyhow!(
+            "The device could not read the descriptor: error {status}"
+        ));
+    }
+    // the remaining bytes would be read as the next reply
+    if actual_length > u32::from(length) {
+        return Err(anyhow!(
+            "The usbip host sent {actual_length} bytes for a descriptor of at most {length} bytes"
+        ));
+    }
+    let mut data = vec![0u8; actual_length as usize];
+    stream.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Read the serial number of an imported device from its string descriptor.
+/// Has to be called before the stream is handed to the kernel.
+/// Returns `None` if the device has no serial number.
+pub fn request_serial<S: Read + Write>(
+    stream: &mut S,
+    device: &UsbDevice,
+) -> anyhow::Result<Option<String>> {
+    let devid = device.busnum << 16 | device.devnum;
+    let descriptor = get_descriptor(stream, devid, 1, USB_DT_DEVICE, 0, 0, USB_DT_DEVICE_SIZE)?;
+    let index = match descriptor.get(SERIAL_INDEX_OFFSET) {
+        Some(0) => return Ok(None),
+        Some(index) => *index,
+        None => {
+            return Err(anyhow!(
+                "The device descriptor of {} is too short",
+                device.busid
+            ))
+        }
+    };
+    // string descriptor 0 lists the supported languages, use the first one like the kernel
+    let languages = get_descriptor(stream, devid, 2, USB_DT_STRING, 0, 0, 255)?;
+    let language = match languages.get(2..4) {
+        Some(&[low, high]) => u16::from_le_bytes([low, high]),
+        _ => return Err(anyhow!("{} does not support any language", device.busid)),
+    };
+    let serial = get_descriptor(stream, devid, 3, USB_DT_STRING, index, language, 255)?;
+    // `bLength`, `bDescriptorType` and the UTF-16LE encoded string
+    let end = usize::from(*serial.first().unwrap_or(&0)).min(serial.len());
+    let utf16 = serial
+        .get(2..end)
+        .unwrap_or_default()
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect::<Vec<_>>();
+    Ok(Some(String::from_utf16_lossy(&utf16)))
+}
+
+/// Request of a client, as seen by the server
+#[derive(Debug, Eq, PartialEq)]
+pub enum Request {
+    Devlist,
+    Import(BusId),
+}
+
+/// Read the request of a client
+pub fn read_request(r: &mut impl Read) -> anyhow::Result<Request> {
+    let header = OpHeader::read_from(r).with_context(|| "Could not read the client request")?;
+    if header.version != USBIP_VERSION {
+        return Err(anyhow!(
+            "Client uses the unsupported protocol version {:#06x}",
+            header.version
+        ));
+    }
+    match header.code {
+        OP_REQ_DEVLIST => Ok(Request::Devlist),
+        OP_REQ_IMPORT => Ok(Request::Import(BusId(read_c_string::<BUSID_SIZE>(r)?))),
+        code => Err(anyhow!("Client sent the unsupported request {code:#06x}")),
+    }
+}
+
+/// Answer a device list request with the given devices
+pub fn write_devlist(w: &mut impl Write, devices: &[UsbDevice]) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    OpHeader {
+        version: USBIP_VERSION,
+        code: OP_REP_DEVLIST,
+        status: ST_OK,
+    }
+    .write_to(&mut buf)?;
+    buf.extend(u32::try_from(devices.len())?.to_be_bytes());
+    for d in devices {
+        d.write_with_interfaces(&mut buf)?;
+    }
+    w.write_all(&buf)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Answer an import request
+pub fn write_import_reply(w: &mut impl Write, reply: &ImportReply) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    let status = match reply {
+        ImportReply::Accepted(_) => ST_OK,
+        ImportReply::Refused(status) => *status,
+    };
+    OpHeader {
+        version: USBIP_VERSION,
+        code: OP_REP_IMPORT,
+        status,
+    }
+    .write_to(&mut buf)?;
+    if let ImportReply::Accepted(device) = reply {
+        device.write_to(&mut buf)?;
+    }
+    w.write_all(&buf)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Connect to the usbip host and return all devices it exports
+pub fn list_remote(host: &str, port: u16) -> anyhow::Result<Vec<UsbDevice>> {
+    let mut stream = connect(host, port)?;
+    request_devlist(&mut stream)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    fn c_string<const N: usize>(s: &str) -> [u8; N] {
+        let mut buf = [0u8; N];
+        buf[..s.len()].copy_from_slice(s.as_bytes());
+        buf
+    }
+
+    /// Encode a device entry of an `OP_REP_DEVLIST` reply by hand
+    pub(crate) fn devlist_entry(
+        busid: &str,
+        vendor: u16,
+        product: u16,
+        interfaces: &[[u8; 3]],
+    ) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(c_string::<PATH_SIZE>(&format!("/sys/devices/usb1/{busid}")));
+        out.extend(c_string::<BUSID_SIZE>(busid));
+        out.extend(1u32.to_be_bytes());
+        out.extend(7u32.to_be_bytes());
+        out.extend(2u32.to_be_bytes());
+        out.extend(vendor.to_be_bytes());
+        out.extend(product.to_be_bytes());
+        out.extend(0x0543u16.to_be_bytes());
+        out.extend([0, 0, 0, 1, 1, interfaces.len() as u8]);
+        for [class, subclass, protocol] in interfaces {
+            out.extend([*class, *subclass, *protocol, 0]);
+        }
+        out
+    }
+
+    /// Start a fake usbip host on loopback that answers a single request
+    /// of the given length with the given bytes and returns the request it received.
+    pub(crate) fn fake_host(
+        request_len: usize,
+        reply: Vec<u8>,
+    ) -> (u16, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![0u8; request_len];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&reply).unwrap();
+            request
+        });
+        (port, handle)
+    }
+
+    /// A fake usbip host that answers an import request with the given status
+    pub(crate) fn fake_import_host(busid: &str, status: u32) -> (u16, thread::JoinHandle<Vec<u8>>) {
+        let mut reply = reply_header(OP_REP_IMPORT, status);
+        if status == ST_OK {
+            reply.extend(devlist_entry(busid, 0x1050, 0x0407, &[]));
+        }
+        fake_host(8 + BUSID_SIZE, reply)
+    }
+
+    /// A fake usbip host that accepts the import and answers the descriptor
+    /// requests of [`request_serial`] for a device with the given serial number
+    pub(crate) fn fake_serial_host(
+        busid: &str,
+        serial: Option<&str>,
+    ) -> (u16, thread::JoinHandle<Vec<u8>>) {
+        let mut reply = reply_header(OP_REP_IMPORT, ST_OK);
+        reply.extend(devlist_entry(busid, 0x1050, 0x0407, &[]));
+        match serial {
+            Some(serial) => {
+                reply.extend(ret_submit(1, &device_descriptor(3)));
+                reply.extend(ret_submit(2, &[4, USB_DT_STRING, 0x09, 0x04]));
+                let mut descriptor = vec![2 + 2 * serial.len() as u8, USB_DT_STRING];
+                descriptor.extend(serial.encode_utf16().flat_map(u16::to_le_bytes));
+                reply.extend(ret_submit(3, &descriptor));
+            }
+            None => reply.extend(ret_submit(1, &device_descriptor(0))),
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![0u8; 8 + BUSID_SIZE];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&reply).unwrap();
+            // keep the connection open until the client is done
+            stream.read_to_end(&mut request).unwrap();
+            request
+        });
+        (port, handle)
+    }
+
+    pub(crate) fn reply_header(code: u16, status: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        OpHeader {
+            version: USBIP_VERSION,
+            code,
+            status,
+        }
+        .write_to(&mut out)
+        .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_list_remote() {
+        let mut reply = reply_header(OP_REP_DEVLIST, 0);
+        reply.extend(2u32.to_be_bytes());
+        reply.extend(devlist_entry("1-11", 0x058f, 0x9540, &[[0x0b, 0, 0]]));
+        reply.extend(devlist_entry(
+            "1-4.3.4",
+            0x1050,
+            0x0407,
+            &[[3, 1, 1], [3, 0, 0], [0x0b, 0, 0]],
+        ));
+        let (port, handle) = fake_host(8, reply);
+
+        let devices = list_remote("127.0.0.1", port).unwrap();
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![0x01, 0x11, 0x80, 0x05, 0, 0, 0, 0]
+        );
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].busid, BusId("1-11".to_string()));
+        assert_eq!(devices[0].usb_id(), UsbId::new(0x058f, 0x9540));
+        assert_eq!(devices[0].path, "/sys/devices/usb1/1-11");
+        assert_eq!(devices[0].speed, UsbSpeed::Full);
+        assert_eq!(devices[0].interfaces.len(), 1);
+        assert_eq!(devices[1].busid, BusId("1-4.3.4".to_string()));
+        assert_eq!(devices[1].usb_id(), UsbId::new(0x1050, 0x0407));
+        assert_eq!(devices[1].busnum, 1);
+        assert_eq!(devices[1].devnum, 7);
+        assert_eq!(
+            devices[1].interfaces[0],
+            UsbInterface {
+                class: 3,
+                subclass: 1,
+                protocol: 1
+            }
+        );
+        assert_eq!(devices[1].interfaces.len(), 3);
+    }
+
+    #[test]
+    fn test_list_remote_empty() {
+        let mut reply = reply_header(OP_REP_DEVLIST, 0);
+        reply.extend(0u32.to_be_bytes());
+        let (port, _handle) = fake_host(8, reply);
+        assert!(list_remote("127.0.0.1", port).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_list_remote_rejects_invalid_reply() {
+        let (port, _handle) = fake_host(8, reply_header(OP_REP_DEVLIST, 1));
+        assert!(list_remote("127.0.0.1", port).is_err());
+        let (port, _handle) = fake_host(8, reply_header(0x0003, 0));
+        assert!(list_remote("127.0.0.1", port).is_err());
+    }
+
+    #[test]
+    fn test_list_remote_truncated_reply() {
+        let mut reply = reply_header(OP_REP_DEVLIST, 0);
+        reply.extend(1u32.to_be_bytes());
+        reply.extend(&devlist_entry("1-1", 1, 1, &[])[..100]);
+        let (port, _handle) = fake_host(8, reply);
+        assert!(list_remote("127.0.0.1", port).is_err());
+    }
+
+    #[test]
+    fn test_request_import() {
+        let (port, handle) = fake_import_host("1-4.3.4", ST_OK);
+        let mut stream = connect("127.0.0.1", port).unwrap();
+        let reply = request_import(&mut stream, &BusId("1-4.3.4".to_string())).unwrap();
+        let request = handle.join().unwrap();
+        assert_eq!(request[..8], [0x01, 0x11, 0x80, 0x03, 0, 0, 0, 0]);
+        assert_eq!(request[8..], c_string::<BUSID_SIZE>("1-4.3.4"));
+        match reply {
+            ImportReply::Accepted(d) => {
+                assert_eq!(d.busid, BusId("1-4.3.4".to_string()));
+                assert_eq!(d.usb_id(), UsbId::new(0x1050, 0x0407));
+                assert!(d.interfaces.is_empty());
+            }
+            r => panic!("Unexpected reply {r:?}"),
+        }
+    }
+
+    #[test]
+    fn test_request_import_refused() {
+        let (port, _handle) = fake_import_host("1-1", ST_DEV_BUSY);
+        let mut stream = connect("127.0.0.1", port).unwrap();
+        assert_eq!(
+            request_import(&mut stream, &BusId("1-1".to_string())).unwrap(),
+            ImportReply::Refused(ST_DEV_BUSY)
+        );
+    }
+
+    #[test]
+    fn test_request_import_wrong_device() {
+        let (port, _handle) = fake_import_host("1-2", ST_OK);
+        let mut stream = connect("127.0.0.1", port).unwrap();
+        assert!(request_import(&mut stream, &BusId("1-1".to_string())).is_err());
+    }
+
+    /// Replays the given bytes and records what is written
+    struct Duplex {
+        replies: std::io::Cursor<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Encode the `USBIP_RET_SUBMIT` reply of a successful control transfer
+    fn ret_submit(seqnum: u32, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for value in [USBIP_RET_SUBMIT, seqnum, 0x0001_0007, 0, 0, 0] {
+            out.extend(value.to_be_bytes());
+        }
+        out.extend((data.len() as u32).to_be_bytes());
+        out.extend([0u8; 20]);
+        out.extend(data);
+        out
+    }
+
+    fn device_descriptor(serial_index: u8) -> Vec<u8> {
+        let mut descriptor = vec![18, USB_DT_DEVICE];
+        descriptor.extend([0; 14]);
+        descriptor.extend([serial_index, 1]);
+        descriptor
+    }
+
+    #[test]
+    fn test_request_serial() {
+        let mut replies = ret_submit(1, &device_descriptor(3));
+        replies.extend(ret_submit(2, &[4, USB_DT_STRING, 0x09, 0x04]));
+        let mut serial = vec![14, USB_DT_STRING];
+        serial.extend("CC1234".encode_utf16().flat_map(u16::to_le_bytes));
+        replies.extend(ret_submit(3, &serial));
+        let mut stream = Duplex {
+            replies: std::io::Cursor::new(replies),
+            written: Vec::new(),
+        };
+        let (device, _) =
+            UsbDevice::read_from(&mut &devlist_entry("1-1", 0x1050, 0x0407, &[])[..]).unwrap();
+        assert_eq!(
+            request_serial(&mut stream, &device).unwrap().as_deref(),
+            Some("CC1234")
+        );
+        // three `USBIP_CMD_SUBMIT` requests
+        assert_eq!(stream.written.len(), 3 * 48);
+        let serial_request = &stream.written[96..];
+        assert_eq!(serial_request[..4], USBIP_CMD_SUBMIT.to_be_bytes());
+        assert_eq!(serial_request[8..12], 0x0001_0007u32.to_be_bytes());
+        assert_eq!(
+            serial_request[40..],
+            [0x80, 0x06, 3, 0x03, 0x09, 0x04, 255, 0]
+        );
+    }
+
+    #[test]
+    fn test_request_serial_without_serial() {
+        let mut stream = Duplex {
+            replies: std::io::Cursor::new(ret_submit(1, &device_descriptor(0))),
+            written: Vec::new(),
+        };
+        let (device, _) =
+            UsbDevice::read_from(&mut &devlist_entry("1-1", 0x1050, 0x0407, &[])[..]).unwrap();
+        assert_eq!(request_serial(&mut stream, &device).unwrap(), None);
+        let mut stream = Duplex {
+            replies: std::io::Cursor::new(ret_submit(2, &device_descriptor(3))),
+            written: Vec::new(),
+        };
+        assert!(request_serial(&mut stream, &device).is_err());
+        // more bytes than requested would desync the stream
+        let mut long = device_descriptor(3);
+        long.push(0);
+        let mut stream = Duplex {
+            replies: std::io::Cursor::new(ret_submit(1, &long)),
+            written: Vec::new(),
+        };
+        assert!(request_serial(&mut stream, &device).is_err());
+    }
+
+    #[test]
+    fn test_devlist_roundtrip() {
+        let mut reply = reply_header(OP_REP_DEVLIST, 0);
+        reply.extend(2u32.to_be_bytes());
+        reply.extend(devlist_entry("1-11", 0x058f, 0x9540, &[[0x0b, 0, 0]]));
+        reply.extend(devlist_entry(
+            "1-4.3.4",
+            0x1050,
+            0x0407,
+            &[[3, 1, 1], [0x0b, 0, 0]],
+        ));
+        let (port, _handle) = fake_host(8, reply.clone());
+        let devices = list_remote("127.0.0.1", port).unwrap();
+
+        let mut encoded = Vec::new();
+        write_devlist(&mut encoded, &devices).unwrap();
+        assert_eq!(encoded, reply);
+    }
+
+    #[test]
+    fn test_import_reply_roundtrip() {
+        let mut reply = reply_header(OP_REP_IMPORT, 0);
+        reply.extend(devlist_entry("1-1", 0x1050, 0x0407, &[]));
+        let (device, _) = UsbDevice::read_from(&mut &reply[8..]).unwrap();
+
+        let mut encoded = Vec::new();
+        write_import_reply(&mut encoded, &ImportReply::Accepted(device)).unwrap();
+        assert_eq!(encoded, reply);
+
+        let mut encoded = Vec::new();
+        write_import_reply(&mut encoded, &ImportReply::Refused(ST_NODEV)).unwrap();
+        assert_eq!(encoded, reply_header(OP_REP_IMPORT, ST_NODEV));
+    }
+
+    #[test]
+    fn test_read_request() {
+        let mut devlist = Vec::new();
+        OpHeader::request(OP_REQ_DEVLIST)
+            .write_to(&mut devlist)
+            .unwrap();
+        assert_eq!(read_request(&mut &devlist[..]).unwrap(), Request::Devlist);
+
+        let mut import = Vec::new();
+        OpHeader::request(OP_REQ_IMPORT)
+            .write_to(&mut import)
+            .unwrap();
+        import.extend(c_string::<BUSID_SIZE>("1-4.3.4"));
+        assert_eq!(
+            read_request(&mut &import[..]).unwrap(),
+            Request::Import(BusId("1-4.3.4".to_string()))
+        );
+
+        let mut unknown = Vec::new();
+        OpHeader::request(0x8042).write_to(&mut unknown).unwrap();
+        assert!(read_request(&mut &unknown[..]).is_err());
+        let old_version = [0x01, 0x06, 0x80, 0x05, 0, 0, 0, 0];
+        assert!(read_request(&mut &old_version[..]).is_err());
+    }
+
+    #[test]
+    fn test_list_remote_no_server() {
+        // Bind and drop to get a port that nobody is listening on
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let err = list_remote("127.0.0.1", port).unwrap_err();
+        assert!(err.to_string().contains(&format!("port {port}")));
+    }
+}
@@ -0,0 +1,562 @@
+//! Select the devices that an operation acts on.
+use core::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::protocol::{UsbDevice, UsbInterface};
+use crate::{required_serials, serial_matches, BusId, UsbClass, UsbId, UsbIdPattern};
+
+/// The properties of a device that a [`Selector`] looks at
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate<'a> {
+    pub usb_id: UsbId,
+    /// Unknown for remote devices that were attached by a different tool
+    pub busid: Option<&'a BusId>,
+    /// Unknown for the devices that a usbip host exports
+    pub serial: Option<&'a str>,
+    /// Unknown for the devices that are attached to a local port
+    pub classes: Option<Classes<'a>>,
+}
+
+/// The class codes from the device and interface descriptors
+#[derive(Debug, Clone, Copy)]
+pub struct Classes<'a> {
+    pub device: u8,
+    pub interfaces: &'a [UsbInterface],
+}
+
+impl Classes<'_> {
+    /// `bDeviceClass` 0 means that each interface defines its own class
+    const PER_INTERFACE: u8 = 0x00;
+
+    /// Whether the device has the class, or one of its interfaces if the class is per interface
+    pub fn has_class(&self, class: UsbClass) -> bool {
+        match self.device {
+            Self::PER_INTERFACE => self.has_interface_class(class),
+            device => device == class.0,
+        }
+    }
+
+    pub fn has_interface_class(&self, class: UsbClass) -> bool {
+        self.interfaces.iter().any(|i| i.class == class.0)
+    }
+}
+
+impl<'a> From<&'a UsbDevice> for Classes<'a> {
+    fn from(device: &'a UsbDevice) -> Self {
+        Classes {
+            device: device.device_class,
+            interfaces: &device.interfaces,
+        }
+    }
+}
+
+/// Selects the devices that match any of the USB IDs, any of the busids, and so on.
+/// An empty list does not restrict the selection, i.e., the default selects all devices.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Selector {
+    pub usb_ids: Vec<UsbIdPattern>,
+    /// The busid is the physical port of the device, e.g., `1-4.3.4` behind a hub
+    pub busids: Vec<BusId>,
+    /// See [`Classes::has_class`], devices with unknown classes never match
+    pub classes: Vec<UsbClass>,
+    /// Devices with unknown classes never match
+    pub interface_classes: Vec<UsbClass>,
+    /// Applied after matching, e.g., to select all devices but the webcam
+    pub excluded: Vec<Exclusion>,
+    /// How many devices the ids have to match, see [`Selector::unmet_expectations`].
+    /// The ids are only counted, add them to `usb_ids` or `busids` to select them.
+    pub expected: Vec<Expectation>,
+}
+
+/// Removes the matching devices from the selection.
+///
+/// Parses a USB ID (`VVVV:PPPP` or `VVVV:*`), a busid (`1-4.3.4`)
+/// or a class (`0x0e` or `video`), in that order.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Exclusion {
+    UsbId(UsbIdPattern),
+    BusId(BusId),
+    /// See [`Classes::has_class`]
+    Class(UsbClass),
+}
+
+impl Exclusion {
+    /// Whether the device is excluded.
+    /// Devices are also excluded if the property is unknown,
+    /// e.g., the class of a mounted device, as acting on too many devices is worse.
+    pub fn excludes(&self, device: &Candidate) -> bool {
+        match self {
+            Exclusion::UsbId(pattern) => pattern.matches(&device.usb_id),
+            Exclusion::BusId(busid) => device.busid.map_or(true, |b| b == busid),
+            Exclusion::Class(class) => device.classes.map_or(true, |c| c.has_class(*class)),
+        }
+    }
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+#[error("Invalid exclusion `{0}`, expected a USB ID like `1050:0407` or `1050:*`, a busid like `1-4.3.4`, or a class like `0x0e` or `video`")]
+pub struct ParseExclusionError(String);
+
+impl FromStr for Exclusion {
+    type Err = ParseExclusionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseExclusionError(s.to_string());
+        if s.contains(':') {
+            return match s.parse::<UsbIdPattern>() {
+                // the serial numbers of remote devices are only known after importing them
+                Ok(pattern) if pattern.serial.is_none() => Ok(Exclusion::UsbId(pattern)),
+                _ => Err(err()),
+            };
+        }
+        s.parse()
+            .map(Exclusion::BusId)
+            .or_else(|_| s.parse().map(Exclusion::Class))
+            .map_err(|_| err())
+    }
+}
+
+impl fmt::Display for Exclusion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Exclusion::UsbId(pattern) => pattern.fmt(f),
+            Exclusion::BusId(busid) => busid.fmt(f),
+            Exclusion::Class(class) => class.fmt(f),
+        }
+    }
+}
+
+/// A USB ID or busid that was asked for
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RequestedId {
+    /// Without the serial number, which is not known for all selected devices
+    UsbId(UsbIdPattern),
+    BusId(BusId),
+}
+
+impl RequestedId {
+    pub fn matches(&self, busid: &BusId, usb_id: &UsbId) -> bool {
+        match self {
+            RequestedId::UsbId(pattern) => pattern.matches(usb_id),
+            RequestedId::BusId(b) => b == busid,
+        }
+    }
+}
+
+impl fmt::Display for RequestedId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestedId::UsbId(pattern) => pattern.fmt(f),
+            RequestedId::BusId(busid) => busid.fmt(f),
+        }
+    }
+}
+
+/// Exactly `count` devices have to match the id, parsed from `<id>=<count>`, e.g., `1050:0407=2`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Expectation {
+    pub id: RequestedId,
+    pub count: usize,
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+#[error("Invalid expectation `{0}`, expected a USB ID or busid and the number of devices, e.g., `1050:0407=2` or `1-4.3.4=1`")]
+pub struct ParseExpectationError(String);
+
+impl FromStr for Expectation {
+    type Err = ParseExpectationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseExpectationError(s.to_string());
+        let (id, count) = s.rsplit_once('=').ok_or_else(err)?;
+        let id = match id.contains(':') {
+            true => match id.parse::<UsbIdPattern>() {
+                Ok(pattern) if pattern.serial.is_none() => RequestedId::UsbId(pattern),
+                _ => return Err(err()),
+            },
+            false => RequestedId::BusId(id.parse().map_err(|_| err())?),
+        };
+        match count.parse() {
+            Ok(count) if count > 0 => Ok(Expectation { id, count }),
+            _ => Err(err()),
+        }
+    }
+}
+
+/// How many of the devices a requested id matched
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MatchCount {
+    pub id: RequestedId,
+    pub found: usize,
+    /// Any number of devices but zero if not given
+    pub expected: Option<usize>,
+}
+
+impl MatchCount {
+    /// Whether more devices have to match, in contrast to too many
+    pub fn is_missing(&self) -> bool {
+        self.found < self.expected.unwrap_or(1)
+    }
+}
+
+/// E.g., `1050:0407 matched 1 instead of 2 devices`
+impl fmt::Display for MatchCount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.expected {
+            None => write!(f, "{} matched no device", self.id),
+            Some(1) => write!(f, "{} matched {} instead of 1 device", self.id, self.found),
+            Some(n) => write!(
+                f,
+                "{} matched {} instead of {n} devices",
+                self.id, self.found
+            ),
+        }
+    }
+}
+
+impl Selector {
+    /// Whether the selector selects all devices, apart from the excluded ones
+    pub fn is_empty(&self) -> bool {
+        self.usb_ids.is_empty()
+            && self.busids.is_empty()
+            && self.classes.is_empty()
+            && self.interface_classes.is_empty()
+    }
+
+    /// Whether one of the USB IDs asks for a serial number
+    pub fn asks_for_serials(&self) -> bool {
+        self.usb_ids.iter().any(|p| p.serial.is_some())
+    }
+
+    /// Whether the device matches, without looking at the serial numbers
+    /// that the USB IDs ask for, see [`Selector::required_serials`]
+    pub fn matches_ignoring_serial(&self, device: &Candidate) -> bool {
+        let usb_id =
+            self.usb_ids.is_empty() || self.usb_ids.iter().any(|p| p.matches(&device.usb_id));
+        let busid = self.busids.is_empty() || device.busid.is_some_and(|b| self.busids.contains(b));
+        let class = self.classes.is_empty()
+            || device
+                .classes
+                .is_some_and(|c| self.classes.iter().any(|class| c.has_class(*class)));
+        let interface_class = self.interface_classes.is_empty()
+            || device.classes.is_some_and(|c| {
+                self.interface_classes
+                    .iter()
+                    .any(|class| c.has_interface_class(*class))
+            });
+        let excluded = self.excluded.iter().any(|e| e.excludes(device));
+        usb_id && busid && class && interface_class && !excluded
+    }
+
+    /// The serial numbers that the USB IDs ask for, empty if any serial number is fine
+    pub fn required_serials(&self, usb_id: &UsbId) -> Vec<&str> {
+        required_serials(&self.usb_ids, usb_id)
+    }
+
+    pub fn matches(&self, device: &Candidate) -> bool {
+        self.matches_ignoring_serial(device)
+            && serial_matches(&self.required_serials(&device.usb_id), device.serial)
+    }
+
+    /// The requested USB IDs and busids, including the expected ones, without duplicates
+    pub fn requested_ids(&self) -> Vec<RequestedId> {
+        let usb_ids = self.usb_ids.iter().map(|p| {
+            RequestedId::UsbId(UsbIdPattern {
+                serial: None,
+                ..p.clone()
+            })
+        });
+        let busids = self.busids.iter().cloned().map(RequestedId::BusId);
+        let expected = self.expected.iter().map(|e| e.id.clone());
+        let mut ids = Vec::new();
+        for id in usb_ids.chain(busids).chain(expected) {
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        ids
+    }
+
+    /// The requested ids that matched no device or not the expected number of devices,
+    /// counted in the devices that an operation acted on
+    pub fn unmet_expectations<'a>(
+        &self,
+        devices: impl IntoIterator<Item = (&'a BusId, UsbId)>,
+    ) -> Vec<MatchCount> {
+        let devices = devices.into_iter().collect::<Vec<_>>();
+        self.requested_ids()
+            .into_iter()
+            .map(|id| MatchCount {
+                found: devices.iter().filter(|(b, u)| id.matches(b, u)).count(),
+                expected: self
+                    .expected
+                    .iter()
+                    .find_map(|e| (e.id == id).then_some(e.count)),
+                id,
+            })
+            .filter(|m| match m.expected {
+                Some(count) => m.found != count,
+                None => m.found == 0,
+            })
+            .collect()
+    }
+
+    /// The matching devices in the given order, identified by `T`
+    pub fn select<'a, T>(
+        &self,
+        devices: impl IntoIterator<Item = (T, Candidate<'a>)>,
+    ) -> Vec<(T, UsbId)> {
+        devices
+            .into_iter()
+            .filter(|(_, device)| self.matches(device))
+            .map(|(t, device)| (t, device.usb_id))
+            .collect()
+    }
+}
+
+impl From<&[UsbIdPattern]> for Selector {
+    fn from(usb_ids: &[UsbIdPattern]) -> Self {
+        Selector {
+            usb_ids: usb_ids.to_vec(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let busids = ["1-1", "1-4.3.4", "1-4.5"].map(|b| BusId(b.to_string()));
+        let devices = [
+            (0, UsbId::new(0x1050, 0x0407), Some("CC1234")),
+            (1, UsbId::new(0x1050, 0x0407), Some("CC5678")),
+            (2, UsbId::new(0x413c, 0xb06e), None),
+        ];
+        let select = |selector: &Selector| {
+            selector
+                .select(devices.iter().map(|(i, usb_id, serial)| {
+                    let device = Candidate {
+                        usb_id: *usb_id,
+                        busid: Some(&busids[*i]),
+                        serial: *serial,
+                        classes: None,
+                    };
+                    (*i, device)
+                }))
+                .into_iter()
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(select(&Selector::default()), vec![0, 1, 2]);
+        let dock = Selector {
+            busids: vec![BusId("1-4.3.4".to_string())],
+            ..Default::default()
+        };
+        assert_eq!(select(&dock), vec![1]);
+        let yubikeys = Selector::from(&["1050:*".parse().unwrap()][..]);
+        assert_eq!(select(&yubikeys), vec![0, 1]);
+        // both have to match
+        let dell_in_dock = Selector {
+            usb_ids: vec!["413c:*".parse().unwrap()],
+            ..dock.clone()
+        };
+        assert!(select(&dell_in_dock).is_empty());
+        let serial = Selector::from(&["1050:0407@CC5678".parse().unwrap()][..]);
+        assert_eq!(select(&serial), vec![1]);
+    }
+
+    #[test]
+    fn test_select_classes() {
+        let interface = |class| UsbInterface {
+            class,
+            subclass: 0,
+            protocol: 0,
+        };
+        // a smartcard reader, a FIDO key with a HID and a CCID interface, and a hub
+        let interfaces = [
+            vec![interface(0x0b)],
+            vec![interface(0x03), interface(0x0b)],
+            vec![interface(0x09)],
+        ];
+        let devices = [(0, 0x00), (1, 0x00), (2, 0x09), (3, 0x00)];
+        let select = |selector: &Selector| {
+            selector
+                .select(devices.iter().map(|(i, device_class)| {
+                    let device = Candidate {
+                        usb_id: UsbId::new(0x1050, 0x0407),
+                        busid: None,
+                        serial: None,
+                        // the classes of the last device are unknown
+                        classes: interfaces.get(*i).map(|interfaces| Classes {
+                            device: *device_class,
+                            interfaces,
+                        }),
+                    };
+                    (*i, device)
+                }))
+                .into_iter()
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>()
+        };
+        let class = |classes: &[&str]| Selector {
+            classes: classes.iter().map(|c| c.parse().unwrap()).collect(),
+            ..Default::default()
+        };
+        assert_eq!(select(&class(&["smartcard"])), vec![0, 1]);
+        assert_eq!(select(&class(&["hid", "hub"])), vec![1, 2]);
+        let interface_class = Selector {
+            interface_classes: vec!["0x0b".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(select(&interface_class), vec![0, 1]);
+        // both have to match
+        let both = Selector {
+            interface_classes: vec![UsbClass(0x03)],
+            ..class(&["0x0b"])
+        };
+        assert_eq!(select(&both), vec![1]);
+        assert_eq!(select(&Selector::default()), vec![0, 1, 2, 3]);
+    }
+
+    #[rstest]
+    #[case("1050:0407", Exclusion::UsbId(UsbId::new(0x1050, 0x0407).into()))]
+    #[case("1050:*", Exclusion::UsbId("1050:*".parse().unwrap()))]
+    #[case("1-4.3.4", Exclusion::BusId(BusId("1-4.3.4".to_string())))]
+    #[case("0x0e", Exclusion::Class(UsbClass(0x0e)))]
+    #[case("video", Exclusion::Class(UsbClass(0x0e)))]
+    fn test_parse_exclusion(#[case] input: &str, #[case] expected: Exclusion) {
+        assert_eq!(input.parse::<Exclusion>(), Ok(expected));
+    }
+
+    #[rstest]
+    #[case("1050:04O7")]
+    #[case("1050:0407@CC1234")]
+    #[case("1-")]
+    #[case("webcam")]
+    fn test_parse_invalid_exclusion(#[case] input: &str) {
+        let err = input.parse::<Exclusion>().unwrap_err();
+        assert!(err.to_string().contains(&format!("`{input}`")));
+    }
+
+    #[rstest]
+    #[case("1050:0407=2", RequestedId::UsbId(UsbId::new(0x1050, 0x0407).into()), 2)]
+    #[case("1050:*=3", RequestedId::UsbId("1050:*".parse().unwrap()), 3)]
+    #[case("1-4.3.4=1", RequestedId::BusId(BusId("1-4.3.4".to_string())), 1)]
+    fn test_parse_expectation(#[case] input: &str, #[case] id: RequestedId, #[case] count: usize) {
+        assert_eq!(input.parse::<Expectation>(), Ok(Expectation { id, count }));
+    }
+
+    #[rstest]
+    #[case("1050:0407")]
+    #[case("1050:0407=0")]
+    #[case("1050:0407=two")]
+    #[case("1050:0407@CC1234=1")]
+    #[case("video=1")]
+    fn test_parse_invalid_expectation(#[case] input: &str) {
+        let err = input.parse::<Expectation>().unwrap_err();
+        assert!(err.to_string().contains(&format!("`{input}`")));
+    }
+
+    #[test]
+    fn test_unmet_expectations() {
+        let busids = ["1-1", "1-2", "1-3"].map(|b| BusId(b.to_string()));
+        let yubikey = UsbId::new(0x1050, 0x0407);
+        let devices = [
+            (&busids[0], yubikey),
+            (&busids[1], yubikey),
+            (&busids[2], UsbId::new(0x058f, 0x9540)),
+        ];
+        let selector = Selector {
+            usb_ids: vec![
+                "1050:0407@CC1234".parse().unwrap(),
+                "1050:0407@CC5678".parse().unwrap(),
+                "dead:beef".parse().unwrap(),
+            ],
+            busids: vec![busids[2].clone()],
+            ..Default::default()
+        };
+        // the serial numbers are not told apart
+        assert_eq!(selector.requested_ids().len(), 3);
+        let unmet = selector.unmet_expectations(devices);
+        assert_eq!(
+            unmet.iter().map(|m| m.to_string()).collect::<Vec<_>>(),
+            vec!["dead:beef matched no device"]
+        );
+        assert!(unmet[0].is_missing());
+
+        let expecting = |expected: &[&str]| Selector {
+            expected: expected.iter().map(|e| e.parse().unwrap()).collect(),
+            ..Default::default()
+        };
+        assert!(expecting(&["1050:0407=2", "1-3=1"])
+            .unmet_expectations(devices)
+            .is_empty());
+        let unmet = expecting(&["1050:0407=3", "1-2=1", "1050:*=1"]).unmet_expectations(devices);
+        assert_eq!(
+            unmet.iter().map(|m| m.to_string()).collect::<Vec<_>>(),
+            vec![
+                "1050:0407 matched 2 instead of 3 devices",
+                "1050:* matched 2 instead of 1 device"
+            ]
+        );
+        assert!(unmet[0].is_missing());
+        assert!(!unmet[1].is_missing());
+    }
+
+    #[test]
+    fn test_select_excluded() {
+        let busids = ["1-1", "1-2"].map(|b| BusId(b.to_string()));
+        let webcam = [UsbInterface {
+            class: 0x0e,
+            subclass: 0,
+            protocol: 0,
+        }];
+        let devices = [
+            (0, UsbId::new(0x1050, 0x0407), &[][..]),
+            (1, UsbId::new(0x04f2, 0xb67c), &webcam[..]),
+        ];
+        let select = |selector: &Selector, known: bool| {
+            selector
+                .select(devices.iter().map(|(i, usb_id, interfaces)| {
+                    let device = Candidate {
+                        usb_id: *usb_id,
+                        busid: known.then(|| &busids[*i]),
+                        serial: None,
+                        classes: known.then_some(Classes {
+                            device: 0,
+                            interfaces,
+                        }),
+                    };
+                    (*i, device)
+                }))
+                .into_iter()
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>()
+        };
+        let excluding = |exclusion: &str| Selector {
+            excluded: vec![exclusion.parse().unwrap()],
+            ..Default::default()
+        };
+        for exclusion in ["04f2:b67c", "04f2:*", "1-2", "video"] {
+            assert_eq!(select(&excluding(exclusion), true), vec![0], "{exclusion}");
+        }
+        assert_eq!(select(&excluding("hid"), true), vec![0, 1]);
+        // unknown properties are excluded to be on the safe side
+        assert!(select(&excluding("1-2"), false).is_empty());
+        assert!(select(&excluding("video"), false).is_empty());
+        assert_eq!(select(&excluding("04f2:b67c"), false), vec![0]);
+        // applied after matching
+        let yubikeys = Selector {
+            usb_ids: vec!["1050:*".parse().unwrap()],
+            ..excluding("1-1")
+        };
+        assert!(select(&yubikeys, true).is_empty());
+    }
+}
@@ -0,0 +1,401 @@
+//! Built-in replacement for the `usbipd` daemon.
+//!
+//! The server answers device list requests with all devices that are bound
+//! to `usbip-host` and exports a device by handing the connected socket of
+//! the client to the kernel.
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use log::{error, info, warn};
+
+use crate::protocol::{self, ImportReply, Request, ST_DEV_BUSY, ST_DEV_ERR, ST_ERROR, ST_NODEV};
+use crate::sysfs::{LocalDevice, Sysfs};
+use crate::usbip_host::{self, ExportStatus, USBIP_HOST_DRIVER};
+
+/// How long to wait for a client to send its request
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait after a connection could not be accepted
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// How often to check for new connections and the shutdown flag
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Listen on all addresses like `usbipd` does.
+/// Falls back to IPv4 only if IPv6 is disabled.
+pub fn listen(tcp_port: u16) -> anyhow::Result<TcpListener> {
+    TcpListener::bind((Ipv6Addr::UNSPECIFIED, tcp_port))
+        .or_else(|_| TcpListener::bind((Ipv4Addr::UNSPECIFIED, tcp_port)))
+        .with_context(|| format!("Could not listen on TCP port {tcp_port}"))
+}
+
+#[derive(Debug, Clone)]
+pub struct Server {
+    sysfs: Sysfs,
+}
+
+impl Server {
+    pub fn new(sysfs: Sysfs) -> Self {
+        Server { sysfs }
+    }
+
+    /// All devices that are bound to `usbip-host` and can be imported by a client
+    pub fn exported_devices(&self) -> anyhow::Result<Vec<LocalDevice>> {
+        Ok(self
+            .sysfs
+            .list_devices()?
+            .into_iter()
+            .filter(|d| d.driver.as_deref() == Some(USBIP_HOST_DRIVER))
+            .collect())
+    }
+
+    /// Human readable summary of the exported devices, e.g., for `systemctl status`
+    pub fn describe_exported(&self) -> anyhow::Result<String> {
+        let devices = self
+            .exported_devices()?
+            .iter()
+            .map(|d| {
+                let busid = d.busid();
+                match usbip_host::export_status(&self.sysfs, busid) {
+                    Ok(ExportStatus::Used) => format!("{busid} ({}, in use)", d.usb_id()),
+                    _ => format!("{busid} ({})", d.usb_id()),
+                }
+            })
+            .collect::<Vec<_>>();
+        Ok(match devices.is_empty() {
+            true => "No devices are exported".to_string(),
+            false => format!("Exporting {}", devices.join(", ")),
+        })
+    }
+
+    /// Whether any exported device is currently imported by a client
+    pub fn any_in_use(&self) -> anyhow::Result<bool> {
+        for device in self.exported_devices()? {
+            if usbip_host::export_status(&self.sysfs, device.busid())? == ExportStatus::Used {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Unbind all exported devices from `usbip-host`, which also
+    /// disconnects the clients that currently use them
+    pub fn unbind_exported(&self) -> anyhow::Result<()> {
+        let mut failed = false;
+        for device in self.exported_devices()? {
+            let busid = device.busid();
+            match usbip_host::unbind(&self.sysfs, busid) {
+                Ok(_) => info!("unbound {busid}"),
+                Err(e) => {
+                    error!("Could not unbind {busid}: {e:#}");
+                    failed = true;
+                }
+            }
+        }
+        match failed {
+            true => Err(anyhow!("Could not unbind all exported devices")),
+            false => Ok(()),
+        }
+    }
+
+    /// Accept and handle connections until the listener fails or `shutdown` is set.
+    /// Errors of a single connection, like an aborted handshake, are logged and skipped.
+    /// Connections are handled one after another, as every client only sends a single
+    /// short request before the connection is either closed or handed to the kernel.
+    pub fn serve(&self, listener: &TcpListener, shutdown: &AtomicBool) -> anyhow::Result<()> {
+        // Poll instead of blocking in `accept` to notice the shutdown
+        listener.set_nonblocking(true)?;
+        while !shutdown.load(Ordering::Relaxed) {
+            let (stream, addr) = match listener.accept() {
+                Ok(connection) => connection,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) if is_fatal_accept_error(&e) => return Err(e.into()),
+                Err(e) => {
+                    warn!("Could not accept a connection: {e}");
+                    // e.g., out of file descriptors, give the other connections time to close
+                    thread::sleep(ACCEPT_RETRY_DELAY);
+                    continue;
+                }
+            };
+            info!("connection from {addr}");
+            if let Err(e) = stream
+                .set_nonblocking(false)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| self.handle(stream))
+            {
+                warn!("Could not handle request from {addr}: {e:#}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Serve all listeners in parallel, e.g., the sockets that were passed by systemd.
+    /// If one of the listeners fails, the others are shut down as well.
+    pub fn serve_all(
+        &self,
+        listeners: &[TcpListener],
+        shutdown: &AtomicBool,
+    ) -> anyhow::Result<()> {
+        thread::scope(|scope| {
+            let handles = listeners
+                .iter()
+                .map(|l| {
+                    scope.spawn(|| {
+                        let result = self.serve(l, shutdown);
+                        shutdown.store(true, Ordering::Relaxed);
+                        result
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles.into_iter().try_for_each(|h| {
+                h.join()
+                    .unwrap_or_else(|_| Err(anyhow!("Server thread panicked")))
+            })
+        })
+    }
+
+    /// Handle the single request of a client connection
+    pub fn handle(&self, mut stream: TcpStream) -> anyhow::Result<()> {
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        // like `usbipd`, as the URBs of an exported device are latency sensitive
+        stream.set_nodelay(true)?;
+        match protocol::read_request(&mut stream)? {
+            Request::Devlist => {
+                let devices = self
+                    .exported_devices()?
+                    .into_iter()
+                    .map(|d| d.info)
+                    .collect::<Vec<_>>();
+                info!("sending list of {} exported devices", devices.len());
+                protocol::write_devlist(&mut stream, &devices)
+            }
+            Request::Import(busid) => {
+                let device = self
+                    .exported_devices()?
+                    .into_iter()
+                    .find(|d| d.busid() == &busid);
+                let Some(device) = device else {
+                    warn!("requested device {busid} is not exported");
+                    return protocol::write_import_reply(
+                        &mut stream,
+                        &ImportReply::Refused(ST_NODEV),
+                    );
+                };
+                match usbip_host::export_status(&self.sysfs, &busid) {
+                    Ok(ExportStatus::Available) => {}
+                    Ok(ExportStatus::Used) => {
+                        warn!("requested device {busid} is already in use");
+                        return protocol::write_import_reply(
+                            &mut stream,
+                            &ImportReply::Refused(ST_DEV_BUSY),
+                        );
+                    }
+                    Ok(status) => {
+                        warn!("requested device {busid} is in state {status:?}");
+                        return protocol::write_import_reply(
+                            &mut stream,
+                            &ImportReply::Refused(ST_DEV_ERR),
+                        );
+                    }
+                    Err(e) => {
+                        error!("Could not read the state of {busid}: {e:#}");
+                        return protocol::write_import_reply(
+                            &mut stream,
+                            &ImportReply::Refused(ST_ERROR),
+                        );
+                    }
+                }
+                // The kernel takes its own reference to the socket,
+                // so the stream can be closed after the reply was sent.
+                if let Err(e) = usbip_host::export(&self.sysfs, &busid, stream.as_raw_fd()) {
+                    error!("Could not export {busid}: {e:#}");
+                    return protocol::write_import_reply(
+                        &mut stream,
+                        &ImportReply::Refused(ST_DEV_ERR),
+                    );
+                }
+                info!("exported {busid}");
+                protocol::write_import_reply(&mut stream, &ImportReply::Accepted(device.info))
+            }
+        }
+    }
+}
+
+/// Whether the listener itself is broken, in contrast to errors of a single
+/// connection (`ECONNABORTED`, `EPROTO`, ...) or a temporary lack of resources (`EMFILE`, ...)
+fn is_fatal_accept_error(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EBADF | libc::EFAULT | libc::EINVAL | libc::ENOTSOCK | libc::EOPNOTSUPP)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::protocol::request_import;
+    use crate::sysfs::tests::FakeSysfs;
+    use crate::{BusId, UsbId};
+
+    /// Two exported devices and one that is not bound to `usbip-host`
+    fn fake_host() -> FakeSysfs {
+        let fake = FakeSysfs::new();
+        for (busid, status) in [("1-1", "1"), ("1-4.3.4", "2")] {
+            fake.add_device(busid, "1050", "0407");
+            fake.set_driver(busid, Some(USBIP_HOST_DRIVER));
+            fake.set_attr(busid, "usbip_status", status);
+            fake.set_attr(busid, "usbip_sockfd", "");
+        }
+        fake.add_device("1-2", "058f", "9540");
+        fake.set_driver("1-2", Some("usb"));
+        fake
+    }
+
+    /// Serve the given number of connections on loopback
+    fn serve(fake: &FakeSysfs, connections: usize) -> (u16, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = Server::new(fake.sysfs());
+        let handle = thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                server.handle(stream.unwrap()).unwrap();
+            }
+        });
+        (port, handle)
+    }
+
+    fn import(port: u16, busid: &str) -> ImportReply {
+        let mut stream = protocol::connect("127.0.0.1", port).unwrap();
+        request_import(&mut stream, &BusId(busid.to_string())).unwrap()
+    }
+
+    #[test]
+    fn test_devlist() {
+        let fake = fake_host();
+        let (port, handle) = serve(&fake, 1);
+        let devices = protocol::list_remote("127.0.0.1", port).unwrap();
+        handle.join().unwrap();
+        assert_eq!(
+            devices
+                .iter()
+                .map(|d| d.busid.0.as_str())
+                .collect::<Vec<_>>(),
+            vec!["1-1", "1-4.3.4"]
+        );
+        assert_eq!(devices[0].usb_id(), UsbId::new(0x1050, 0x0407));
+        assert_eq!(devices[0].interfaces.len(), 1);
+        assert_eq!(
+            devices[0],
+            fake.sysfs().device(&BusId("1-1".to_string())).unwrap().info
+        );
+    }
+
+    #[test]
+    fn test_fatal_accept_error() {
+        for errno in [libc::ECONNABORTED, libc::EINTR, libc::EMFILE, libc::ENFILE] {
+            assert!(!is_fatal_accept_error(&io::Error::from_raw_os_error(errno)));
+        }
+        assert!(is_fatal_accept_error(&io::Error::from_raw_os_error(
+            libc::EBADF
+        )));
+        assert!(is_fatal_accept_error(&io::Error::from_raw_os_error(
+            libc::EINVAL
+        )));
+    }
+
+    #[test]
+    fn test_describe_exported() {
+        let fake = fake_host();
+        assert_eq!(
+            Server::new(fake.sysfs()).describe_exported().unwrap(),
+            "Exporting 1-1 (1050:0407), 1-4.3.4 (1050:0407, in use)"
+        );
+        assert_eq!(
+            Server::new(FakeSysfs::new().sysfs())
+                .describe_exported()
+                .unwrap(),
+            "No devices are exported"
+        );
+    }
+
+    #[test]
+    fn test_any_in_use() {
+        let fake = fake_host();
+        assert!(Server::new(fake.sysfs()).any_in_use().unwrap());
+        fake.set_attr("1-4.3.4", "usbip_status", "1");
+        assert!(!Server::new(fake.sysfs()).any_in_use().unwrap());
+    }
+
+    #[test]
+    fn test_serve_until_shutdown() {
+        let fake = fake_host();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = Server::new(fake.sysfs());
+        let shutdown = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let handle = scope.spawn(|| server.serve_all(&[listener], &shutdown));
+            assert_eq!(protocol::list_remote("127.0.0.1", port).unwrap().len(), 2);
+            shutdown.store(true, Ordering::Relaxed);
+            handle.join().unwrap().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_import() {
+        let fake = fake_host();
+        let (port, handle) = serve(&fake, 1);
+        match import(port, "1-1") {
+            ImportReply::Accepted(d) => assert_eq!(d.busid, BusId("1-1".to_string())),
+            r => panic!("Unexpected reply {r:?}"),
+        }
+        handle.join().unwrap();
+        let sockfd = fs::read_to_string(fake.root().join("devices/usb1/1-1/usbip_sockfd")).unwrap();
+        assert!(sockfd.parse::<i32>().is_ok(), "{sockfd}");
+    }
+
+    #[test]
+    fn test_import_refused() {
+        let fake = fake_host();
+        let (port, handle) = serve(&fake, 3);
+        assert_eq!(import(port, "1-4.3.4"), ImportReply::Refused(ST_DEV_BUSY));
+        assert_eq!(import(port, "1-2"), ImportReply::Refused(ST_NODEV));
+        assert_eq!(import(port, "9-9"), ImportReply::Refused(ST_NODEV));
+        handle.join().unwrap();
+        assert_eq!(
+            fs::read_to_string(fake.root().join("devices/usb1/1-4.3.4/usbip_sockfd")).unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_import_unknown_state() {
+        let fake = fake_host();
+        fs::remove_file(fake.root().join("devices/usb1/1-1/usbip_status")).unwrap();
+        let (port, handle) = serve(&fake, 1);
+        assert_eq!(import(port, "1-1"), ImportReply::Refused(ST_ERROR));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_import_export_failure() {
+        let fake = fake_host();
+        // without the attribute the kernel would not accept the socket
+        fs::remove_file(fake.root().join("devices/usb1/1-1/usbip_sockfd")).unwrap();
+        fs::create_dir(fake.root().join("devices/usb1/1-1/usbip_sockfd")).unwrap();
+        let (port, handle) = serve(&fake, 1);
+        assert_eq!(import(port, "1-1"), ImportReply::Refused(ST_DEV_ERR));
+        handle.join().unwrap();
+    }
+}
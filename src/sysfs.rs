@@ -0,0 +1,407 @@
+//! Read-only access to the USB devices that the kernel exposes via sysfs.
+//!
+//! The root of the sysfs hierarchy is configurable, which allows
+//! to point the functions to a fake directory tree during testing.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context};
+use log::warn;
+
+use crate::protocol::{UsbDevice, UsbInterface, UsbSpeed};
+use crate::{BusId, UsbId};
+
+/// `bDeviceClass` of USB hubs, which cannot be exported
+pub const USB_CLASS_HUB: u8 = 0x09;
+
+/// Entry point to a (potentially fake) sysfs hierarchy
+#[derive(Debug, Clone)]
+pub struct Sysfs {
+    root: PathBuf,
+}
+
+/// A USB device that is connected locally
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct LocalDevice {
+    /// The device description in the same format as it is sent over the wire
+    pub info: UsbDevice,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    /// Name of the driver that the device is currently bound to
+    pub driver: Option<String>,
+}
+
+impl LocalDevice {
+    pub fn busid(&self) -> &BusId {
+        &self.info.busid
+    }
+
+    pub fn usb_id(&self) -> UsbId {
+        self.info.usb_id()
+    }
+}
+
+/// The real sysfs hierarchy
+impl Default for Sysfs {
+    fn default() -> Self {
+        Sysfs::new("/sys")
+    }
+}
+
+impl Sysfs {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Sysfs { root: root.into() }
+    }
+
+    /// Directory that contains a symlink for every USB device and interface
+    pub fn devices_dir(&self) -> PathBuf {
+        self.root.join("bus/usb/devices")
+    }
+
+    pub fn device_dir(&self, busid: &BusId) -> PathBuf {
+        self.devices_dir().join(&busid.0)
+    }
+
+    /// Directory of a platform device, like the virtual host controller
+    pub fn platform_device_dir(&self, name: &str) -> PathBuf {
+        self.root.join("devices/platform").join(name)
+    }
+
+    /// Directory of a USB driver, which provides the `bind` and `unbind` attributes
+    pub fn driver_dir(&self, driver: &str) -> PathBuf {
+        self.root.join("bus/usb/drivers").join(driver)
+    }
+
+    /// Return all locally connected USB devices that could be exported,
+    /// sorted by their bus and port path.
+    /// Root hubs, interfaces and hubs are skipped, as `usbip list --local` does.
+    /// Devices that cannot be read, e.g., because they were unplugged meanwhile, are skipped with a warning.
+    pub fn list_devices(&self) -> anyhow::Result<Vec<LocalDevice>> {
+        let dir = self.devices_dir();
+        let entries = fs::read_dir(&dir)
+            .with_context(|| format!("Could not list USB devices in {}", dir.display()))?;
+        let mut devices = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if name.contains(':') || name.starts_with("usb") {
+                continue;
+            }
+            let device = match self.device(&BusId(name.clone())) {
+                Ok(device) => device,
+                Err(e) => {
+                    warn!("Skipping the USB device {name}: {e:#}");
+                    continue;
+                }
+            };
+            if device.info.device_class != USB_CLASS_HUB {
+                devices.push(device);
+            }
+        }
+        devices.sort_by_cached_key(|d| port_path(d.busid()));
+        Ok(devices)
+    }
+
+    /// Read a single device given its busid
+    pub fn device(&self, busid: &BusId) -> anyhow::Result<LocalDevice> {
+        let dir = self.device_dir(busid);
+        if !dir.exists() {
+            return Err(anyhow!("There is no USB device with busid {busid}"));
+        }
+        let attr = |name: &str| read_attr(&dir, name);
+        let configuration_value = match attr("bConfigurationValue")?.as_str() {
+            // unconfigured devices report an empty value
+            "" => 0,
+            v => v.parse()?,
+        };
+        let info = UsbDevice {
+            path: fs::canonicalize(&dir)
+                .unwrap_or_else(|_| dir.clone())
+                .to_string_lossy()
+                .to_string(),
+            busid: busid.clone(),
+            busnum: attr("busnum")?.parse()?,
+            devnum: attr("devnum")?.parse()?,
+            speed: parse_speed(&attr("speed")?),
+            id_vendor: parse_hex(&attr("idVendor")?)?,
+            id_product: parse_hex(&attr("idProduct")?)?,
+            bcd_device: parse_hex(&attr("bcdDevice")?)?,
+            device_class: parse_hex(&attr("bDeviceClass")?)?,
+            device_subclass: parse_hex(&attr("bDeviceSubClass")?)?,
+            device_protocol: parse_hex(&attr("bDeviceProtocol")?)?,
+            configuration_value,
+            num_configurations: attr("bNumConfigurations")?.parse()?,
+            interfaces: self.interfaces(busid)?,
+        };
+        Ok(LocalDevice {
+            info,
+            manufacturer: read_optional_attr(&dir, "manufacturer"),
+            product: read_optional_attr(&dir, "product"),
+            serial: read_optional_attr(&dir, "serial"),
+            driver: read_driver(&dir),
+        })
+    }
+
+    /// Read the interfaces of the active configuration,
+    /// which are exposed as `<busid>:<config>.<interface>` next to the device
+    fn interfaces(&self, busid: &BusId) -> anyhow::Result<Vec<UsbInterface>> {
+        let prefix = format!("{busid}:");
+        let mut dirs = fs::read_dir(self.devices_dir())?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with(&prefix))
+            .collect::<Vec<_>>();
+        // sort numerically by the interface number to keep `x.10` after `x.9`
+        dirs.sort_by_key(|name| {
+            name.rsplit('.')
+                .next()
+                .and_then(|n| n.parse::<u32>().ok())
+                .unwrap_or(u32::MAX)
+        });
+        dirs.into_iter()
+            .map(|name| {
+                let dir = self.devices_dir().join(name);
+                Ok(UsbInterface {
+                    class: parse_hex(&read_attr(&dir, "bInterfaceClass")?)?,
+                    subclass: parse_hex(&read_attr(&dir, "bInterfaceSubClass")?)?,
+                    protocol: parse_hex(&read_attr(&dir, "bInterfaceProtocol")?)?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The bus and the port numbers of a busid, to sort `1-2` before `1-10`
+fn port_path(busid: &BusId) -> Vec<u32> {
+    busid
+        .0
+        .split(['-', '.'])
+        .map(|n| n.parse().unwrap_or(u32::MAX))
+        .collect()
+}
+
+/// Read a sysfs attribute without the trailing whitespace
+fn read_attr(dir: &Path, name: &str) -> anyhow::Result<String> {
+    let path = dir.join(name);
+    let value =
+        fs::read_to_string(&path).with_context(|| format!("Could not read {}", path.display()))?;
+    Ok(value.trim().to_string())
+}
+
+/// Read an attribute that is not provided by every device, like the string descriptors
+fn read_optional_attr(dir: &Path, name: &str) -> Option<String> {
+    read_attr(dir, name).ok().filter(|v| !v.is_empty())
+}
+
+/// The driver is exposed as a symlink that points to the driver directory
+fn read_driver(dir: &Path) -> Option<String> {
+    fs::read_link(dir.join("driver"))
+        .ok()
+        .and_then(|p| p.file_name().map(|n| n.to_string_lossy().to_string()))
+}
+
+fn parse_hex<T: TryFrom<u32>>(s: &str) -> anyhow::Result<T> {
+    let v = u32::from_str_radix(s, 16).with_context(|| format!("`{s}` is not a hex value"))?;
+    T::try_from(v).map_err(|_| anyhow!("`{s}` is out of range"))
+}
+
+/// Convert the value of the `speed` attribute (in Mbit/s)
+fn parse_speed(s: &str) -> UsbSpeed {
+    match s {
+        "1.5" => UsbSpeed::Low,
+        "12" => UsbSpeed::Full,
+        "480" => UsbSpeed::High,
+        "53.3-480" => UsbSpeed::Wireless,
+        "5000" => UsbSpeed::Super,
+        "10000" | "20000" => UsbSpeed::SuperPlus,
+        _ => UsbSpeed::Unknown,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::os::unix::fs::symlink;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A fake sysfs tree that mimics the layout of the USB subsystem.
+    /// The device directories are placed below `devices/` and symlinked from
+    /// `bus/usb/devices/`, just like the kernel does it.
+    pub(crate) struct FakeSysfs {
+        dir: TempDir,
+    }
+
+    impl FakeSysfs {
+        pub(crate) fn new() -> Self {
+            let dir = TempDir::new().unwrap();
+            fs::create_dir_all(dir.path().join("bus/usb/devices")).unwrap();
+            fs::create_dir_all(dir.path().join("bus/usb/drivers")).unwrap();
+            FakeSysfs { dir }
+        }
+
+        pub(crate) fn root(&self) -> &Path {
+            self.dir.path()
+        }
+
+        pub(crate) fn sysfs(&self) -> Sysfs {
+            Sysfs::new(self.root())
+        }
+
+        fn link(&self, name: &str) -> PathBuf {
+            let target = self.root().join("devices/usb1").join(name);
+            fs::create_dir_all(&target).unwrap();
+            symlink(&target, self.root().join("bus/usb/devices").join(name)).unwrap();
+            target
+        }
+
+        pub(crate) fn set_attr(&self, name: &str, attr: &str, value: &str) {
+            fs::write(
+                self.root().join("devices/usb1").join(name).join(attr),
+                value,
+            )
+            .unwrap();
+        }
+
+        /// Add a full-speed device with a single interface of the given class
+        pub(crate) fn add_device(&self, busid: &str, vendor: &str, product: &str) {
+            self.link(busid);
+            let (busnum, _) = busid.split_once('-').unwrap();
+            for (attr, value) in [
+                ("busnum", busnum),
+                ("devnum", "2"),
+                ("speed", "12"),
+                ("idVendor", vendor),
+                ("idProduct", product),
+                ("bcdDevice", "0543"),
+                ("bDeviceClass", "00"),
+                ("bDeviceSubClass", "00"),
+                ("bDeviceProtocol", "00"),
+                ("bConfigurationValue", "1"),
+                ("bNumConfigurations", "1"),
+                ("bNumInterfaces", " 1"),
+            ] {
+                self.set_attr(busid, attr, &format!("{value}\n"));
+            }
+            self.add_interface(busid, 0, "03");
+        }
+
+        pub(crate) fn add_interface(&self, busid: &str, number: u8, class: &str) {
+            let name = format!("{busid}:1.{number}");
+            self.link(&name);
+            self.set_attr(&name, "bInterfaceClass", class);
+            self.set_attr(&name, "bInterfaceSubClass", "00");
+            self.set_attr(&name, "bInterfaceProtocol", "00");
+        }
+
+        pub(crate) fn set_platform_attr(&self, device: &str, attr: &str, value: &str) {
+            let dir = self.root().join("devices/platform").join(device);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(attr), value).unwrap();
+        }
+
+        pub(crate) fn add_driver(&self, driver: &str) {
+            fs::create_dir_all(self.root().join("bus/usb/drivers").join(driver)).unwrap();
+        }
+
+        /// Point the `driver` symlink of the device to the given driver
+        pub(crate) fn set_driver(&self, busid: &str, driver: Option<&str>) {
+            let link = self.root().join("devices/usb1").join(busid).join("driver");
+            let _ = fs::remove_file(&link);
+            if let Some(driver) = driver {
+                self.add_driver(driver);
+                symlink(self.root().join("bus/usb/drivers").join(driver), link).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_list_devices() {
+        let fake = FakeSysfs::new();
+        fake.add_device("1-11", "058f", "9540");
+        fake.add_device("1-4.3.4", "1050", "0407");
+        fake.set_attr("1-4.3.4", "manufacturer", "Yubico\n");
+        fake.set_attr("1-4.3.4", "product", "YubiKey OTP+FIDO+CCID\n");
+        fake.set_attr("1-4.3.4", "serial", "0001234\n");
+        fake.set_attr("1-4.3.4", "speed", "480\n");
+        fake.add_interface("1-4.3.4", 1, "0b");
+        fake.set_driver("1-4.3.4", Some("usb"));
+
+        let devices = fake.sysfs().list_devices().unwrap();
+        assert_eq!(devices.len(), 2);
+        let yubikey = &devices[0];
+        assert_eq!(yubikey.busid(), &BusId("1-4.3.4".to_string()));
+        assert_eq!(yubikey.usb_id(), UsbId::new(0x1050, 0x0407));
+        assert_eq!(yubikey.manufacturer.as_deref(), Some("Yubico"));
+        assert_eq!(yubikey.product.as_deref(), Some("YubiKey OTP+FIDO+CCID"));
+        assert_eq!(yubikey.serial.as_deref(), Some("0001234"));
+        assert_eq!(yubikey.driver.as_deref(), Some("usb"));
+        assert_eq!(yubikey.info.speed, UsbSpeed::High);
+        assert_eq!(yubikey.info.busnum, 1);
+        assert_eq!(yubikey.info.bcd_device, 0x0543);
+        assert_eq!(
+            yubikey
+                .info
+                .interfaces
+                .iter()
+                .map(|i| i.class)
+                .collect::<Vec<_>>(),
+            vec![0x03, 0x0b]
+        );
+        assert!(yubikey.info.path.ends_with("devices/usb1/1-4.3.4"));
+
+        let reader = &devices[1];
+        assert_eq!(reader.busid(), &BusId("1-11".to_string()));
+        assert_eq!(reader.manufacturer, None);
+        assert_eq!(reader.driver, None);
+    }
+
+    #[test]
+    fn test_list_devices_skips_hubs_and_interfaces() {
+        let fake = FakeSysfs::new();
+        fake.add_device("1-1", "058f", "9540");
+        fake.add_device("1-4", "0bda", "5411");
+        fake.set_attr("1-4", "bDeviceClass", "09\n");
+        fake.link("usb1");
+
+        let devices = fake.sysfs().list_devices().unwrap();
+        assert_eq!(
+            devices
+                .iter()
+                .map(|d| d.busid().0.as_str())
+                .collect::<Vec<_>>(),
+            vec!["1-1"]
+        );
+    }
+
+    #[test]
+    fn test_list_devices_skips_unreadable() {
+        let fake = FakeSysfs::new();
+        for busid in ["1-10", "1-2", "2-1", "1-2.1"] {
+            fake.add_device(busid, "058f", "9540");
+        }
+        // unplugged while listing
+        fs::remove_file(fake.root().join("devices/usb1/2-1/idVendor")).unwrap();
+
+        let devices = fake.sysfs().list_devices().unwrap();
+        assert_eq!(
+            devices
+                .iter()
+                .map(|d| d.busid().0.as_str())
+                .collect::<Vec<_>>(),
+            vec!["1-2", "1-2.1", "1-10"]
+        );
+    }
+
+    #[test]
+    fn test_missing_device() {
+        let fake = FakeSysfs::new();
+        assert!(fake.sysfs().device(&BusId("1-1".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_missing_sysfs() {
+        assert!(Sysfs::new("/does/not/exist").list_devices().is_err());
+    }
+}
@@ -0,0 +1,252 @@
+//! Integration with the systemd service manager.
+//!
+//! Implements the receiving side of socket activation, see `sd_listen_fds(3)`,
+//! and the readiness notifications, see `sd_notify(3)`.
+use std::env;
+use std::ffi::OsStr;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+use anyhow::{anyhow, Context};
+use log::{debug, warn};
+
+/// The first file descriptor that is passed by systemd
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Return the number of file descriptors that were passed to the process
+/// with the given PID, given the values of `LISTEN_PID` and `LISTEN_FDS`.
+fn parse_listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> anyhow::Result<RawFd> {
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(0);
+    };
+    let listen_pid = listen_pid
+        .parse::<u32>()
+        .with_context(|| format!("Invalid LISTEN_PID `{listen_pid}`"))?;
+    if listen_pid != pid {
+        // the variables were meant for a different process, e.g., our parent
+        return Ok(0);
+    }
+    listen_fds
+        .parse::<RawFd>()
+        .with_context(|| format!("Invalid LISTEN_FDS `{listen_fds}`"))
+}
+
+/// Take ownership of `count` listening sockets that start at the file descriptor `start`
+fn take_listeners(start: RawFd, count: RawFd) -> anyhow::Result<Vec<TcpListener>> {
+    (start..start + count)
+        .map(|fd| {
+            // The passed descriptors must not leak into child processes like `usbipd`
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(anyhow!(
+                    "File descriptor {fd} was passed via LISTEN_FDS but is not open"
+                ));
+            }
+            // SAFETY: systemd passes the ownership of the descriptors to the process
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            listener.local_addr().with_context(|| {
+                format!("File descriptor {fd} that was passed via LISTEN_FDS is not a TCP socket")
+            })?;
+            Ok(listener)
+        })
+        .collect()
+}
+
+/// Take the listening sockets that were passed via socket activation.
+/// Returns an empty list if the process was not socket activated.
+///
+/// The environment variables are removed afterwards, so that they are
+/// not inherited by child processes.
+/// Must be called before any other thread is started.
+pub fn listen_fds() -> anyhow::Result<Vec<TcpListener>> {
+    let count = parse_listen_fds(
+        env::var("LISTEN_PID").ok().as_deref(),
+        env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    )?;
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    take_listeners(SD_LISTEN_FDS_START, count)
+}
+
+/// Sends state changes to the service manager.
+/// All notifications are silently dropped if the process was not started by systemd.
+#[derive(Debug)]
+pub struct Notifier {
+    target: Option<(UnixDatagram, SocketAddr)>,
+}
+
+impl Notifier {
+    /// Notify the socket from `NOTIFY_SOCKET`.
+    /// The variable is removed, so that child processes do not send conflicting states.
+    /// Must be called before any other thread is started.
+    pub fn from_env() -> Notifier {
+        let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+            return Notifier { target: None };
+        };
+        env::remove_var("NOTIFY_SOCKET");
+        Notifier::new(&path).unwrap_or_else(|e| {
+            warn!("Ignoring NOTIFY_SOCKET: {e:#}");
+            Notifier { target: None }
+        })
+    }
+
+    /// Notify the socket at the given path, a leading `@` refers to an abstract socket
+    pub fn new(path: &OsStr) -> anyhow::Result<Notifier> {
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name),
+            None => SocketAddr::from_pathname(path),
+        }
+        .with_context(|| format!("Invalid notification socket {path:?}"))?;
+        let socket = UnixDatagram::unbound()?;
+        Ok(Notifier {
+            target: Some((socket, addr)),
+        })
+    }
+
+    /// Send the newline separated assignments like `READY=1`.
+    /// Failures are only logged, as the service keeps working without the notifications.
+    pub fn notify(&self, state: &str) {
+        let Some((socket, addr)) = &self.target else {
+            return;
+        };
+        debug!("notifying systemd: {state}");
+        if let Err(e) = socket.send_to_addr(state.as_bytes(), addr) {
+            warn!("Could not notify systemd about `{state}`: {e}");
+        }
+    }
+
+    /// The service finished its start-up and accepts connections
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    /// Human readable status that is shown by `systemctl status`
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={}", status.replace('\n', " ")));
+    }
+
+    /// The service started to shut down
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::os::fd::IntoRawFd;
+    use std::time::Duration;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_fds() {
+        assert_eq!(parse_listen_fds(None, None, 42).unwrap(), 0);
+        assert_eq!(parse_listen_fds(Some("42"), None, 42).unwrap(), 0);
+        assert_eq!(parse_listen_fds(Some("42"), Some("2"), 42).unwrap(), 2);
+        assert_eq!(parse_listen_fds(Some("41"), Some("2"), 42).unwrap(), 0);
+        assert!(parse_listen_fds(Some("abc"), Some("1"), 42).is_err());
+        assert!(parse_listen_fds(Some("42"), Some("abc"), 42).is_err());
+    }
+
+    #[test]
+    fn test_take_listeners() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // a connection that arrives before the listener was handed over
+        let mut client = TcpStream::connect(addr).unwrap();
+
+        let fd = listener.into_raw_fd();
+        let listeners = take_listeners(fd, 1).unwrap();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].local_addr().unwrap(), addr);
+
+        let (mut stream, _) = listeners[0].accept().unwrap();
+        stream.write_all(b"ok").unwrap();
+        let mut buf = [0u8; 2];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ok");
+    }
+
+    #[test]
+    fn test_take_listeners_rejects_non_sockets() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let fd = file.into_raw_fd();
+        assert!(take_listeners(fd, 1).is_err());
+    }
+
+    /// A bound datagram socket that receives the notifications like systemd does
+    pub(crate) struct FakeNotifySocket {
+        _dir: TempDir,
+        socket: UnixDatagram,
+        pub path: std::path::PathBuf,
+    }
+
+    impl FakeNotifySocket {
+        pub fn new() -> Self {
+            let dir = TempDir::new().unwrap();
+            let path = dir.path().join("notify");
+            let socket = UnixDatagram::bind(&path).unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            FakeNotifySocket {
+                _dir: dir,
+                socket,
+                path,
+            }
+        }
+
+        pub fn notifier(&self) -> Notifier {
+            Notifier::new(self.path.as_os_str()).unwrap()
+        }
+
+        pub fn recv(&self) -> String {
+            let mut buf = [0u8; 4096];
+            let n = self.socket.recv(&mut buf).unwrap();
+            String::from_utf8(buf[..n].to_vec()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_notify() {
+        let fake = FakeNotifySocket::new();
+        let notifier = fake.notifier();
+        notifier.ready();
+        notifier.status("Exporting 1-1\nand more");
+        notifier.stopping();
+        assert_eq!(fake.recv(), "READY=1");
+        assert_eq!(fake.recv(), "STATUS=Exporting 1-1 and more");
+        assert_eq!(fake.recv(), "STOPPING=1");
+    }
+
+    #[test]
+    fn test_notify_abstract_socket() {
+        let name = format!("usbip_wrapper_test_{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        Notifier::new(OsStr::new(&format!("@{name}")))
+            .unwrap()
+            .ready();
+        let mut buf = [0u8; 16];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+
+    #[test]
+    fn test_notify_without_socket() {
+        // must not fail if the process was not started by systemd
+        Notifier { target: None }.ready();
+    }
+}
@@ -0,0 +1,70 @@
+//! Names of the vendors and products from the [USB ID list](http://www.linux-usb.org/usb.ids).
+//!
+//! The list is vendored as `data/usb.ids` and embedded into the binary by `build.rs`,
+//! so no `usb.ids`/`hwdata` package has to be installed.
+use crate::{UsbId, UsbIdPattern};
+
+#[cfg(test)]
+mod parse;
+
+include!(concat!(env!("OUT_DIR"), "/usb_ids.rs"));
+
+/// The name of the vendor, e.g., `Yubico.com` for `1050`
+pub fn vendor_name(vendor: u16) -> Option<&'static str> {
+    VENDORS
+        .binary_search_by_key(&vendor, |(v, _)| *v)
+        .ok()
+        .map(|i| VENDORS[i].1)
+}
+
+/// The name of the product, e.g., `Yubikey 4/5 OTP+U2F+CCID` for `1050:0407`
+pub fn product_name(usb_id: UsbId) -> Option<&'static str> {
+    PRODUCTS
+        .binary_search_by_key(&(usb_id.vendor, usb_id.product), |(v, p, _)| (*v, *p))
+        .ok()
+        .map(|i| PRODUCTS[i].2)
+}
+
+/// Whether the embedded list is the full list and not a trimmed copy.
+/// Only the full list knows enough ids to tell a typo from a device that is missing in the list.
+pub fn is_complete() -> bool {
+    COMPLETE
+}
+
+/// Whether the list knows the id, only the vendor has to be known for `VVVV:*`.
+/// Unknown ids are not necessarily wrong but are often a typo.
+pub fn is_known(pattern: &UsbIdPattern) -> bool {
+    match pattern.product {
+        Some(product) => product_name(UsbId::new(pattern.vendor, product)).is_some(),
+        None => vendor_name(pattern.vendor).is_some(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(vendor_name(0x1050), Some("Yubico.com"));
+        assert_eq!(
+            product_name(UsbId::new(0x1050, 0x0407)),
+            Some("Yubikey 4/5 OTP+U2F+CCID")
+        );
+        // the product is unknown but the vendor is not
+        assert_eq!(product_name(UsbId::new(0x0bda, 0x402e)), None);
+        assert_eq!(vendor_name(0x0bda), Some("Realtek Semiconductor Corp."));
+        // the class section is not mistaken for vendors
+        assert_eq!(vendor_name(0x0000), None);
+        assert!(VENDORS.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn test_is_known() {
+        let known = |s: &str| is_known(&s.parse().unwrap());
+        assert!(known("1050:0407"));
+        assert!(known("1050:*"));
+        assert!(!known("1050:4070"));
+        assert!(!known("1500:*"));
+    }
+}
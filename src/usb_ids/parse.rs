@@ -0,0 +1,129 @@
+//! Parses the USB ID list, shared by `build.rs` and the tests of the lookup.
+
+/// The vendors as `(vendor, name)` and the products as `(vendor, product, name)`,
+/// both sorted and without duplicate ids
+pub type Tables<'a> = (Vec<(u16, &'a str)>, Vec<(u16, u16, &'a str)>);
+
+/// Split `XXXX  name` into the hexadecimal id and the name
+fn parse_entry(line: &str) -> Option<(u16, &str)> {
+    let (id, name) = line.split_once("  ")?;
+    if id.len() != 4 {
+        return None;
+    }
+    Some((u16::from_str_radix(id, 16).ok()?, name.trim()))
+}
+
+/// Whether it is the full list, which starts with the date of its release, e.g., `# Version: 2023.01.16`
+pub fn is_complete(list: &str) -> bool {
+    list.lines()
+        .take_while(|l| l.starts_with('#'))
+        .any(|l| l.starts_with("# Version:"))
+}
+
+/// Collect the vendors and their products, the other sections of the list are skipped
+pub fn parse(list: &str) -> Tables<'_> {
+    let mut vendors = Vec::new();
+    let mut products = Vec::new();
+    let mut vendor = None;
+    for line in list.lines() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        if let Some(product_line) = line.strip_prefix('\t') {
+            // interfaces are indented by two tabs
+            if product_line.starts_with('\t') {
+                continue;
+            }
+            if let (Some(v), Some((p, name))) = (vendor, parse_entry(product_line)) {
+                products.push((v, p, name));
+            }
+            continue;
+        }
+        // a vendor, or the start of one of the sections after the vendors, e.g., `C 00  ...`
+        vendor = parse_entry(line).map(|(v, name)| {
+            vendors.push((v, name));
+            v
+        });
+    }
+    vendors.sort();
+    vendors.dedup_by_key(|(v, _)| *v);
+    products.sort();
+    products.dedup_by_key(|(v, p, _)| (*v, *p));
+    (vendors, products)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One entry of every section of the upstream list, in its order
+    const SECTIONS: &str = "\
+# Syntax:
+# vendor  vendor_name
+#\tdevice  device_name\t\t\t\t<-- single tab
+#\t\tinterface  interface_name\t\t<-- two tabs
+
+1050  Yubico.com
+\t0407  Yubikey 4/5 OTP+U2F+CCID
+8087  Intel Corp.
+\t0029  AX200 Bluetooth
+\t\t00  Interface
+
+C 03  Human Interface Device
+\t01  Boot Interface Subclass
+\t\t01  Keyboard
+AT 0201  Microphone
+HID 21  HID
+R 04  Usage Page
+BIAS 1  Right Hand
+PHY 01  Hand
+HUT 01  Generic Desktop Controls
+\t002  Mouse
+L 0009  English
+\t01  US
+HCC 01  Arabic
+VT 0101  USB Streaming
+";
+
+    #[test]
+    fn test_parse_sections() {
+        let (vendors, products) = parse(SECTIONS);
+        assert_eq!(vendors, [(0x1050, "Yubico.com"), (0x8087, "Intel Corp.")]);
+        assert_eq!(
+            products,
+            [
+                (0x1050, 0x0407, "Yubikey 4/5 OTP+U2F+CCID"),
+                (0x8087, 0x0029, "AX200 Bluetooth"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_complete() {
+        assert!(is_complete(
+            "#\n# Version: 2023.01.16\n# Date:    2023-01-16 20:34:02\n"
+        ));
+        assert!(!is_complete(SECTIONS));
+        assert!(!is_complete(include_str!("../../data/usb.ids")));
+    }
+
+    #[test]
+    fn test_parse_vendored_list() {
+        let list = include_str!("../../data/usb.ids");
+        let (vendors, products) = parse(list);
+        let vendor_lines = list
+            .lines()
+            .filter(|l| {
+                l.get(..4)
+                    .is_some_and(|id| id.chars().all(|c| c.is_ascii_hexdigit()))
+                    && l[4..].starts_with("  ")
+            })
+            .count();
+        assert_eq!(vendors.len(), vendor_lines);
+        assert!(!products.is_empty());
+        // every product belongs to a vendor
+        assert!(products
+            .iter()
+            .all(|(v, _, _)| vendors.binary_search_by_key(v, |(v, _)| *v).is_ok()));
+    }
+}
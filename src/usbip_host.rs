@@ -0,0 +1,265 @@
+//! Export local USB devices by (un)binding them to the `usbip-host` driver.
+//!
+//! This re-implements the sysfs steps of `usbip bind` and `usbip unbind`.
+//! Whether a device is already (un)bound is decided by the `driver` symlink
+//! of the device, which makes both operations idempotent.
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use log::debug;
+
+use crate::sysfs::{Sysfs, USB_CLASS_HUB};
+use crate::{BusId, Error};
+
+/// Name of the kernel driver that exports the devices
+pub const USBIP_HOST_DRIVER: &str = "usbip-host";
+
+/// Whether an operation had to change the state of the device or
+/// if the device was already in the requested state
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum BindOutcome {
+    Changed,
+    Unchanged,
+}
+
+/// Export state of a device bound to `usbip-host`,
+/// see the `usbip_status` attribute and `enum stub_device_status` of the kernel
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ExportStatus {
+    /// Ready to be imported by a client
+    Available,
+    /// Currently imported by a client
+    Used,
+    Error,
+    Unknown(u32),
+}
+
+impl From<u32> for ExportStatus {
+    fn from(v: u32) -> Self {
+        match v {
+            1 => ExportStatus::Available,
+            2 => ExportStatus::Used,
+            3 => ExportStatus::Error,
+            v => ExportStatus::Unknown(v),
+        }
+    }
+}
+
+/// Read the export state of a device that is bound to `usbip-host`
+pub fn export_status(sysfs: &Sysfs, busid: &BusId) -> anyhow::Result<ExportStatus> {
+    let path = sysfs.device_dir(busid).join("usbip_status");
+    let value =
+        fs::read_to_string(&path).with_context(|| format!("Could not read {}", path.display()))?;
+    Ok(ExportStatus::from(value.trim().parse::<u32>()?))
+}
+
+/// Hand the connected socket of a client to the kernel, which
+/// takes over the URB traffic of the exported device
+pub fn export(sysfs: &Sysfs, busid: &BusId, sockfd: i32) -> anyhow::Result<()> {
+    write_attr(
+        &sysfs.device_dir(busid).join("usbip_sockfd"),
+        &sockfd.to_string(),
+    )
+}
+
+/// Write the value to a sysfs attribute
+fn write_attr(path: &Path, value: &str) -> anyhow::Result<()> {
+    debug!("writing `{value}` to {}", path.display());
+    fs::write(path, value).with_context(|| {
+        format!(
+            "Could not write to {}. Are you running with root privileges?",
+            path.display()
+        )
+    })
+}
+
+/// The `match_busid` attribute restricts the devices that `usbip-host` is allowed to probe
+fn modify_match_busid(sysfs: &Sysfs, busid: &BusId, add: bool) -> anyhow::Result<()> {
+    let op = if add { "add" } else { "del" };
+    write_attr(
+        &sysfs.driver_dir(USBIP_HOST_DRIVER).join("match_busid"),
+        &format!("{op} {busid}"),
+    )
+}
+
+/// Unbind the device from its current driver and bind it to `usbip-host`
+pub fn bind(sysfs: &Sysfs, busid: &BusId) -> anyhow::Result<BindOutcome> {
+    let device = sysfs.device(busid)?;
+    if device.info.device_class == USB_CLASS_HUB {
+        return Err(anyhow!("{busid} is a USB hub and cannot be exported"));
+    }
+    if device.driver.as_deref() == Some(USBIP_HOST_DRIVER) {
+        debug!("{busid} is already bound to {USBIP_HOST_DRIVER}");
+        return Ok(BindOutcome::Unchanged);
+    }
+    let host_driver = sysfs.driver_dir(USBIP_HOST_DRIVER);
+    if !host_driver.exists() {
+        return Err(Error::MissingKernelModule {
+            module: "usbip_host",
+        }
+        .into());
+    }
+
+    if let Some(driver) = &device.driver {
+        write_attr(&sysfs.driver_dir(driver).join("unbind"), &busid.0)?;
+    }
+    let bound = modify_match_busid(sysfs, busid, true)
+        .and_then(|()| write_attr(&host_driver.join("bind"), &busid.0));
+    if let Err(e) = bound {
+        // Do not leave the busid behind, otherwise usbip-host would grab
+        // the device the next time it is plugged in
+        let reverted = modify_match_busid(sysfs, busid, false);
+        // and do not leave the device without any driver
+        if let Some(driver) = &device.driver {
+            write_attr(&sysfs.driver_dir(driver).join("bind"), &busid.0)?;
+        }
+        reverted?;
+        return Err(e);
+    }
+    Ok(BindOutcome::Changed)
+}
+
+/// Unbind the device from `usbip-host` and let the kernel probe
+/// the original driver again
+pub fn unbind(sysfs: &Sysfs, busid: &BusId) -> anyhow::Result<BindOutcome> {
+    let device = sysfs.device(busid)?;
+    if device.driver.as_deref() != Some(USBIP_HOST_DRIVER) {
+        debug!("{busid} is not bound to {USBIP_HOST_DRIVER}");
+        return Ok(BindOutcome::Unchanged);
+    }
+    let host_driver = sysfs.driver_dir(USBIP_HOST_DRIVER);
+    write_attr(&host_driver.join("unbind"), &busid.0)?;
+    modify_match_busid(sysfs, busid, false)?;
+    write_attr(&host_driver.join("rebind"), &busid.0)?;
+    Ok(BindOutcome::Changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysfs::tests::FakeSysfs;
+
+    fn busid() -> BusId {
+        BusId("1-1".to_string())
+    }
+
+    fn read(fake: &FakeSysfs, path: &str) -> Option<String> {
+        fs::read_to_string(fake.root().join(path)).ok()
+    }
+
+    fn fake_with_device(driver: Option<&str>) -> FakeSysfs {
+        let fake = FakeSysfs::new();
+        fake.add_device("1-1", "1050", "0407");
+        fake.add_driver(USBIP_HOST_DRIVER);
+        fake.set_driver("1-1", driver);
+        fake
+    }
+
+    #[test]
+    fn test_bind() {
+        let fake = fake_with_device(Some("usb"));
+        assert_eq!(bind(&fake.sysfs(), &busid()).unwrap(), BindOutcome::Changed);
+        assert_eq!(read(&fake, "bus/usb/drivers/usb/unbind").unwrap(), "1-1");
+        assert_eq!(
+            read(&fake, "bus/usb/drivers/usbip-host/match_busid").unwrap(),
+            "add 1-1"
+        );
+        assert_eq!(
+            read(&fake, "bus/usb/drivers/usbip-host/bind").unwrap(),
+            "1-1"
+        );
+    }
+
+    #[test]
+    fn test_bind_without_driver() {
+        let fake = fake_with_device(None);
+        assert_eq!(bind(&fake.sysfs(), &busid()).unwrap(), BindOutcome::Changed);
+        assert_eq!(
+            read(&fake, "bus/usb/drivers/usbip-host/bind").unwrap(),
+            "1-1"
+        );
+    }
+
+    #[test]
+    fn test_bind_is_idempotent() {
+        let fake = fake_with_device(Some(USBIP_HOST_DRIVER));
+        assert_eq!(
+            bind(&fake.sysfs(), &busid()).unwrap(),
+            BindOutcome::Unchanged
+        );
+        assert_eq!(read(&fake, "bus/usb/drivers/usbip-host/match_busid"), None);
+        assert_eq!(read(&fake, "bus/usb/drivers/usbip-host/bind"), None);
+    }
+
+    #[test]
+    fn test_bind_requires_module() {
+        let fake = FakeSysfs::new();
+        fake.add_device("1-1", "1050", "0407");
+        fake.set_driver("1-1", Some("usb"));
+        let err = bind(&fake.sysfs(), &busid()).unwrap_err();
+        assert!(err.to_string().contains("usbip_host"));
+        // nothing must have been touched
+        assert_eq!(read(&fake, "bus/usb/drivers/usb/unbind"), None);
+    }
+
+    #[test]
+    fn test_bind_failure_restores_driver() {
+        let fake = fake_with_device(Some("usb"));
+        // writing to a directory fails like a refused bind
+        fs::create_dir(fake.root().join("bus/usb/drivers/usbip-host/bind")).unwrap();
+        assert!(bind(&fake.sysfs(), &busid()).is_err());
+        assert_eq!(read(&fake, "bus/usb/drivers/usb/unbind").unwrap(), "1-1");
+        assert_eq!(
+            read(&fake, "bus/usb/drivers/usbip-host/match_busid").unwrap(),
+            "del 1-1"
+        );
+        assert_eq!(read(&fake, "bus/usb/drivers/usb/bind").unwrap(), "1-1");
+    }
+
+    #[test]
+    fn test_bind_rejects_hubs() {
+        let fake = fake_with_device(Some("hub"));
+        fake.set_attr("1-1", "bDeviceClass", "09");
+        assert!(bind(&fake.sysfs(), &busid()).is_err());
+    }
+
+    #[test]
+    fn test_bind_missing_device() {
+        let fake = fake_with_device(None);
+        assert!(bind(&fake.sysfs(), &BusId("1-2".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_unbind() {
+        let fake = fake_with_device(Some(USBIP_HOST_DRIVER));
+        assert_eq!(
+            unbind(&fake.sysfs(), &busid()).unwrap(),
+            BindOutcome::Changed
+        );
+        assert_eq!(
+            read(&fake, "bus/usb/drivers/usbip-host/unbind").unwrap(),
+            "1-1"
+        );
+        assert_eq!(
+            read(&fake, "bus/usb/drivers/usbip-host/match_busid").unwrap(),
+            "del 1-1"
+        );
+        assert_eq!(
+            read(&fake, "bus/usb/drivers/usbip-host/rebind").unwrap(),
+            "1-1"
+        );
+    }
+
+    #[test]
+    fn test_unbind_is_idempotent() {
+        for driver in [None, Some("usb")] {
+            let fake = fake_with_device(driver);
+            assert_eq!(
+                unbind(&fake.sysfs(), &busid()).unwrap(),
+                BindOutcome::Unchanged
+            );
+            assert_eq!(read(&fake, "bus/usb/drivers/usbip-host/unbind"), None);
+        }
+    }
+}
@@ -0,0 +1,603 @@
+//! Import remote devices through the sysfs interface of the `vhci_hcd` driver.
+//!
+//! This re-implements `usbip attach`: the import handshake is done in userspace
+//! and the connected socket is then handed to a free port of the virtual host controller.
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context};
+use log::{debug, warn};
+use thiserror::Error;
+
+use crate::protocol::{self, ImportReply, UsbSpeed, ST_DEV_BUSY, ST_NA, ST_NODEV};
+use crate::sysfs::Sysfs;
+use crate::{serial_matches, BusId};
+
+/// Name of the platform device of the first virtual host controller,
+/// which provides the `attach`, `detach` and `status` attributes
+const VHCI_CONTROLLER: &str = "vhci_hcd.0";
+
+/// Directory in which `usbip attach` records the remote end of every port
+pub const VHCI_STATE_PATH: &str = "/var/run/vhci_hcd";
+
+/// Reasons why a remote device could not be attached
+#[derive(Debug, Error)]
+pub enum AttachError {
+    #[error(
+        "The vhci_hcd driver is not available.\n  Please enable the `vhci-hcd` kernel module."
+    )]
+    MissingDriver,
+    #[error("{0} is not exported by the usbip host")]
+    NoSuchDevice(BusId),
+    #[error("{0} is already imported by a different client")]
+    DeviceBusy(BusId),
+    #[error("The usbip host refused to export {busid} with status {status}")]
+    ImportRefused { busid: BusId, status: u32 },
+    #[error("{0} does not have any of the requested serial numbers")]
+    SerialMismatch(BusId),
+    #[error("There is no free vhci port for a device with {0}")]
+    NoFreePort(UsbSpeed),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// The root hub a vhci port belongs to
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum HubSpeed {
+    /// USB 2.0 hub, used for every device up to high speed
+    High,
+    /// USB 3.0 hub, used for super speed devices
+    Super,
+}
+
+/// State of a vhci port, see `enum usbip_device_status` of the kernel
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum PortStatus {
+    Free,
+    NotAssigned,
+    Used,
+    Error,
+    Unknown(u32),
+}
+
+impl From<u32> for PortStatus {
+    fn from(v: u32) -> Self {
+        match v {
+            4 => PortStatus::Free,
+            5 => PortStatus::NotAssigned,
+            6 => PortStatus::Used,
+            7 => PortStatus::Error,
+            v => PortStatus::Unknown(v),
+        }
+    }
+}
+
+/// The remote end of an attached port as recorded by `usbip attach`
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct RemoteConnection {
+    pub host: String,
+    pub tcp_port: String,
+    pub busid: BusId,
+}
+
+impl RemoteConnection {
+    fn parse(content: &str) -> Option<Self> {
+        let mut fields = content.split_whitespace();
+        Some(RemoteConnection {
+            host: fields.next()?.to_string(),
+            tcp_port: fields.next()?.to_string(),
+            busid: BusId(fields.next()?.to_string()),
+        })
+    }
+}
+
+/// A single port of the virtual host controller
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct VhciPort {
+    pub hub: HubSpeed,
+    pub port: u32,
+    pub status: PortStatus,
+    pub speed: UsbSpeed,
+    /// `busnum << 16 | devnum` of the device on the usbip host
+    pub devid: u32,
+    /// File descriptor of the socket in the process that attached the device.
+    /// Kernels before 4.14 print the kernel address of the socket instead,
+    /// which is not exposed.
+    pub sockfd: Option<u32>,
+    /// busid of the attached device on the local machine
+    pub local_busid: Option<BusId>,
+    /// Only known if the port was attached by `usbip attach` or this tool
+    pub remote: Option<RemoteConnection>,
+}
+
+/// Parse the content of a vhci `status` attribute.
+///
+/// The layout changed between kernel versions, so the columns are
+/// identified by the header line:
+/// - `prt sta spd bus dev socket local_busid` until 4.12,
+///   where used ports print `bus dev` as a single devid
+/// - `hub port sta spd dev socket local_busid` in 4.13, which added USB 3.0 support
+/// - `hub port sta spd dev sockfd local_busid` since 4.14
+fn parse_status(content: &str) -> anyhow::Result<Vec<VhciPort>> {
+    let mut lines = content.lines().filter(|l| !l.trim().is_empty());
+    let Some(header) = lines.next() else {
+        return Ok(Vec::new());
+    };
+    let all_columns = header
+        .split_whitespace()
+        .map(|c| match c {
+            "prt" => "port",
+            c => c,
+        })
+        .collect::<Vec<_>>();
+    let merged_columns = all_columns
+        .iter()
+        .copied()
+        .filter(|c| *c != "bus")
+        .collect::<Vec<_>>();
+    lines
+        .map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let columns = match fields.len() {
+                n if n == all_columns.len() => &all_columns,
+                n if n == merged_columns.len() => &merged_columns,
+                _ => return Err(anyhow!("Unexpected line in vhci status: `{line}`")),
+            };
+            let field = |name: &str| columns.iter().position(|c| *c == name).map(|i| fields[i]);
+            let required = |name: &str| {
+                field(name).ok_or_else(|| anyhow!("vhci status has no `{name}` column"))
+            };
+            Ok(VhciPort {
+                hub: match field("hub") {
+                    // older kernels only provide a USB 2.0 hub
+                    None | Some("hs") => HubSpeed::High,
+                    Some("ss") => HubSpeed::Super,
+                    Some(hub) => return Err(anyhow!("Unknown hub type `{hub}` in vhci status")),
+                },
+                port: required("port")?.parse()?,
+                status: PortStatus::from(required("sta")?.parse::<u32>()?),
+                speed: UsbSpeed::from(required("spd")?.parse::<u32>()?),
+                devid: u32::from_str_radix(required("dev")?, 16)?,
+                sockfd: field("sockfd").map(|fd| fd.parse()).transpose()?,
+                local_busid: match required("local_busid")? {
+                    "0-0" => None,
+                    b => Some(BusId(b.to_string())),
+                },
+                remote: None,
+            })
+        })
+        .collect()
+}
+
+/// Entry point to the virtual host controller
+#[derive(Debug, Clone)]
+pub struct Vhci {
+    sysfs: Sysfs,
+    state_dir: PathBuf,
+}
+
+impl Vhci {
+    pub fn new(sysfs: Sysfs, state_dir: impl Into<PathBuf>) -> Self {
+        Vhci {
+            sysfs,
+            state_dir: state_dir.into(),
+        }
+    }
+
+    fn controller_dir(&self) -> Result<PathBuf, AttachError> {
+        let dir = self.sysfs.platform_device_dir(VHCI_CONTROLLER);
+        if !dir.exists() {
+            return Err(AttachError::MissingDriver);
+        }
+        Ok(dir)
+    }
+
+    /// Read the state of all ports of every controller instance.
+    /// All instances are described by the first controller, as `status`
+    /// for the first one and `status.<N>` for the following ones.
+    pub fn ports(&self) -> Result<Vec<VhciPort>, AttachError> {
+        let dir = self.controller_dir()?;
+        let mut status_files = fs::read_dir(&dir)
+            .with_context(|| format!("Could not read {}", dir.display()))?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .filter_map(|name| match name.as_str() {
+                "status" => Some((0, name)),
+                _ => name
+                    .strip_prefix("status.")
+                    .and_then(|n| n.parse::<u32>().ok())
+                    .map(|n| (n, name)),
+            })
+            .collect::<Vec<_>>();
+        status_files.sort();
+
+        let mut ports = Vec::new();
+        for (_, name) in status_files {
+            let path = dir.join(name);
+            let content = fs::read_to_string(&path)
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            ports.extend(parse_status(&content)?);
+        }
+        for p in ports.iter_mut().filter(|p| p.status == PortStatus::Used) {
+            p.remote = fs::read_to_string(self.state_dir.join(format!("port{}", p.port)))
+                .ok()
+                .and_then(|c| RemoteConnection::parse(&c));
+        }
+        Ok(ports)
+    }
+
+    /// All ports that currently have a remote device attached
+    pub fn used_ports(&self) -> Result<Vec<VhciPort>, AttachError> {
+        Ok(self
+            .ports()?
+            .into_iter()
+            .filter(|p| p.status == PortStatus::Used)
+            .collect())
+    }
+
+    /// Import the device with the given busid from the usbip host and
+    /// attach it to a free port. Returns the used port.
+    /// If serial numbers are given, the device is only attached if it has one of them.
+    pub fn attach(
+        &self,
+        host: &str,
+        tcp_port: u16,
+        busid: &BusId,
+        serials: &[&str],
+    ) -> Result<u32, AttachError> {
+        let attach_path = self.controller_dir()?.join("attach");
+        let mut stream = protocol::connect(host, tcp_port)?;
+        let device = match protocol::request_import(&mut stream, busid)? {
+            ImportReply::Accepted(device) => device,
+            ImportReply::Refused(ST_NA | ST_NODEV) => {
+                return Err(AttachError::NoSuchDevice(busid.clone()))
+            }
+            ImportReply::Refused(ST_DEV_BUSY) => {
+                return Err(AttachError::DeviceBusy(busid.clone()))
+            }
+            ImportReply::Refused(status) => {
+                return Err(AttachError::ImportRefused {
+                    busid: busid.clone(),
+                    status,
+                })
+            }
+        };
+        if !serials.is_empty() {
+            let serial = protocol::request_serial(&mut stream, &device)?;
+            debug!("{busid} has the serial number {serial:?}");
+            // closing the stream releases the device on the usbip host again
+            if !serial_matches(serials, serial.as_deref()) {
+                return Err(AttachError::SerialMismatch(busid.clone()));
+            }
+        }
+
+        let hub = match device.speed {
+            UsbSpeed::Super | UsbSpeed::SuperPlus => HubSpeed::Super,
+            _ => HubSpeed::High,
+        };
+        let devid = device.busnum << 16 | device.devnum;
+        let free_ports = self
+            .ports()?
+            .into_iter()
+            .filter(|p| p.hub == hub && p.status == PortStatus::Free);
+        for p in free_ports {
+            let request = format!(
+                "{} {} {devid} {}",
+                p.port,
+                stream.as_raw_fd(),
+                u32::from(device.speed)
+            );
+            debug!("writing `{request}` to {}", attach_path.display());
+            // The kernel takes its own reference to the socket,
+            // so the stream can be closed after the write.
+            let res = OpenOptions::new()
+                .write(true)
+                .open(&attach_path)
+                .and_then(|mut f| f.write_all(request.as_bytes()));
+            match res {
+                Ok(()) => {
+                    self.record_connection(p.port, host, tcp_port, busid);
+                    return Ok(p.port);
+                }
+                // the port was taken in the meantime, try the next one
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => continue,
+                Err(e) => {
+                    return Err(anyhow!(e)
+                        .context(format!(
+                            "Could not write to {}. Are you running with root privileges?",
+                            attach_path.display()
+                        ))
+                        .into())
+                }
+            }
+        }
+        Err(AttachError::NoFreePort(device.speed))
+    }
+
+    /// Detach the device from the given port and forget its remote end
+    pub fn detach(&self, port: u32) -> Result<(), AttachError> {
+        let detach_path = self.controller_dir()?.join("detach");
+        debug!("writing `{port}` to {}", detach_path.display());
+        fs::write(&detach_path, port.to_string()).with_context(|| {
+            format!(
+                "Could not write to {}. Are you running with root privileges?",
+                detach_path.display()
+            )
+        })?;
+        let record = self.state_dir.join(format!("port{port}"));
+        if let Err(e) = fs::remove_file(&record) {
+            if e.kind() != ErrorKind::NotFound {
+                warn!("Could not remove {}: {e}", record.display());
+            }
+        }
+        Ok(())
+    }
+
+    /// Remember the remote end of the port like `usbip attach` does,
+    /// so that `usbip port` can show it.
+    /// This is purely informational, so failures are only logged.
+    fn record_connection(&self, port: u32, host: &str, tcp_port: u16, busid: &BusId) {
+        let path = self.state_dir.join(format!("port{port}"));
+        let res = fs::create_dir_all(&self.state_dir)
+            .and_then(|_| fs::write(&path, format!("{host} {tcp_port} {busid}\n")));
+        if let Err(e) = res {
+            warn!("Could not record connection in {}: {e}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::protocol::tests::{fake_import_host, fake_serial_host};
+    use crate::protocol::ST_OK;
+    use crate::sysfs::tests::FakeSysfs;
+
+    const STATUS: &str = "\
+hub port sta spd dev      sockfd local_busid
+hs  0000 006 002 00010002 000003 3-1
+hs  0001 004 000 00000000 000000 0-0
+ss  0002 004 000 00000000 000000 0-0
+ss  0003 004 000 00000000 000000 0-0
+";
+
+    fn setup(status: &str) -> (FakeSysfs, TempDir, Vhci) {
+        let fake = FakeSysfs::new();
+        fake.set_platform_attr(VHCI_CONTROLLER, "status", status);
+        fake.set_platform_attr(VHCI_CONTROLLER, "attach", "");
+        let state_dir = TempDir::new().unwrap();
+        let vhci = Vhci::new(fake.sysfs(), state_dir.path());
+        (fake, state_dir, vhci)
+    }
+
+    /// Laid out like `status_show` in drivers/usb/usbip/vhci_sysfs.c of 4.9,
+    /// before USB 3.0 support was added
+    const STATUS_4_9: &str = "\
+prt sta spd bus dev socket           local_busid
+000 006 002 00010007 ffff8800b9c6e3c0 3-1
+001 004 000 000 000 0000000000000000 0-0
+002 004 000 000 000 0000000000000000 0-0
+";
+
+    /// Laid out like `port_show_vhci` of 4.13, which printed the kernel address of the socket
+    const STATUS_4_13: &str = "\
+hub port sta spd dev      socket           local_busid
+hs  0000 004 000 00000000 0000000000000000 0-0
+hs  0001 006 003 0001000b ffff9a41f6a5c000 3-2
+ss  0002 004 000 00000000 0000000000000000 0-0
+ss  0003 006 005 00020003 ffff9a41f6a5d400 4-1
+";
+
+    /// Laid out like 6.1 with `vhci_hcd.num_controllers=2`,
+    /// the ports of the second controller are numbered consecutively
+    const STATUS_6_1: &str = "\
+hub port sta spd dev      sockfd local_busid
+hs  0000 006 002 00010007 000004 3-1
+hs  0001 004 000 00000000 000000 0-0
+ss  0002 004 000 00000000 000000 0-0
+ss  0003 004 000 00000000 000000 0-0
+";
+    const STATUS_6_1_SECOND: &str = "\
+hub port sta spd dev      sockfd local_busid
+hs  0004 004 000 00000000 000000 0-0
+hs  0005 006 003 00010004 000005 5-1.2
+ss  0006 004 000 00000000 000000 0-0
+ss  0007 004 000 00000000 000000 0-0
+";
+
+    #[test]
+    fn test_parse_status() {
+        let ports = parse_status(STATUS).unwrap();
+        assert_eq!(ports.len(), 4);
+        assert_eq!(
+            ports[0],
+            VhciPort {
+                hub: HubSpeed::High,
+                port: 0,
+                status: PortStatus::Used,
+                speed: UsbSpeed::Full,
+                devid: 0x00010002,
+                sockfd: Some(3),
+                local_busid: Some(BusId("3-1".to_string())),
+                remote: None,
+            }
+        );
+        assert_eq!(ports[3].hub, HubSpeed::Super);
+        assert_eq!(ports[3].status, PortStatus::Free);
+        assert_eq!(ports[3].local_busid, None);
+    }
+
+    #[test]
+    fn test_parse_status_4_9() {
+        let ports = parse_status(STATUS_4_9).unwrap();
+        assert_eq!(ports.len(), 3);
+        assert!(ports.iter().all(|p| p.hub == HubSpeed::High));
+        assert_eq!(ports[0].status, PortStatus::Used);
+        assert_eq!(ports[0].devid, 0x00010007);
+        assert_eq!(ports[0].sockfd, None);
+        assert_eq!(ports[0].local_busid, Some(BusId("3-1".to_string())));
+        assert_eq!(ports[2].port, 2);
+        assert_eq!(ports[2].status, PortStatus::Free);
+    }
+
+    #[test]
+    fn test_parse_status_4_13() {
+        let ports = parse_status(STATUS_4_13).unwrap();
+        assert_eq!(ports.len(), 4);
+        assert_eq!(ports[1].hub, HubSpeed::High);
+        assert_eq!(ports[1].speed, UsbSpeed::High);
+        assert_eq!(ports[1].sockfd, None);
+        assert_eq!(ports[3].hub, HubSpeed::Super);
+        assert_eq!(ports[3].speed, UsbSpeed::Super);
+        assert_eq!(ports[3].local_busid, Some(BusId("4-1".to_string())));
+    }
+
+    #[test]
+    fn test_parse_status_invalid() {
+        assert!(parse_status("hub port sta spd dev sockfd local_busid\nhs 0000 004\n").is_err());
+        assert!(parse_status("hub port\nxs 0000\n").is_err());
+        // only the `bus` column of the old layout may be merged
+        assert!(parse_status("hub port sta spd dev\nhs 0000 004 000\n").is_err());
+        assert!(parse_status("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ports_of_all_controllers() {
+        let (fake, state_dir, vhci) = setup(STATUS_6_1);
+        fake.set_platform_attr(VHCI_CONTROLLER, "status.1", STATUS_6_1_SECOND);
+        fs::write(state_dir.path().join("port5"), "laptop 3240 1-7\n").unwrap();
+
+        let ports = vhci.ports().unwrap();
+        assert_eq!(
+            ports.iter().map(|p| p.port).collect::<Vec<_>>(),
+            (0..8).collect::<Vec<_>>()
+        );
+        let used = vhci.used_ports().unwrap();
+        assert_eq!(used.len(), 2);
+        assert_eq!(used[0].port, 0);
+        assert_eq!(used[0].remote, None);
+        assert_eq!(used[1].port, 5);
+        assert_eq!(used[1].sockfd, Some(5));
+        assert_eq!(used[1].local_busid, Some(BusId("5-1.2".to_string())));
+        assert_eq!(
+            used[1].remote,
+            Some(RemoteConnection {
+                host: "laptop".to_string(),
+                tcp_port: "3240".to_string(),
+                busid: BusId("1-7".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_attach() {
+        let (fake, state_dir, vhci) = setup(STATUS);
+        let (port, _handle) = fake_import_host("1-4.3.4", ST_OK);
+        let busid = BusId("1-4.3.4".to_string());
+        assert_eq!(vhci.attach("127.0.0.1", port, &busid, &[]).unwrap(), 1);
+
+        let request = fs::read_to_string(
+            fake.root()
+                .join("devices/platform")
+                .join(VHCI_CONTROLLER)
+                .join("attach"),
+        )
+        .unwrap();
+        let fields = request.split(' ').collect::<Vec<_>>();
+        // port, sockfd, devid and speed
+        assert_eq!(fields.len(), 4);
+        assert_eq!(fields[0], "1");
+        assert_eq!(fields[2], (1 << 16 | 7).to_string());
+        assert_eq!(fields[3], "2");
+        assert_eq!(
+            fs::read_to_string(state_dir.path().join("port1")).unwrap(),
+            format!("127.0.0.1 {port} 1-4.3.4\n")
+        );
+    }
+
+    #[test]
+    fn test_attach_serial() {
+        let (_fake, _state_dir, vhci) = setup(STATUS);
+        let busid = BusId("1-1".to_string());
+        let (port, _handle) = fake_serial_host("1-1", Some("CC1234"));
+        assert_eq!(
+            vhci.attach("127.0.0.1", port, &busid, &["CC1234"]).unwrap(),
+            1
+        );
+        for serial in [Some("CC5678"), None] {
+            let (port, handle) = fake_serial_host("1-1", serial);
+            assert!(matches!(
+                vhci.attach("127.0.0.1", port, &busid, &["CC1234"]),
+                Err(AttachError::SerialMismatch(_))
+            ));
+            // the connection is closed without attaching the device
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_attach_no_free_port() {
+        let (_fake, _state_dir, vhci) = setup(
+            "\
+hub port sta spd dev      sockfd local_busid
+hs  0000 006 002 00010002 000003 3-1
+ss  0001 004 000 00000000 000000 0-0
+",
+        );
+        let (port, _handle) = fake_import_host("1-1", ST_OK);
+        let err = vhci
+            .attach("127.0.0.1", port, &BusId("1-1".to_string()), &[])
+            .unwrap_err();
+        assert!(matches!(err, AttachError::NoFreePort(UsbSpeed::Full)));
+    }
+
+    #[test]
+    fn test_attach_refused() {
+        for (status, expected) in [
+            (ST_DEV_BUSY, "already imported"),
+            (ST_NODEV, "not exported"),
+            (5, "status 5"),
+        ] {
+            let (_fake, _state_dir, vhci) = setup(STATUS);
+            let (port, _handle) = fake_import_host("1-1", status);
+            let err = vhci
+                .attach("127.0.0.1", port, &BusId("1-1".to_string()), &[])
+                .unwrap_err();
+            assert!(err.to_string().contains(expected), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_detach() {
+        let (fake, state_dir, vhci) = setup(STATUS);
+        fs::write(state_dir.path().join("port0"), "laptop 3240 1-7\n").unwrap();
+        vhci.detach(0).unwrap();
+        assert_eq!(
+            fs::read_to_string(
+                fake.root()
+                    .join("devices/platform")
+                    .join(VHCI_CONTROLLER)
+                    .join("detach")
+            )
+            .unwrap(),
+            "0"
+        );
+        assert!(!state_dir.path().join("port0").exists());
+        // without a recorded connection
+        vhci.detach(1).unwrap();
+    }
+
+    #[test]
+    fn test_attach_missing_driver() {
+        let fake = FakeSysfs::new();
+        let vhci = Vhci::new(fake.sysfs(), fake.root().join("state"));
+        let err = vhci
+            .attach("127.0.0.1", 1, &BusId("1-1".to_string()), &[])
+            .unwrap_err();
+        assert!(matches!(err, AttachError::MissingDriver));
+    }
+}
@@ -0,0 +1,160 @@
+//! A scriptable fake `usbip`/`usbipd` that is put on PATH to test the shell-out path.
+//!
+//! Every command line is answered with recorded replies. If a command is called
+//! more often than replies were recorded, the last reply is repeated.
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+use tempfile::TempDir;
+
+/// The recorded output of a single call
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub stdout: String,
+    pub stderr: String,
+    pub status: i32,
+}
+
+impl Reply {
+    pub fn ok(stdout: &str) -> Self {
+        Reply {
+            stdout: stdout.to_string(),
+            stderr: String::new(),
+            status: 0,
+        }
+    }
+
+    /// `usbip` reports progress on stderr, even if the command succeeded
+    pub fn info(stderr: &str) -> Self {
+        Reply {
+            stdout: String::new(),
+            stderr: stderr.to_string(),
+            status: 0,
+        }
+    }
+
+    pub fn err(stderr: &str) -> Self {
+        Reply {
+            stdout: String::new(),
+            stderr: stderr.to_string(),
+            status: 1,
+        }
+    }
+}
+
+pub struct FakeUsbip {
+    dir: TempDir,
+    /// The replies for each program and command line
+    replies: BTreeMap<String, BTreeMap<String, Vec<Reply>>>,
+}
+
+impl FakeUsbip {
+    /// Neither `usbip` nor `usbipd` is installed
+    pub fn new() -> Self {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("bin")).unwrap();
+        fs::create_dir(dir.path().join("replies")).unwrap();
+        FakeUsbip {
+            dir,
+            replies: BTreeMap::new(),
+        }
+    }
+
+    /// A working `usbip` without any other recorded replies
+    pub fn usbip() -> Self {
+        let mut fake = FakeUsbip::new();
+        fake.reply("usbip version", [Reply::ok("usbip (usbip-utils 2.0)\n")]);
+        fake
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.dir.path().to_path_buf()
+    }
+
+    /// Answer the command line with the given replies, one per call
+    pub fn reply(
+        &mut self,
+        command_line: &str,
+        replies: impl IntoIterator<Item = Reply>,
+    ) -> &mut Self {
+        let program = command_line.split(' ').next().unwrap().to_string();
+        self.replies
+            .entry(program.clone())
+            .or_default()
+            .insert(command_line.to_string(), replies.into_iter().collect());
+        self.write_script(&program);
+        self
+    }
+
+    fn write_script(&self, program: &str) {
+        let dir = self.dir.path();
+        let replies_dir = dir.join("replies").join(program);
+        fs::create_dir_all(&replies_dir).unwrap();
+        let mut cases = String::new();
+        for (i, (command_line, replies)) in self.replies[program].iter().enumerate() {
+            for (n, reply) in replies.iter().enumerate() {
+                let prefix = replies_dir.join(format!("{i}_{n}"));
+                fs::write(prefix.with_extension("stdout"), &reply.stdout).unwrap();
+                fs::write(prefix.with_extension("stderr"), &reply.stderr).unwrap();
+                fs::write(prefix.with_extension("status"), reply.status.to_string()).unwrap();
+            }
+            cases.push_str(&format!(
+                "  '{}') respond {i} {} ;;\n",
+                command_line.replace('\'', r"'\''"),
+                replies.len()
+            ));
+        }
+        let script = format!(
+            r#"#!/bin/sh
+PATH=/usr/bin:/bin
+D='{replies}'
+echo "{program} $*" >> '{calls}'
+respond() {{
+  c=$(cat "$D/count$1" 2>/dev/null || echo 0)
+  n=$c
+  [ "$n" -ge "$2" ] && n=$(($2 - 1))
+  echo $((c + 1)) > "$D/count$1"
+  cat "$D/$1_$n.stdout"
+  cat "$D/$1_$n.stderr" >&2
+  exit "$(cat "$D/$1_$n.status")"
+}}
+case "{program} $*" in
+{cases}  *) echo "fake {program}: unexpected call \`{program} $*\`" >&2; exit 99 ;;
+esac
+"#,
+            replies = replies_dir.display(),
+            calls = dir.join("calls").display(),
+        );
+        let path = dir.join("bin").join(program);
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    /// All command lines in the order they were called
+    pub fn calls(&self) -> Vec<String> {
+        fs::read_to_string(self.dir.path().join("calls"))
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Run the CLI with the shell backend and only the fakes on PATH
+    pub fn run(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_usbip_wrapper"))
+            .args(args)
+            .env_clear()
+            .env("PATH", self.dir.path().join("bin"))
+            .env("USBIP_BACKEND", "shell")
+            .output()
+            .unwrap()
+    }
+}
+
+/// The stderr of the CLI, to check the error messages
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
@@ -0,0 +1,634 @@
+//! Run the CLI with `--backend shell` against the fake `usbip`/`usbipd` on PATH.
+mod common;
+
+use common::{stderr, FakeUsbip, Reply};
+
+const LIST_LOCAL: &str = " - busid 1-1 (1050:0407)
+   Yubico.com : Yubikey 4/5 OTP+U2F+CCID (1050:0407)
+
+ - busid 1-2 (1050:0407)
+   Yubico.com : Yubikey 4/5 OTP+U2F+CCID (1050:0407)
+
+ - busid 1-3 (058f:9540)
+   Alcor Micro Corp. : AU9540 Smartcard Reader (058f:9540)
+
+";
+
+const LIST_REMOTE: &str = "Exportable USB devices
+======================
+ - localhost
+        1-1: Yubico.com : Yubikey 4/5 OTP+U2F+CCID (1050:0407)
+           : /sys/devices/pci0000:00/0000:00:14.0/usb1/1-1
+           : (Defined at Interface level) (00/00/00)
+           :  0 - Human Interface Device / Boot Interface Subclass / Keyboard (03/01/01)
+
+";
+
+const NO_PORTS: &str = "Imported USB devices
+====================
+";
+
+const ONE_PORT: &str = "Imported USB devices
+====================
+Port 00: <Port in Use> at Full Speed(12Mbps)
+       Yubico.com : Yubikey 4/5 OTP+U2F+CCID (1050:0407)
+       3-1 -> usbip://localhost:3240/1-1
+           -> remote bus/dev 001/002
+";
+
+fn bound(busid: &str) -> Reply {
+    Reply::info(&format!(
+        "usbip: info: bind device on busid {busid}: complete\n"
+    ))
+}
+
+fn already_bound(busid: &str) -> Reply {
+    Reply::err(&format!(
+        "usbip: error: device on busid {busid} is already bound to usbip-host\n"
+    ))
+}
+
+#[test]
+fn test_host_is_idempotent() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply("usbip list --local", [Reply::ok(LIST_LOCAL)])
+        .reply(
+            "usbip bind --busid=1-1",
+            [bound("1-1"), already_bound("1-1")],
+        )
+        .reply("usbip bind --busid=1-2", [already_bound("1-2")]);
+    for _ in 0..2 {
+        let output = fake.run(&["host", "--", "1050:0407"]);
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+    let binds = fake
+        .calls()
+        .into_iter()
+        .filter(|c| c.starts_with("usbip bind"))
+        .count();
+    assert_eq!(binds, 4);
+}
+
+#[test]
+fn test_unhost_is_idempotent() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply("usbip list --local", [Reply::ok(LIST_LOCAL)])
+        .reply(
+            "usbip unbind --busid=1-3",
+            [Reply::err(
+                "usbip: error: device is not bound to usbip-host driver\n",
+            )],
+        );
+    let output = fake.run(&["unhost", "--", "058f:9540"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn test_unknown_bind_error() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply("usbip list --local", [Reply::ok(LIST_LOCAL)])
+        .reply(
+            "usbip bind --busid=1-3",
+            [Reply::err("usbip: error: unable to bind device on 1-3\n")],
+        );
+    let output = fake.run(&["host", "--", "058f:9540"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("unable to bind device on 1-3"));
+}
+
+#[test]
+fn test_partial_failure() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply("usbip list --local", [Reply::ok(LIST_LOCAL)])
+        .reply("usbip bind --busid=1-1", [bound("1-1")])
+        .reply(
+            "usbip bind --busid=1-2",
+            [Reply::err("usbip: error: unable to bind device on 1-2\n")],
+        );
+    let output = fake.run(&["host", "--", "1050:0407"]);
+    assert_eq!(output.status.code(), Some(8));
+    assert!(stderr(&output).contains("1 of 2 devices failed: 1-2"));
+    // the other device is bound nevertheless
+    assert!(fake.calls().contains(&"usbip bind --busid=1-1".to_string()));
+}
+
+#[test]
+fn test_no_matching_device() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply("usbip list --local", [Reply::ok(LIST_LOCAL)]);
+    let output = fake.run(&["host", "--", "dead:beef"]);
+    assert_eq!(output.status.code(), Some(6));
+    // only the full USB ID list knows enough devices to warn about typos
+    assert_eq!(
+        stderr(&output).contains("dead:beef is not in the USB ID list"),
+        usbip_wrapper::usb_ids::is_complete()
+    );
+    assert!(!fake.calls().iter().any(|c| c.starts_with("usbip bind")));
+}
+
+#[test]
+fn test_daemon_down() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply(
+        "usbip --tcp-port=3240 list --remote=localhost",
+        [Reply::err(
+            "usbip: error: could not connect to localhost:3240\n",
+        )],
+    );
+    let output = fake.run(&["list-mountable"]);
+    assert_eq!(output.status.code(), Some(5));
+    assert!(stderr(&output).contains("could not connect to localhost:3240"));
+}
+
+#[test]
+fn test_mount_is_idempotent() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply(
+        "usbip --tcp-port=3240 list --remote=localhost",
+        [Reply::ok(LIST_REMOTE)],
+    )
+    .reply("usbip port", [Reply::ok(NO_PORTS), Reply::ok(ONE_PORT)])
+    .reply(
+        "usbip --tcp-port=3240 attach --remote=localhost --busid=1-1",
+        [
+            Reply::ok(""),
+            Reply::err("usbip: error: Attach Request for 1-1 failed - Device busy (exported)\n"),
+        ],
+    );
+    for _ in 0..2 {
+        let output = fake.run(&["mount-remote", "--host", "localhost", "--", "1050:0407"]);
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+    let attaches = fake
+        .calls()
+        .into_iter()
+        .filter(|c| c.contains(" attach "))
+        .count();
+    assert_eq!(attaches, 2);
+}
+
+#[test]
+fn test_missing_vhci_driver() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply(
+        "usbip --tcp-port=3240 list --remote=localhost",
+        [Reply::ok(LIST_REMOTE)],
+    )
+    .reply("usbip port", [Reply::ok(NO_PORTS)])
+    .reply(
+        "usbip --tcp-port=3240 attach --remote=localhost --busid=1-1",
+        [Reply::err(
+            "libusbip: error: udev_device_new_from_subsystem_sysname failed\nusbip: error: open vhci_driver\n",
+        )],
+    );
+    let output = fake.run(&["mount-remote", "--host", "localhost", "--all"]);
+    assert_eq!(output.status.code(), Some(4));
+    assert!(stderr(&output).contains("modprobe vhci_hcd"));
+}
+
+#[test]
+fn test_unmount() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply("usbip port", [Reply::ok(ONE_PORT)]).reply(
+        "usbip detach --port=0",
+        [Reply::info("usbip: info: Port 0 is now detached!\n")],
+    );
+    let output = fake.run(&["unmount-remote", "--", "1050:0407"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(fake.calls().contains(&"usbip detach --port=0".to_string()));
+}
+
+#[test]
+fn test_usbip_not_found() {
+    let fake = FakeUsbip::new();
+    let output = fake.run(&["list-hostable"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stderr(&output).contains("Is usbip installed/added to PATH?"));
+}
+
+#[test]
+fn test_usbipd_not_found() {
+    let fake = FakeUsbip::usbip();
+    let output = fake.run(&["start-usb-hoster"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stderr(&output).contains("Is usbipd installed/added to PATH?"));
+}
+
+#[test]
+fn test_usbipd_fails() {
+    let mut fake = FakeUsbip::usbip();
+    let pid = fake.path().join("usbipd.pid");
+    let pid = pid.to_str().unwrap();
+    fake.reply(
+        "usbipd --version",
+        [Reply::ok("usbipd (usbip-utils 2.0)\n")],
+    )
+    .reply(
+        &format!("usbipd --tcp-port 3240 --pid {pid}"),
+        [Reply::err(
+            "usbipd: error: failed to open a listening socket\n",
+        )],
+    );
+    let output = fake.run(&["start-usb-hoster", "--pid", pid]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("usbipd exited with"));
+}
+
+#[test]
+fn test_list_json() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply("usbip list --local", [Reply::ok(LIST_LOCAL)])
+        .reply(
+            "usbip --tcp-port=3240 list --remote=localhost",
+            [Reply::ok(LIST_REMOTE)],
+        )
+        .reply("usbip port", [Reply::ok(ONE_PORT)]);
+
+    let output = fake.run(&["list-hostable", "--output", "jsonl"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let records = stdout
+        .lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[2]["busid"], "1-3");
+    assert_eq!(records[2]["vid"], "058f");
+    assert_eq!(records[2]["product"], "AU9540 Smartcard Reader");
+
+    let output = fake.run(&["list-mountable", "--output", "json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let records: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(records[0]["busid"], "1-1");
+    assert_eq!(records[0]["remote_host"], "localhost");
+
+    let output = fake.run(&["list-unmountable", "--output", "json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let records: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        records,
+        serde_json::json!([{
+            "busid": "1-1",
+            "vid": "1050",
+            "pid": "0407",
+            "vendor": "Yubico.com",
+            "product": "Yubikey 4/5 OTP+U2F+CCID",
+            "serial": null,
+            "driver": null,
+            "bound": null,
+            "remote_host": "localhost",
+            "port": "0",
+        }])
+    );
+}
+
+#[test]
+fn test_normalized_usb_ids() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply("usbip list --local", [Reply::ok(LIST_LOCAL)])
+        .reply("usbip bind --busid=1-3", [bound("1-3")]);
+    let output = fake.run(&["host", "--", "0x058F:0x9540"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!stderr(&output).contains("Warning"));
+}
+
+#[test]
+fn test_invalid_usb_id() {
+    let fake = FakeUsbip::usbip();
+    let output = fake.run(&["host", "--", "1050:04O7"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("Invalid USB ID `1050:04O7`"));
+    assert!(fake.calls().is_empty());
+}
+
+#[test]
+fn test_vendor_wildcard() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply("usbip list --local", [Reply::ok(LIST_LOCAL)])
+        .reply("usbip bind --busid=1-1", [bound("1-1")])
+        .reply("usbip bind --busid=1-2", [bound("1-2")]);
+    let output = fake.run(&["host", "--", "1050:*"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("1050:* expanded to 1050:0407"));
+    assert!(!fake.calls().contains(&"usbip bind --busid=1-3".to_string()));
+}
+
+#[test]
+fn test_mount_serial_needs_native_backend() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply(
+        "usbip --tcp-port=3240 list --remote=localhost",
+        [Reply::ok(LIST_REMOTE)],
+    )
+    .reply("usbip port", [Reply::ok(NO_PORTS)]);
+    let output = fake.run(&[
+        "mount-remote",
+        "--host",
+        "localhost",
+        "--serial",
+        "CC1234",
+        "--",
+        "1050:0407",
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("--backend native"));
+    assert!(!fake.calls().iter().any(|c| c.contains(" attach ")));
+
+    // `--serial` narrows down the given ids
+    let output = fake.run(&["mount-remote", "--host", "localhost", "--serial", "CC1234"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_busid_behind_hub() {
+    let mut fake = FakeUsbip::usbip();
+    let list = format!(
+        "{LIST_LOCAL} - busid 1-4.3.4 (0bda:402e)
+   Realtek Semiconductor Corp. : unknown product (0bda:402e)
+
+"
+    );
+    fake.reply("usbip list --local", [Reply::ok(&list)])
+        .reply("usbip bind --busid=1-4.3.4", [bound("1-4.3.4")]);
+    let output = fake.run(&["host", "--busid", "1-4.3.4"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let binds = fake
+        .calls()
+        .into_iter()
+        .filter(|c| c.starts_with("usbip bind"))
+        .collect::<Vec<_>>();
+    assert_eq!(binds, vec!["usbip bind --busid=1-4.3.4"]);
+
+    let output = fake.run(&["host", "--busid", "1-4.3.", "--", "0bda:402e"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("Invalid busid `1-4.3.`"));
+}
+
+#[test]
+fn test_mount_class() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply(
+        "usbip --tcp-port=3240 list --remote=localhost",
+        [Reply::ok(LIST_REMOTE)],
+    )
+    .reply("usbip port", [Reply::ok(NO_PORTS), Reply::ok(ONE_PORT)])
+    .reply(
+        "usbip --tcp-port=3240 attach --remote=localhost --busid=1-1",
+        [Reply::ok("")],
+    );
+    // the interfaces are listed by the usbip host
+    let output = fake.run(&[
+        "mount-remote",
+        "--host",
+        "localhost",
+        "--class",
+        "smartcard",
+    ]);
+    assert_eq!(output.status.code(), Some(6));
+    let output = fake.run(&["mount-remote", "--host", "localhost", "--class", "hid"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(fake.calls().iter().any(|c| c.contains(" attach ")));
+}
+
+#[test]
+fn test_host_class_needs_native_backend() {
+    let fake = FakeUsbip::usbip();
+    let output = fake.run(&["host", "--class", "0x0b"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("--backend native"));
+    assert!(fake.calls().is_empty());
+
+    let output = fake.run(&["host", "--class", "0x100"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("Invalid USB class `0x100`"));
+}
+
+#[test]
+fn test_unhost_excluded() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply("usbip list --local", [Reply::ok(LIST_LOCAL)])
+        .reply(
+            "usbip unbind --busid=1-3",
+            [Reply::info(
+                "usbip: info: unbind device on busid 1-3: complete\n",
+            )],
+        );
+    let output = fake.run(&["unhost", "--all", "--exclude", "1050:*"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let unbinds = fake
+        .calls()
+        .into_iter()
+        .filter(|c| c.starts_with("usbip unbind"))
+        .collect::<Vec<_>>();
+    assert_eq!(unbinds, vec!["usbip unbind --busid=1-3"]);
+
+    // the classes of the local devices are unknown to `usbip`
+    let output = fake.run(&["unhost", "--all", "--exclude", "hid"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("--backend native"));
+}
+
+#[test]
+fn test_unmount_remote_excluded_class() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply("usbip port", [Reply::ok(ONE_PORT)]);
+    // a class exclusion would exclude every mounted device
+    let output = fake.run(&["unmount-remote", "--all", "--exclude", "video"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("only accepts USB IDs and busids"));
+    assert!(fake.calls().is_empty());
+}
+
+#[test]
+fn test_all_devices_requires_all() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply("usbip port", [Reply::ok(ONE_PORT)]).reply(
+        "usbip detach --port=0",
+        [Reply::info("usbip: info: Port 0 is now detached!\n")],
+    );
+    for args in [
+        &["unhost"][..],
+        &["unhost", "--exclude", "1050:*"],
+        &["mount-remote", "--host", "localhost"],
+        &["unmount-remote"],
+    ] {
+        let output = fake.run(args);
+        assert_eq!(output.status.code(), Some(2), "{args:?}");
+        assert!(stderr(&output).contains("pass `--all`"), "{args:?}");
+    }
+    assert!(!fake.calls().iter().any(|c| c.contains("detach")));
+
+    // without a terminal, there is nobody to confirm
+    let output = fake.run(&["unmount-remote", "--all"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(fake.calls().contains(&"usbip detach --port=0".to_string()));
+
+    // `--yes` only makes sense with `--all`
+    let output = fake.run(&["unmount-remote", "--yes", "--", "1050:0407"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_dry_run() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply("usbip list --local", [Reply::ok(LIST_LOCAL)])
+        .reply(
+            "usbip --tcp-port=3240 list --remote=localhost",
+            [Reply::ok(LIST_REMOTE)],
+        );
+    let output = fake.run(&["host", "--dry-run", "--", "1050:*"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "bind   1-1 (1050:0407)\nbind   1-2 (1050:0407)\n"
+    );
+
+    let output = fake.run(&[
+        "mount-remote",
+        "--host",
+        "localhost",
+        "--all",
+        "--dry-run=json",
+    ]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let plan: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        plan,
+        serde_json::json!([{
+            "operation": "attach",
+            "busid": "1-1",
+            "vid": "1050",
+            "pid": "0407",
+            "vendor": "Yubico.com",
+            "product": "Yubikey 4/5 OTP+U2F+CCID",
+            "serial": null,
+            "driver": null,
+            "bound": null,
+            "remote_host": "localhost",
+            "port": null,
+            "required_serials": [],
+        }])
+    );
+    assert!(!fake
+        .calls()
+        .iter()
+        .any(|c| c.contains(" bind ") || c.contains(" attach ")));
+
+    // fails like the command itself
+    let output = fake.run(&["unhost", "--dry-run", "--", "dead:beef"]);
+    assert_eq!(output.status.code(), Some(6));
+    let output = fake.run(&["unhost", "--dry-run"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_mount_wait() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply(
+        "usbip --tcp-port=3240 list --remote=localhost",
+        [
+            Reply::err("usbip: error: could not connect to localhost:3240\n"),
+            Reply::ok(LIST_REMOTE),
+        ],
+    )
+    .reply("usbip port", [Reply::ok(NO_PORTS), Reply::ok(ONE_PORT)])
+    .reply(
+        "usbip --tcp-port=3240 attach --remote=localhost --busid=1-1",
+        [
+            Reply::ok(""),
+            Reply::err("usbip: error: Attach Request for 1-1 failed - Device busy (exported)\n"),
+        ],
+    );
+    let args = ["mount-remote", "--host", "localhost"];
+    let output = fake.run(&[&args[..], &["--wait=10s", "--", "1050:0407"]].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(fake.calls().iter().any(|c| c.contains(" attach ")));
+
+    let output = fake.run(&[&args[..], &["--wait=1s", "--", "1050:0407", "dead:beef"]].concat());
+    assert_eq!(output.status.code(), Some(9), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Timed out waiting for dead:beef"));
+}
+
+#[test]
+fn test_mount_wait_retries_failed_device() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply(
+        "usbip --tcp-port=3240 list --remote=localhost",
+        [Reply::ok(LIST_REMOTE)],
+    )
+    .reply(
+        "usbip port",
+        [
+            Reply::ok(NO_PORTS),
+            Reply::ok(NO_PORTS),
+            Reply::ok(ONE_PORT),
+        ],
+    )
+    .reply(
+        "usbip --tcp-port=3240 attach --remote=localhost --busid=1-1",
+        [Reply::err("usbip: error: import device\n"), Reply::ok("")],
+    );
+    let args = ["mount-remote", "--host", "localhost", "--wait=10s"];
+    let output = fake.run(&[&args[..], &["--", "1050:0407"]].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+    let attaches = fake
+        .calls()
+        .iter()
+        .filter(|c| c.contains(" attach "))
+        .count();
+    assert_eq!(attaches, 2);
+}
+
+#[test]
+fn test_unmet_expectations() {
+    let mut fake = FakeUsbip::usbip();
+    fake.reply("usbip list --local", [Reply::ok(LIST_LOCAL)])
+        .reply("usbip bind --busid=1-1", [bound("1-1")])
+        .reply("usbip bind --busid=1-2", [bound("1-2")])
+        .reply(
+            "usbip --tcp-port=3240 list --remote=localhost",
+            [Reply::ok(LIST_REMOTE)],
+        )
+        .reply("usbip port", [Reply::ok(NO_PORTS), Reply::ok(ONE_PORT)])
+        .reply(
+            "usbip --tcp-port=3240 attach --remote=localhost --busid=1-1",
+            [Reply::ok("")],
+        );
+    // the expectations are checked before anything is bound or attached
+    let output = fake.run(&["host", "--require-all", "--", "1050:0407", "dead:beef"]);
+    assert_eq!(output.status.code(), Some(6));
+    assert!(stderr(&output).contains("dead:beef matched no device"));
+    let output = fake.run(&["host", "--expect", "1050:0407=3"]);
+    assert_eq!(output.status.code(), Some(6));
+    assert!(stderr(&output).contains("1050:0407 matched 2 instead of 3 devices"));
+    let output = fake.run(&["host", "--dry-run", "--require-all", "--", "dead:beef"]);
+    assert_eq!(output.status.code(), Some(6));
+
+    let mount = ["mount-remote", "--host", "localhost", "--require-all"];
+    let output = fake.run(&[&mount[..], &["--", "1050:0407", "058f:9540"]].concat());
+    assert_eq!(output.status.code(), Some(6));
+    assert!(stderr(&output).contains("058f:9540 matched no device"));
+    let output = fake.run(&[&mount[..], &["--dry-run", "--", "058f:9540"]].concat());
+    assert_eq!(output.status.code(), Some(6));
+    assert!(!fake
+        .calls()
+        .iter()
+        .any(|c| c.starts_with("usbip bind") || c.contains(" attach ")));
+
+    let output = fake.run(&["host", "--", "1050:0407", "dead:beef"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Warning: dead:beef matched no device"));
+    let output = fake.run(&["host", "--expect", "1050:0407=2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // the remote devices are only listed once for checking and mounting them
+    let remote_lists = || {
+        fake.calls()
+            .iter()
+            .filter(|c| c.contains(" list --remote"))
+            .count()
+    };
+    let before = remote_lists();
+    let output = fake.run(&[&mount[..], &["--", "1050:0407"]].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(remote_lists(), before + 1);
+}